@@ -0,0 +1,140 @@
+// diranalyze/backend/src/blob_store.rs
+
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use sha2::{Digest, Sha256};
+use chrono::Utc;
+
+/// Bodies smaller than this are stored as-is; compressing them rarely pays off.
+const COMPRESSION_MIN_SIZE: usize = 512;
+const ZSTD_LEVEL: i32 = 3;
+
+const COMPRESSION_NONE: &str = "none";
+const COMPRESSION_ZSTD: &str = "zstd";
+
+/// Returns the lowercase hex SHA-256 digest of `content`.
+pub fn sha256_hex(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+/// Brings a client-supplied hash into the canonical form used throughout the database:
+/// trimmed, lowercase hex, without an algorithm prefix such as `sha256:`.
+pub fn normalize_content_hash(hash: &str) -> String {
+    let trimmed = hash.trim();
+    let without_prefix = ["sha256:", "sha256-"]
+        .iter()
+        .find_map(|prefix| {
+            trimmed
+                .get(..prefix.len())
+                .filter(|p| p.eq_ignore_ascii_case(prefix))
+                .map(|_| &trimmed[prefix.len()..])
+        })
+        .unwrap_or(trimmed);
+    without_prefix.to_ascii_lowercase()
+}
+
+/// Stores `content` in the `Blobs` table, keyed by its SHA-256 hash.
+/// Content that is already present (from any version or project) is not written again.
+/// Larger bodies are zstd-compressed when that actually makes them smaller.
+///
+/// # Returns
+/// The content hash the blob is stored under.
+pub fn put_blob(conn: &Connection, content: &[u8]) -> Result<String> {
+    let content_hash = sha256_hex(content);
+    if has_blob(conn, &content_hash)? {
+        return Ok(content_hash);
+    }
+
+    let compressed = if content.len() >= COMPRESSION_MIN_SIZE {
+        zstd::encode_all(content, ZSTD_LEVEL)
+            .ok()
+            .filter(|c| c.len() < content.len())
+    } else {
+        None
+    };
+    let (compression, stored): (&str, &[u8]) = match &compressed {
+        Some(c) => (COMPRESSION_ZSTD, c),
+        None => (COMPRESSION_NONE, content),
+    };
+
+    conn.execute(
+        "INSERT OR IGNORE INTO Blobs (content_hash, compression, original_size, stored_size, data, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            content_hash,
+            compression,
+            content.len() as i64,
+            stored.len() as i64,
+            stored,
+            Utc::now().to_rfc3339()
+        ],
+    )?;
+    Ok(content_hash)
+}
+
+/// Returns whether a blob with this hash is stored.
+pub fn has_blob(conn: &Connection, content_hash: &str) -> Result<bool> {
+    conn.prepare("SELECT 1 FROM Blobs WHERE content_hash = ?1")?
+        .exists(params![content_hash])
+}
+
+/// Loads and decompresses the blob stored under `content_hash`, or `None` if it is unknown.
+pub fn get_blob(conn: &Connection, content_hash: &str) -> Result<Option<Vec<u8>>> {
+    let row: Option<(String, Vec<u8>)> = conn
+        .query_row(
+            "SELECT compression, data FROM Blobs WHERE content_hash = ?1",
+            params![content_hash],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+
+    match row {
+        None => Ok(None),
+        Some((compression, data)) if compression == COMPRESSION_ZSTD => zstd::decode_all(data.as_slice())
+            .map(Some)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Blob, Box::new(e))),
+        Some((_, data)) => Ok(Some(data)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_manage;
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().expect("Failed to open in-memory DB");
+        db_manage::initialize_database(&conn).expect("Failed to initialize test DB schema");
+        conn
+    }
+
+    #[test]
+    fn test_normalize_content_hash() {
+        assert_eq!(normalize_content_hash("  ABCDEF01 "), "abcdef01");
+        assert_eq!(normalize_content_hash("SHA256:ABCDEF01"), "abcdef01");
+        assert_eq!(normalize_content_hash("sha256-abcdef01"), "abcdef01");
+        assert_eq!(normalize_content_hash("readmehash123"), "readmehash123");
+    }
+
+    #[test]
+    fn test_put_and_get_blob_round_trip_and_dedup() {
+        let conn = setup_test_db();
+        let small = b"hello world".to_vec();
+        let large = "fn main() { println!(\"hi\"); }\n".repeat(100).into_bytes();
+
+        let small_hash = put_blob(&conn, &small).unwrap();
+        assert_eq!(small_hash, "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        let large_hash = put_blob(&conn, &large).unwrap();
+        assert_eq!(put_blob(&conn, &large).unwrap(), large_hash);
+
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM Blobs", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 2);
+        let compression: String = conn
+            .query_row("SELECT compression FROM Blobs WHERE content_hash = ?1", params![large_hash], |row| row.get(0))
+            .unwrap();
+        assert_eq!(compression, COMPRESSION_ZSTD);
+
+        assert_eq!(get_blob(&conn, &small_hash).unwrap(), Some(small));
+        assert_eq!(get_blob(&conn, &large_hash).unwrap(), Some(large));
+        assert_eq!(get_blob(&conn, "missing").unwrap(), None);
+    }
+}
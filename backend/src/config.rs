@@ -0,0 +1,464 @@
+// diranalyze/backend/src/config.rs
+
+use clap::{Parser, ValueEnum};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_DB_PATH: &str = ".diranalyze_db.sqlite3";
+pub const DEFAULT_PROJECTS_DIR: &str = ".diranalyze_projects";
+/// Looked up in the working directory when no `--config` is given.
+pub const DEFAULT_CONFIG_FILE: &str = "diranalyze.toml";
+pub const DEFAULT_LLM_PROVIDER: &str = "openai";
+/// How long a cached LLM response is served before it is fetched again.
+pub const DEFAULT_LLM_CACHE_TTL_SECS: u64 = 24 * 60 * 60;
+pub const DEFAULT_OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com";
+pub const DEFAULT_OLLAMA_BASE_URL: &str = "http://localhost:11434";
+/// The model name the built-in `mock` provider reports.
+pub const DEFAULT_MOCK_MODEL: &str = "mock-1";
+
+/// How the backend maps projects onto SQLite files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DbMode {
+    /// Every project shares the database at `database.path`.
+    Single,
+    /// Each project root gets its own database file under `database.projects_dir`.
+    PerProject,
+}
+
+/// The API dialect an LLM provider speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LlmProviderKind {
+    /// OpenAI Chat Completions at `api.openai.com`.
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// Anthropic Messages API.
+    Anthropic,
+    /// Ollama's native `/api/chat`.
+    Ollama,
+    /// Any server exposing OpenAI-style `/chat/completions` under its base URL.
+    #[serde(rename = "openai-compatible")]
+    OpenAiCompatible,
+    /// Answers offline from fixture files instead of calling anything; see `llm_mock`.
+    Mock,
+}
+
+/// One configured LLM endpoint. The API key is read from `api_key_env` when a request is made.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LlmProviderConfig {
+    pub kind: LlmProviderKind,
+    /// Required for every kind except `mock`.
+    #[serde(default)]
+    pub base_url: String,
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+    /// Used when a request does not name a model.
+    #[serde(default)]
+    pub default_model: Option<String>,
+    /// `mock` only: a fixture file, or a directory of `*.json` fixture files. The built-in
+    /// demo fixtures are used when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fixtures: Option<PathBuf>,
+}
+
+/// What a model costs, in US dollars per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelPrice {
+    pub input_per_mtok: f64,
+    pub output_per_mtok: f64,
+}
+
+/// Command-line flags. Every flag can also be set through the listed environment variable
+/// (including via `.env`), and both override values from the config file.
+#[derive(Debug, Default, Parser)]
+#[command(name = "diranalyze-backend", about = "DirAnalyze local backend server")]
+pub struct CliArgs {
+    /// Path to a TOML config file (defaults to ./diranalyze.toml if present).
+    #[arg(long, env = "DIRANALYZE_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// SQLite database file used in single mode, and before any project is opened.
+    #[arg(long, env = "DIRANALYZE_DB_PATH")]
+    pub db_path: Option<PathBuf>,
+    /// Whether all projects share one database or each gets its own.
+    #[arg(long, env = "DIRANALYZE_DB_MODE", value_enum)]
+    pub db_mode: Option<DbMode>,
+    /// Directory holding per-project databases.
+    #[arg(long, env = "DIRANALYZE_PROJECTS_DIR")]
+    pub projects_dir: Option<PathBuf>,
+    /// LLM provider used when a request does not pick one.
+    #[arg(long, env = "DIRANALYZE_LLM_PROVIDER")]
+    pub llm_provider: Option<String>,
+    /// Keep the full body of every LLM request and response in the blob store (`true`/`false`).
+    #[arg(long, env = "DIRANALYZE_LLM_STORE_BODIES")]
+    pub llm_store_bodies: Option<bool>,
+    /// Largest prompt, in estimated tokens, a single LLM request may send.
+    #[arg(long, env = "DIRANALYZE_LLM_MAX_REQUEST_TOKENS")]
+    pub llm_max_request_tokens: Option<u64>,
+    /// Most tokens one session may use across its LLM requests.
+    #[arg(long, env = "DIRANALYZE_LLM_MAX_SESSION_TOKENS")]
+    pub llm_max_session_tokens: Option<u64>,
+    /// Most US dollars LLM calls may cost per UTC day.
+    #[arg(long, env = "DIRANALYZE_LLM_DAILY_SPEND_CAP")]
+    pub llm_daily_spend_cap: Option<f64>,
+    /// Most US dollars LLM calls may cost per UTC calendar month.
+    #[arg(long, env = "DIRANALYZE_LLM_MONTHLY_SPEND_CAP")]
+    pub llm_monthly_spend_cap: Option<f64>,
+    /// Serve repeated identical LLM requests from the response cache (`true`/`false`).
+    #[arg(long, env = "DIRANALYZE_LLM_CACHE")]
+    pub llm_cache: Option<bool>,
+    /// Seconds a cached LLM response stays valid.
+    #[arg(long, env = "DIRANALYZE_LLM_CACHE_TTL_SECS")]
+    pub llm_cache_ttl_secs: Option<u64>,
+    /// How many times an LLM request is tried before its error is returned (1 disables retries).
+    #[arg(long, env = "DIRANALYZE_LLM_MAX_ATTEMPTS")]
+    pub llm_max_attempts: Option<u32>,
+    /// Seconds to wait for a connection to an LLM provider.
+    #[arg(long, env = "DIRANALYZE_LLM_CONNECT_TIMEOUT_SECS")]
+    pub llm_connect_timeout_secs: Option<u64>,
+    /// Seconds an LLM provider may go without sending anything before the request fails.
+    #[arg(long, env = "DIRANALYZE_LLM_READ_TIMEOUT_SECS")]
+    pub llm_read_timeout_secs: Option<u64>,
+    /// Fixture file or directory for the built-in `mock` LLM provider.
+    #[arg(long, env = "DIRANALYZE_LLM_MOCK_FIXTURES")]
+    pub llm_mock_fixtures: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    #[serde(default)]
+    database: DatabaseFileConfig,
+    #[serde(default)]
+    llm: LlmFileConfig,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DatabaseFileConfig {
+    path: Option<PathBuf>,
+    mode: Option<DbMode>,
+    projects_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LlmFileConfig {
+    default_provider: Option<String>,
+    store_bodies: Option<bool>,
+    max_request_tokens: Option<u64>,
+    max_session_tokens: Option<u64>,
+    daily_spend_cap_usd: Option<f64>,
+    monthly_spend_cap_usd: Option<f64>,
+    cache: Option<bool>,
+    cache_ttl_secs: Option<u64>,
+    #[serde(default)]
+    providers: BTreeMap<String, LlmProviderConfig>,
+    #[serde(default)]
+    prices: BTreeMap<String, ModelPrice>,
+    #[serde(default)]
+    retry: LlmRetryFileConfig,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LlmRetryFileConfig {
+    max_attempts: Option<u32>,
+    connect_timeout_secs: Option<u64>,
+    read_timeout_secs: Option<u64>,
+    initial_backoff_ms: Option<u64>,
+    max_backoff_ms: Option<u64>,
+    circuit_failure_threshold: Option<u32>,
+    circuit_open_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+    pub mode: DbMode,
+    pub projects_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LlmConfig {
+    pub default_provider: String,
+    /// Built-in providers (`openai`, `anthropic`, `ollama`, `mock`) plus those from the config file,
+    /// which replace built-ins of the same name.
+    pub providers: BTreeMap<String, LlmProviderConfig>,
+    /// Whether LLM request and response bodies are kept for replay, not just their hashes.
+    pub store_bodies: bool,
+    /// Prompt size limit per request; `None` means unlimited.
+    pub max_request_tokens: Option<u64>,
+    /// Token limit per session; `None` means unlimited.
+    pub max_session_tokens: Option<u64>,
+    /// Prices by model name. A price also applies to model names it is a prefix of, so
+    /// `gpt-4o` covers `gpt-4o-2024-08-06`; the longest matching name wins.
+    pub prices: BTreeMap<String, ModelPrice>,
+    /// Spend limits in US dollars; `None` means unlimited.
+    pub daily_spend_cap_usd: Option<f64>,
+    pub monthly_spend_cap_usd: Option<f64>,
+    /// Whether identical requests are answered from the response cache. Off unless enabled.
+    pub cache_enabled: bool,
+    pub cache_ttl_secs: u64,
+    pub retry: LlmRetryConfig,
+}
+
+/// Timeouts, retries and the circuit breaker for calls to LLM providers (`[llm.retry]`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LlmRetryConfig {
+    /// Attempts per request, including the first; at least 1.
+    pub max_attempts: u32,
+    pub connect_timeout_secs: u64,
+    /// Longest silence between reads, so long streams are fine as long as they keep sending.
+    pub read_timeout_secs: u64,
+    /// Wait before the first retry; doubled for each further one, up to `max_backoff_ms`.
+    /// A longer `Retry-After` from the provider is honoured up to `max_backoff_ms`.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Consecutive failed requests after which a provider's circuit opens; 0 disables the breaker.
+    pub circuit_failure_threshold: u32,
+    /// How long an open circuit refuses calls before letting a trial request through.
+    pub circuit_open_secs: u64,
+}
+
+impl Default for LlmRetryConfig {
+    fn default() -> Self {
+        LlmRetryConfig {
+            max_attempts: 3,
+            connect_timeout_secs: 10,
+            read_timeout_secs: 300,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            circuit_failure_threshold: 5,
+            circuit_open_secs: 30,
+        }
+    }
+}
+
+/// Fully resolved backend configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppConfig {
+    pub database: DatabaseConfig,
+    pub llm: LlmConfig,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read config file '{}': {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config file '{}': {}", path.display(), e),
+            ConfigError::Invalid(message) => write!(f, "invalid configuration: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Loads the configuration from the process arguments, environment and config file.
+pub fn load() -> Result<AppConfig, ConfigError> {
+    load_from(CliArgs::parse())
+}
+
+/// Loads the configuration for already-parsed CLI arguments.
+/// An explicitly named config file must exist; the default one is optional.
+pub fn load_from(cli: CliArgs) -> Result<AppConfig, ConfigError> {
+    let (config_path, required) = match &cli.config {
+        Some(path) => (path.clone(), true),
+        None => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+    };
+    let file_config = match std::fs::read_to_string(&config_path) {
+        Ok(text) => {
+            println!("[CONFIG] Using config file '{}'", config_path.display());
+            let parsed: FileConfig =
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(config_path.clone(), e))?;
+            Some((config_path, parsed))
+        }
+        Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(ConfigError::Io(config_path, e)),
+    };
+    let config = resolve(cli, file_config);
+    if config.llm.retry.max_attempts == 0 {
+        return Err(ConfigError::Invalid("LLM max_attempts must be at least 1".to_string()));
+    }
+    if let Some((name, _)) =
+        config.llm.providers.iter().find(|(_, p)| p.kind != LlmProviderKind::Mock && p.base_url.trim().is_empty())
+    {
+        return Err(ConfigError::Invalid(format!("LLM provider '{}' has no base_url", name)));
+    }
+    if !config.llm.providers.contains_key(&config.llm.default_provider) {
+        return Err(ConfigError::Invalid(format!(
+            "default LLM provider '{}' is not configured",
+            config.llm.default_provider
+        )));
+    }
+    Ok(config)
+}
+
+/// The providers available without any configuration.
+fn builtin_llm_providers(mock_fixtures: Option<PathBuf>) -> BTreeMap<String, LlmProviderConfig> {
+    let provider = |kind, base_url: &str, api_key_env: Option<&str>| LlmProviderConfig {
+        kind,
+        base_url: base_url.to_string(),
+        api_key_env: api_key_env.map(str::to_string),
+        default_model: None,
+        fixtures: None,
+    };
+    let mock = LlmProviderConfig {
+        default_model: Some(DEFAULT_MOCK_MODEL.to_string()),
+        fixtures: mock_fixtures,
+        ..provider(LlmProviderKind::Mock, "", None)
+    };
+    BTreeMap::from([
+        ("openai".to_string(), provider(LlmProviderKind::OpenAi, DEFAULT_OPENAI_BASE_URL, Some("OPENAI_API_KEY"))),
+        ("anthropic".to_string(), provider(LlmProviderKind::Anthropic, DEFAULT_ANTHROPIC_BASE_URL, Some("ANTHROPIC_API_KEY"))),
+        ("ollama".to_string(), provider(LlmProviderKind::Ollama, DEFAULT_OLLAMA_BASE_URL, None)),
+        ("mock".to_string(), mock),
+    ])
+}
+
+/// Merges CLI/env values over config file values over defaults. Relative paths from the
+/// config file are taken relative to the file itself, not the working directory.
+fn resolve(cli: CliArgs, file_config: Option<(PathBuf, FileConfig)>) -> AppConfig {
+    let (config_dir, file_db, file_llm) = match file_config {
+        Some((path, parsed)) => (path.parent().map(Path::to_path_buf), parsed.database, parsed.llm),
+        None => (None, DatabaseFileConfig::default(), LlmFileConfig::default()),
+    };
+    let from_file = |p: Option<PathBuf>| match (&config_dir, p) {
+        (Some(dir), Some(p)) if p.is_relative() => Some(dir.join(p)),
+        (_, p) => p,
+    };
+    let (file_retry, retry_defaults) = (&file_llm.retry, LlmRetryConfig::default());
+
+    AppConfig {
+        database: DatabaseConfig {
+            path: cli.db_path.or_else(|| from_file(file_db.path)).unwrap_or_else(|| PathBuf::from(DEFAULT_DB_PATH)),
+            mode: cli.db_mode.or(file_db.mode).unwrap_or(DbMode::Single),
+            projects_dir: cli
+                .projects_dir
+                .or_else(|| from_file(file_db.projects_dir))
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_PROJECTS_DIR)),
+        },
+        llm: LlmConfig {
+            default_provider: cli
+                .llm_provider
+                .or(file_llm.default_provider)
+                .unwrap_or_else(|| DEFAULT_LLM_PROVIDER.to_string()),
+            providers: builtin_llm_providers(cli.llm_mock_fixtures)
+                .into_iter()
+                .chain(file_llm.providers.into_iter().map(|(name, provider)| {
+                    (name, LlmProviderConfig { fixtures: from_file(provider.fixtures.clone()), ..provider })
+                }))
+                .collect(),
+            store_bodies: cli.llm_store_bodies.or(file_llm.store_bodies).unwrap_or(false),
+            max_request_tokens: cli.llm_max_request_tokens.or(file_llm.max_request_tokens),
+            max_session_tokens: cli.llm_max_session_tokens.or(file_llm.max_session_tokens),
+            prices: file_llm.prices,
+            daily_spend_cap_usd: cli.llm_daily_spend_cap.or(file_llm.daily_spend_cap_usd),
+            monthly_spend_cap_usd: cli.llm_monthly_spend_cap.or(file_llm.monthly_spend_cap_usd),
+            cache_enabled: cli.llm_cache.or(file_llm.cache).unwrap_or(false),
+            cache_ttl_secs: cli.llm_cache_ttl_secs.or(file_llm.cache_ttl_secs).unwrap_or(DEFAULT_LLM_CACHE_TTL_SECS),
+            retry: LlmRetryConfig {
+                max_attempts: cli.llm_max_attempts.or(file_retry.max_attempts).unwrap_or(retry_defaults.max_attempts),
+                connect_timeout_secs: cli
+                    .llm_connect_timeout_secs
+                    .or(file_retry.connect_timeout_secs)
+                    .unwrap_or(retry_defaults.connect_timeout_secs),
+                read_timeout_secs: cli
+                    .llm_read_timeout_secs
+                    .or(file_retry.read_timeout_secs)
+                    .unwrap_or(retry_defaults.read_timeout_secs),
+                initial_backoff_ms: file_retry.initial_backoff_ms.unwrap_or(retry_defaults.initial_backoff_ms),
+                max_backoff_ms: file_retry.max_backoff_ms.unwrap_or(retry_defaults.max_backoff_ms),
+                circuit_failure_threshold: file_retry
+                    .circuit_failure_threshold
+                    .unwrap_or(retry_defaults.circuit_failure_threshold),
+                circuit_open_secs: file_retry.circuit_open_secs.unwrap_or(retry_defaults.circuit_open_secs),
+            },
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_file(text: &str) -> FileConfig {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_defaults_without_flags_or_file() {
+        let config = resolve(CliArgs::default(), None);
+        assert_eq!(config.database.path, PathBuf::from(DEFAULT_DB_PATH));
+        assert_eq!(config.database.mode, DbMode::Single);
+        assert_eq!(config.database.projects_dir, PathBuf::from(DEFAULT_PROJECTS_DIR));
+    }
+
+    #[test]
+    fn test_cli_overrides_file_and_file_paths_are_relative_to_it() {
+        let file = parse_file(
+            "[database]\npath = \"data/main.sqlite3\"\nmode = \"per-project\"\nprojects_dir = \"/srv/projects\"\n",
+        );
+        let cli = CliArgs { db_path: Some(PathBuf::from("override.sqlite3")), ..CliArgs::default() };
+        let config = resolve(cli, Some((PathBuf::from("/etc/diranalyze/diranalyze.toml"), file)));
+        assert_eq!(config.database.path, PathBuf::from("override.sqlite3"));
+        assert_eq!(config.database.mode, DbMode::PerProject);
+        assert_eq!(config.database.projects_dir, PathBuf::from("/srv/projects"));
+
+        let file = parse_file("[database]\npath = \"data/main.sqlite3\"\n");
+        let config = resolve(CliArgs::default(), Some((PathBuf::from("/etc/diranalyze/diranalyze.toml"), file)));
+        assert_eq!(config.database.path, PathBuf::from("/etc/diranalyze/data/main.sqlite3"));
+    }
+
+    #[test]
+    fn test_cli_flags_parse() {
+        let cli = CliArgs::try_parse_from(["backend", "--db-path", "x.sqlite3", "--db-mode", "per-project"]).unwrap();
+        assert_eq!(cli.db_path, Some(PathBuf::from("x.sqlite3")));
+        assert_eq!(cli.db_mode, Some(DbMode::PerProject));
+    }
+
+    #[test]
+    fn test_llm_providers_merge_over_builtins() {
+        let config = resolve(CliArgs::default(), None);
+        assert_eq!(config.llm.default_provider, DEFAULT_LLM_PROVIDER);
+        assert!(!config.llm.store_bodies);
+        assert_eq!((config.llm.cache_enabled, config.llm.cache_ttl_secs), (false, DEFAULT_LLM_CACHE_TTL_SECS));
+        assert_eq!(config.llm.retry, LlmRetryConfig::default());
+        assert_eq!(config.llm.providers.keys().collect::<Vec<_>>(), ["anthropic", "mock", "ollama", "openai"]);
+
+        let file = parse_file(
+            "[llm]\ndefault_provider = \"local\"\nstore_bodies = true\nmax_request_tokens = 8000\ndaily_spend_cap_usd = 2.5\ncache = true\n\n\
+             [llm.retry]\nmax_attempts = 5\ncircuit_failure_threshold = 0\n\n\
+             [llm.prices]\n\"gpt-4o\" = { input_per_mtok = 2.5, output_per_mtok = 10.0 }\n\n\
+             [llm.providers.local]\nkind = \"openai-compatible\"\nbase_url = \"http://localhost:1234/v1\"\ndefault_model = \"qwen\"\n\n\
+             [llm.providers.ollama]\nkind = \"ollama\"\nbase_url = \"http://gpu-box:11434\"\n\n\
+             [llm.providers.demo]\nkind = \"mock\"\nfixtures = \"fixtures/demo.json\"\n",
+        );
+        let config = resolve(CliArgs::default(), Some((PathBuf::from("/etc/diranalyze/diranalyze.toml"), file)));
+        assert_eq!(config.llm.default_provider, "local");
+        assert!(config.llm.store_bodies);
+        assert_eq!((config.llm.max_request_tokens, config.llm.max_session_tokens), (Some(8000), None));
+        assert_eq!((config.llm.daily_spend_cap_usd, config.llm.monthly_spend_cap_usd), (Some(2.5), None));
+        assert!(config.llm.cache_enabled);
+        assert_eq!((config.llm.retry.max_attempts, config.llm.retry.circuit_failure_threshold), (5, 0));
+        assert_eq!(config.llm.retry.read_timeout_secs, LlmRetryConfig::default().read_timeout_secs);
+        assert_eq!(config.llm.prices["gpt-4o"], ModelPrice { input_per_mtok: 2.5, output_per_mtok: 10.0 });
+        assert_eq!(config.llm.providers["local"].kind, LlmProviderKind::OpenAiCompatible);
+        assert_eq!(config.llm.providers["local"].default_model.as_deref(), Some("qwen"));
+        assert_eq!(config.llm.providers["ollama"].base_url, "http://gpu-box:11434");
+        assert_eq!(config.llm.providers["demo"].fixtures, Some(PathBuf::from("/etc/diranalyze/fixtures/demo.json")));
+        assert_eq!(config.llm.providers["mock"].default_model.as_deref(), Some(DEFAULT_MOCK_MODEL));
+        assert_eq!(config.llm.providers.len(), 6);
+    }
+}
@@ -1,17 +1,272 @@
 // diranalyze/backend/src/db_manage.rs
 use rusqlite::{Connection, Result as RusqliteResult};
-use std::path::PathBuf; // PathBuf is still useful for canonicalize
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use crate::config::{DatabaseConfig, DbMode};
+use crate::db_pool::{self, DbPool};
+use crate::operation_log;
 
+/// A numbered schema change. Applied in order, each in its own transaction, and recorded in
+/// `PRAGMA user_version` so a database only ever runs a migration once.
+struct Migration {
+    version: i64,
+    description: &'static str,
+    sql: &'static str,
+    /// Runs after `sql`, in the same transaction, for data changes SQL alone cannot express.
+    backfill: Option<fn(&Connection) -> RusqliteResult<()>>,
+}
+
+/// All schema migrations, oldest first. Never edit a released entry; append a new one instead.
+/// Migration 1 is the original schema and uses `IF NOT EXISTS` so databases created before the
+/// migration runner existed (user_version 0 with tables present) upgrade cleanly.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Baseline versioning schema",
+        sql: "CREATE TABLE IF NOT EXISTS ProjectVersions (
+            version_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            parent_version_id INTEGER,
+            timestamp TEXT NOT NULL,
+            description TEXT,
+            CONSTRAINT fk_parent_version
+                FOREIGN KEY (parent_version_id)
+                REFERENCES ProjectVersions (version_id)
+                ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS VersionFiles (
+            version_file_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            project_version_id INTEGER NOT NULL,
+            file_path TEXT NOT NULL,
+            content_hash TEXT NOT NULL,
+            file_size INTEGER NOT NULL,
+            CONSTRAINT fk_project_version
+                FOREIGN KEY (project_version_id)
+                REFERENCES ProjectVersions (version_id)
+                ON DELETE CASCADE,
+            UNIQUE (project_version_id, file_path)
+        );
+        CREATE TABLE IF NOT EXISTS OperationLog (
+            log_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            linked_project_version_id INTEGER,
+            timestamp TEXT NOT NULL,
+            operation_type TEXT NOT NULL,
+            target_entity TEXT,
+            content_hash_before TEXT,
+            content_hash_after TEXT,
+            details_json TEXT
+        );",
+        backfill: None,
+    },
+    Migration {
+        version: 2,
+        description: "Content-addressed blob store",
+        sql: "CREATE TABLE Blobs (
+            content_hash TEXT PRIMARY KEY,
+            compression TEXT NOT NULL,
+            original_size INTEGER NOT NULL,
+            stored_size INTEGER NOT NULL,
+            data BLOB NOT NULL,
+            created_at TEXT NOT NULL
+        );",
+        backfill: None,
+    },
+    Migration {
+        version: 3,
+        description: "Delta-encoded file history",
+        sql: "CREATE TABLE FileDiffs (
+            diff_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            target_project_version_id INTEGER NOT NULL,
+            file_path TEXT NOT NULL,
+            diff_content TEXT NOT NULL,
+            source_content_hash TEXT NOT NULL,
+            target_content_hash TEXT NOT NULL,
+            chain_depth INTEGER NOT NULL,
+            created_at TEXT NOT NULL,
+            CONSTRAINT fk_target_project_version
+                FOREIGN KEY (target_project_version_id)
+                REFERENCES ProjectVersions (version_id)
+                ON DELETE CASCADE
+        );
+        CREATE INDEX idx_filediffs_target_hash ON FileDiffs (target_content_hash);",
+        backfill: None,
+    },
+    Migration {
+        version: 4,
+        description: "Projects table and project ownership of versions",
+        // Existing history is backfilled with one project per root version, named after the
+        // initial snapshot's target entity, and every descendant version inherits its root's project.
+        sql: "CREATE TABLE Projects (
+            project_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            display_name TEXT NOT NULL,
+            root_fingerprint TEXT NOT NULL UNIQUE,
+            created_at TEXT NOT NULL
+        );
+        ALTER TABLE ProjectVersions ADD COLUMN project_id INTEGER
+            REFERENCES Projects (project_id) ON DELETE CASCADE;
+        INSERT INTO Projects (display_name, root_fingerprint, created_at)
+            SELECT COALESCE(
+                       (SELECT ol.target_entity FROM OperationLog ol
+                        WHERE ol.linked_project_version_id = pv.version_id
+                          AND ol.operation_type = 'PROJECT_SNAPSHOT_INITIAL'
+                        LIMIT 1),
+                       pv.description,
+                       'Untitled project'),
+                   'legacy-root-' || pv.version_id,
+                   pv.timestamp
+            FROM ProjectVersions pv
+            WHERE pv.parent_version_id IS NULL
+            ORDER BY pv.version_id;
+        WITH RECURSIVE lineage (version_id, root_id) AS (
+            SELECT version_id, version_id FROM ProjectVersions WHERE parent_version_id IS NULL
+            UNION ALL
+            SELECT pv.version_id, l.root_id FROM ProjectVersions pv
+            JOIN lineage l ON pv.parent_version_id = l.version_id
+        )
+        UPDATE ProjectVersions SET project_id = (
+            SELECT p.project_id FROM lineage l
+            JOIN Projects p ON p.root_fingerprint = 'legacy-root-' || l.root_id
+            WHERE l.version_id = ProjectVersions.version_id
+        );
+        CREATE INDEX idx_projectversions_project ON ProjectVersions (project_id);",
+        backfill: None,
+    },
+    Migration {
+        version: 5,
+        description: "Hash chain over OperationLog",
+        // Existing rows are chained in log_id order, so verification covers the whole history.
+        sql: "ALTER TABLE OperationLog ADD COLUMN prev_hash TEXT;
+        ALTER TABLE OperationLog ADD COLUMN entry_hash TEXT;",
+        backfill: Some(operation_log::backfill_chain),
+    },
+    Migration {
+        version: 6,
+        description: "UsageLedger for LLM token usage and cost",
+        sql: "CREATE TABLE UsageLedger (
+            usage_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT NOT NULL,
+            provider TEXT NOT NULL,
+            model TEXT NOT NULL,
+            project_id INTEGER REFERENCES Projects (project_id) ON DELETE SET NULL,
+            session TEXT,
+            input_tokens INTEGER NOT NULL,
+            output_tokens INTEGER NOT NULL,
+            tokens_estimated INTEGER NOT NULL DEFAULT 0,
+            cost_usd REAL,
+            log_id INTEGER REFERENCES OperationLog (log_id)
+        );
+        CREATE INDEX idx_usageledger_timestamp ON UsageLedger (timestamp);",
+        backfill: None,
+    },
+    Migration {
+        version: 7,
+        description: "LlmCache of responses keyed by request hash",
+        sql: "CREATE TABLE LlmCache (
+            request_sha256 TEXT PRIMARY KEY,
+            provider TEXT NOT NULL,
+            response_json TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            expires_at TEXT NOT NULL,
+            hit_count INTEGER NOT NULL DEFAULT 0,
+            last_hit_at TEXT,
+            log_id INTEGER REFERENCES OperationLog (log_id)
+        );",
+        backfill: None,
+    },
+];
+
+/// The schema version this build of the backend writes and understands.
+pub const LATEST_SCHEMA_VERSION: i64 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+#[derive(Debug)]
+pub enum SchemaError {
+    Sqlite(rusqlite::Error),
+    /// The database was written by a newer backend; opening it could corrupt data we don't understand.
+    TooNew { found: i64, supported: i64 },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            SchemaError::TooNew { found, supported } => write!(
+                f,
+                "database schema version {} is newer than the latest supported version {}",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl From<rusqlite::Error> for SchemaError {
+    fn from(e: rusqlite::Error) -> Self {
+        SchemaError::Sqlite(e)
+    }
+}
+
+/// Reads the schema version recorded in the database (`PRAGMA user_version`).
+pub fn schema_version(conn: &Connection) -> RusqliteResult<i64> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Applies every migration newer than the database's current schema version.
+///
+/// # Returns
+/// The number of migrations applied, or `SchemaError::TooNew` if the database comes from a newer
+/// backend.
+pub fn run_migrations(conn: &Connection) -> Result<usize, SchemaError> {
+    let current = schema_version(conn)?;
+    if current > LATEST_SCHEMA_VERSION {
+        return Err(SchemaError::TooNew { found: current, supported: LATEST_SCHEMA_VERSION });
+    }
+
+    let mut applied = 0;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        println!("[DB_SCHEMA] Applying migration {}: {}", migration.version, migration.description);
+        // `PRAGMA user_version` is transactional, so a failed migration leaves no trace.
+        conn.execute_batch(&format!("BEGIN;\n{}", migration.sql))
+            .and_then(|_| migration.backfill.map_or(Ok(()), |backfill| backfill(conn)))
+            .and_then(|_| conn.execute_batch(&format!("PRAGMA user_version = {};\nCOMMIT;", migration.version)))
+            .inspect_err(|_| {
+                let _ = conn.execute_batch("ROLLBACK;");
+            })?;
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+/// How long a connection waits on a locked database before giving up with `SQLITE_BUSY`.
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens a read-write connection with WAL journaling, so readers on other connections are never
+/// blocked by the writer. WAL is a property of the database file, so this also converts older
+/// databases the first time they are opened.
 pub fn open_db_connection_with_path(db_path_str: &str) -> RusqliteResult<Connection> {
     let conn = Connection::open(db_path_str)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    let journal_mode: String = conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+    if !journal_mode.eq_ignore_ascii_case("wal") {
+        println!("[DB_INIT] Warning: WAL not available for '{}', using journal mode '{}'.", db_path_str, journal_mode);
+    }
+    // NORMAL is durable in WAL mode except for the last transactions before a power loss.
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
     Ok(conn)
 }
 
-pub fn open_db_connection() -> RusqliteResult<Connection> {
-    open_db_connection_with_path(".diranalyze_db.sqlite3")
+/// Opens the database at `path` and brings its schema up to date.
+pub fn open_and_initialize(path: &Path) -> Result<Connection, SchemaError> {
+    let conn = open_db_connection_with_path(&path.to_string_lossy())?;
+    initialize_database(&conn)?;
+    Ok(conn)
 }
 
-pub fn initialize_database(conn: &Connection) -> RusqliteResult<()> {
+pub fn initialize_database(conn: &Connection) -> Result<(), SchemaError> {
     // conn.path() returns Option<&str>
     let db_path_str_opt: Option<&str> = conn.path();
 
@@ -27,37 +282,228 @@ pub fn initialize_database(conn: &Connection) -> RusqliteResult<()> {
             }
         }
         None => {
-            ".diranalyze_db.sqlite3 (Path not directly available from connection object)".to_string()
+            "(in-memory database; no path available from connection object)".to_string()
         }
     };
 
     println!("[DB_SCHEMA] Initializing schema for database resolved to: '{}'", canonical_path_display);
 
-    conn.execute_batch(
-        "BEGIN;
-        CREATE TABLE IF NOT EXISTS ProjectVersions (
+    let applied = run_migrations(conn)?;
+    println!(
+        "[DB_SCHEMA] Schema is at version {} for '{}' ({} migration(s) applied).",
+        LATEST_SCHEMA_VERSION, canonical_path_display, applied
+    );
+    Ok(())
+}
+
+/// Shared handle to one open project database.
+pub type DbHandle = Arc<DbPool>;
+
+/// File inside `projects_dir` mapping project root names to their database files.
+const REGISTRY_FILE: &str = "registry.json";
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Io(std::io::Error),
+    Schema(SchemaError),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::Io(e) => write!(f, "database registry I/O error: {}", e),
+            RegistryError::Schema(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl From<std::io::Error> for RegistryError {
+    fn from(e: std::io::Error) -> Self {
+        RegistryError::Io(e)
+    }
+}
+
+impl From<SchemaError> for RegistryError {
+    fn from(e: SchemaError) -> Self {
+        RegistryError::Schema(e)
+    }
+}
+
+/// One entry in the registry listing returned by `DbRegistry::list`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct DatabaseEntry {
+    pub project_root_name: Option<String>,
+    pub path: String,
+    pub active: bool,
+    pub open: bool,
+}
+
+struct RegistryState {
+    handles: BTreeMap<PathBuf, DbHandle>,
+    active_path: PathBuf,
+    active_project: Option<String>,
+}
+
+/// Keeps track of the databases the server can work against and which one is active.
+///
+/// In `single` mode there is exactly one database and opening a project only changes the label.
+/// In `per-project` mode every project root gets its own file under `projects_dir`; the default
+/// database stays active until the first project is opened.
+pub struct DbRegistry {
+    mode: DbMode,
+    default_path: PathBuf,
+    projects_dir: PathBuf,
+    state: Mutex<RegistryState>,
+}
+
+impl DbRegistry {
+    /// Opens (and migrates) the default database and makes it active.
+    /// Must be called from within a Tokio runtime, which runs the database's checkpointer.
+    pub fn new(config: &DatabaseConfig) -> Result<Self, RegistryError> {
+        let mut handles = BTreeMap::new();
+        handles.insert(config.path.clone(), open_pool(config.path.clone())?);
+        Ok(DbRegistry {
+            mode: config.mode,
+            default_path: config.path.clone(),
+            projects_dir: config.projects_dir.clone(),
+            state: Mutex::new(RegistryState {
+                handles,
+                active_path: config.path.clone(),
+                active_project: None,
+            }),
+        })
+    }
+
+    pub fn mode(&self) -> DbMode {
+        self.mode
+    }
+
+    /// The database file a project root maps to in `per-project` mode. The name is sanitised for
+    /// the file system and suffixed with a hash so distinct names never share a file.
+    pub fn project_db_path(&self, project_root_name: &str) -> PathBuf {
+        let safe_name: String = project_root_name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+            .collect();
+        let digest = hex::encode(Sha256::digest(project_root_name.as_bytes()));
+        self.projects_dir.join(format!("{}-{}.sqlite3", safe_name, &digest[..8]))
+    }
+
+    /// The currently active database.
+    pub async fn active(&self) -> DbHandle {
+        let state = self.state.lock().await;
+        state.handles[&state.active_path].clone()
+    }
+
+    /// Makes `project_root_name` the active project, opening (and creating) its database first
+    /// when running in `per-project` mode.
+    pub async fn open_project(&self, project_root_name: &str) -> Result<DbHandle, RegistryError> {
+        let path = match self.mode {
+            DbMode::Single => self.default_path.clone(),
+            DbMode::PerProject => self.project_db_path(project_root_name),
+        };
+
+        let mut state = self.state.lock().await;
+        if !state.handles.contains_key(&path) {
+            std::fs::create_dir_all(&self.projects_dir)?;
+            println!("[DB_REGISTRY] Opening database for project '{}' at '{}'", project_root_name, path.display());
+            let open_path = path.clone();
+            let pool = tokio::task::spawn_blocking(move || open_pool(open_path))
+                .await
+                .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?;
+            state.handles.insert(path.clone(), pool);
+            self.remember_project(project_root_name, &path)?;
+        }
+        state.active_path = path.clone();
+        state.active_project = Some(project_root_name.to_string());
+        Ok(state.handles[&path].clone())
+    }
+
+    /// Like `open_project`, but never creates a database: in `per-project` mode a project root
+    /// that has no database in the registry yet gives `None`.
+    pub async fn open_known_project(&self, project_root_name: &str) -> Result<Option<DbHandle>, RegistryError> {
+        if self.mode == DbMode::PerProject && !self.read_registry()?.contains_key(project_root_name) {
+            return Ok(None);
+        }
+        self.open_project(project_root_name).await.map(Some)
+    }
+
+    /// Lists the default database and every project database recorded in the registry file.
+    pub async fn list(&self) -> Result<Vec<DatabaseEntry>, RegistryError> {
+        let state = self.state.lock().await;
+        let entry = |project: Option<String>, path: &Path| DatabaseEntry {
+            active: state.active_path == path,
+            open: state.handles.contains_key(path),
+            path: path.display().to_string(),
+            project_root_name: project,
+        };
+
+        let mut entries = vec![entry(
+            if self.mode == DbMode::Single { state.active_project.clone() } else { None },
+            &self.default_path,
+        )];
+        if self.mode == DbMode::PerProject {
+            for (project, file_name) in self.read_registry()? {
+                entries.push(entry(Some(project), &self.projects_dir.join(file_name)));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read_registry(&self) -> Result<BTreeMap<String, String>, RegistryError> {
+        match std::fs::read_to_string(self.projects_dir.join(REGISTRY_FILE)) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| RegistryError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn remember_project(&self, project_root_name: &str, path: &Path) -> Result<(), RegistryError> {
+        let mut registry = self.read_registry()?;
+        let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        if registry.get(project_root_name) != Some(&file_name) {
+            registry.insert(project_root_name.to_string(), file_name);
+            let text = serde_json::to_string_pretty(&registry)
+                .map_err(|e| RegistryError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+            std::fs::write(self.projects_dir.join(REGISTRY_FILE), text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens a pool for the database at `path` and starts its background checkpointer.
+fn open_pool(path: PathBuf) -> Result<DbHandle, RegistryError> {
+    let pool = Arc::new(DbPool::open(&path, db_pool::DEFAULT_MAX_READERS)?);
+    db_pool::spawn_checkpointer(&pool, db_pool::CHECKPOINT_INTERVAL);
+    Ok(pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The schema exactly as `initialize_database` created it before migrations existed.
+    const BASELINE_SCHEMA_SQL: &str = "
+        CREATE TABLE ProjectVersions (
             version_id INTEGER PRIMARY KEY AUTOINCREMENT,
             parent_version_id INTEGER,
             timestamp TEXT NOT NULL,
             description TEXT,
-            CONSTRAINT fk_parent_version
-                FOREIGN KEY (parent_version_id)
-                REFERENCES ProjectVersions (version_id)
-                ON DELETE CASCADE
+            CONSTRAINT fk_parent_version FOREIGN KEY (parent_version_id) REFERENCES ProjectVersions (version_id) ON DELETE CASCADE
         );
-        CREATE TABLE IF NOT EXISTS VersionFiles (
+        CREATE TABLE VersionFiles (
             version_file_id INTEGER PRIMARY KEY AUTOINCREMENT,
             project_version_id INTEGER NOT NULL,
             file_path TEXT NOT NULL,
             content_hash TEXT NOT NULL,
             file_size INTEGER NOT NULL,
-            CONSTRAINT fk_project_version
-                FOREIGN KEY (project_version_id)
-                REFERENCES ProjectVersions (version_id)
-                ON DELETE CASCADE,
+            CONSTRAINT fk_project_version FOREIGN KEY (project_version_id) REFERENCES ProjectVersions (version_id) ON DELETE CASCADE,
             UNIQUE (project_version_id, file_path)
         );
-        CREATE TABLE IF NOT EXISTS OperationLog (
+        CREATE TABLE OperationLog (
             log_id INTEGER PRIMARY KEY AUTOINCREMENT,
             linked_project_version_id INTEGER,
             timestamp TEXT NOT NULL,
@@ -66,9 +512,139 @@ pub fn initialize_database(conn: &Connection) -> RusqliteResult<()> {
             content_hash_before TEXT,
             content_hash_after TEXT,
             details_json TEXT
-        );
-        COMMIT;"
-    )?;
-    println!("[DB_SCHEMA] Schema initialization SQL batch executed for '{}'.", canonical_path_display);
-    Ok(())
-}
\ No newline at end of file
+        );";
+
+    fn table_exists(conn: &Connection, name: &str) -> bool {
+        conn.prepare("SELECT 1 FROM sqlite_master WHERE type='table' AND name = ?1")
+            .unwrap()
+            .exists([name])
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fresh_database_migrates_to_latest() {
+        let conn = Connection::open_in_memory().unwrap();
+        initialize_database(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_SCHEMA_VERSION);
+        for table in ["ProjectVersions", "VersionFiles", "OperationLog", "Blobs", "FileDiffs", "Projects", "UsageLedger", "LlmCache"] {
+            assert!(table_exists(&conn, table), "missing table {}", table);
+        }
+        // Re-running is a no-op.
+        assert_eq!(run_migrations(&conn).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_upgrades_baseline_schema_and_keeps_data() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(BASELINE_SCHEMA_SQL).unwrap();
+        conn.execute_batch(
+            "INSERT INTO ProjectVersions (parent_version_id, timestamp, description) VALUES (NULL, '2024-01-01T00:00:00Z', 'Initial');
+             INSERT INTO VersionFiles (project_version_id, file_path, content_hash, file_size) VALUES (1, 'P/a.txt', 'h', 1);
+             INSERT INTO OperationLog (linked_project_version_id, timestamp, operation_type, target_entity) VALUES (1, '2024-01-01T00:00:00Z', 'PROJECT_SNAPSHOT_INITIAL', 'P');
+             INSERT INTO ProjectVersions (parent_version_id, timestamp, description) VALUES (1, '2024-01-02T00:00:00Z', 'Patch');
+             INSERT INTO ProjectVersions (parent_version_id, timestamp, description) VALUES (NULL, '2024-01-03T00:00:00Z', 'Initial snapshot of project: P');",
+        )
+        .unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        assert_eq!(run_migrations(&conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(schema_version(&conn).unwrap(), LATEST_SCHEMA_VERSION);
+        assert!(table_exists(&conn, "FileDiffs"));
+        let files: i64 = conn.query_row("SELECT COUNT(*) FROM VersionFiles", [], |row| row.get(0)).unwrap();
+        assert_eq!(files, 1);
+
+        // Each legacy root became its own project and the child inherited its root's project.
+        let mut stmt = conn.prepare(
+            "SELECT pv.version_id, p.display_name FROM ProjectVersions pv JOIN Projects p USING (project_id) ORDER BY pv.version_id",
+        ).unwrap();
+        let owners: Vec<(i64, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(owners, vec![
+            (1, "P".to_string()),
+            (2, "P".to_string()),
+            (3, "Initial snapshot of project: P".to_string()),
+        ]);
+        let projects: i64 = conn.query_row("SELECT COUNT(*) FROM Projects", [], |row| row.get(0)).unwrap();
+        assert_eq!(projects, 2);
+
+        // Pre-existing log rows were chained.
+        let report = operation_log::verify_log_chain(&conn).unwrap();
+        assert!(report.intact);
+        assert_eq!(report.entries_checked, 1);
+    }
+
+    #[test]
+    fn test_upgrades_committed_baseline_database_file() {
+        let source = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(".diranalyze_db.sqlite3");
+        let copy = std::env::temp_dir().join(format!("diranalyze_migration_test_{}.sqlite3", std::process::id()));
+        std::fs::copy(&source, &copy).unwrap();
+
+        let conn = open_db_connection_with_path(copy.to_str().unwrap()).unwrap();
+        let result = initialize_database(&conn).map(|_| schema_version(&conn).unwrap());
+        drop(conn);
+        let _ = std::fs::remove_file(&copy);
+        assert_eq!(result.unwrap(), LATEST_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_refuses_newer_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!("PRAGMA user_version = {};", LATEST_SCHEMA_VERSION + 1)).unwrap();
+        match initialize_database(&conn) {
+            Err(SchemaError::TooNew { found, supported }) => {
+                assert_eq!((found, supported), (LATEST_SCHEMA_VERSION + 1, LATEST_SCHEMA_VERSION));
+            }
+            other => panic!("expected TooNew, got {:?}", other),
+        }
+        assert!(!table_exists(&conn, "ProjectVersions"));
+    }
+
+    #[test]
+    fn test_failed_migration_rolls_back() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(BASELINE_SCHEMA_SQL).unwrap();
+        // A stray table with the same name makes migration 2 fail.
+        conn.execute_batch("CREATE TABLE Blobs (x INTEGER);").unwrap();
+        assert!(run_migrations(&conn).is_err());
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+        assert!(!table_exists(&conn, "FileDiffs"));
+    }
+
+    #[tokio::test]
+    async fn test_per_project_registry_switches_databases() {
+        let dir = std::env::temp_dir().join(format!("diranalyze_registry_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DatabaseConfig {
+            path: dir.join("default.sqlite3"),
+            mode: DbMode::PerProject,
+            projects_dir: dir.join("projects"),
+        };
+        let registry = DbRegistry::new(&config).unwrap();
+
+        let alpha = registry.open_project("Alpha").await.unwrap();
+        alpha.write(|conn| conn.execute("INSERT INTO ProjectVersions (timestamp) VALUES ('t')", [])).await.unwrap();
+        registry.open_project("Beta").await.unwrap();
+        let active_count: i64 = registry
+            .active()
+            .await
+            .read(|conn| conn.query_row("SELECT COUNT(*) FROM ProjectVersions", [], |row| row.get(0)))
+            .await
+            .unwrap();
+        assert_eq!(active_count, 0);
+
+        let entries = registry.list().await.unwrap();
+        let projects: Vec<_> = entries.iter().filter_map(|e| e.project_root_name.as_deref()).collect();
+        assert_eq!(projects, vec!["Alpha", "Beta"]);
+        assert!(entries.iter().any(|e| e.active && e.project_root_name.as_deref() == Some("Beta")));
+        assert_ne!(registry.project_db_path("a b"), registry.project_db_path("a_b"));
+
+        assert!(registry.open_known_project("Gamma").await.unwrap().is_none());
+        assert!(!registry.project_db_path("Gamma").exists());
+        registry.open_known_project("Alpha").await.unwrap().unwrap();
+        assert!(registry.list().await.unwrap().iter().any(|e| e.active && e.project_root_name.as_deref() == Some("Alpha")));
+
+        drop(alpha);
+        drop(registry);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
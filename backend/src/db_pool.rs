@@ -0,0 +1,227 @@
+// diranalyze/backend/src/db_pool.rs
+
+use rusqlite::{Connection, OpenFlags, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::{Mutex, Semaphore};
+use crate::db_manage::{self, SchemaError};
+
+/// Upper bound on read-only connections kept open per database.
+pub const DEFAULT_MAX_READERS: usize = 4;
+/// How often the background checkpointer folds the WAL back into the main database file.
+pub const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Connections to one SQLite database: a single writer plus a small pool of read-only readers.
+///
+/// The database runs in WAL mode (set by `db_manage` at open time), so readers never wait for
+/// the writer and see the last committed state. Writes are serialised on the writer connection.
+/// All SQLite work runs on the blocking thread pool via `spawn_blocking`, never on the executor.
+pub struct DbPool {
+    /// `None` for in-memory databases, which cannot be shared across connections; reads then
+    /// go through the writer.
+    path: Option<PathBuf>,
+    writer: Arc<Mutex<Connection>>,
+    idle_readers: std::sync::Mutex<Vec<Connection>>,
+    reader_permits: Arc<Semaphore>,
+}
+
+impl DbPool {
+    /// Opens (and migrates) the database at `path`. Reader connections are opened on demand,
+    /// up to `max_readers` at a time.
+    pub fn open(path: &Path, max_readers: usize) -> std::result::Result<Self, SchemaError> {
+        let writer = db_manage::open_and_initialize(path)?;
+        Ok(DbPool {
+            path: Some(path.to_path_buf()),
+            writer: Arc::new(Mutex::new(writer)),
+            idle_readers: std::sync::Mutex::new(Vec::new()),
+            reader_permits: Arc::new(Semaphore::new(max_readers.max(1))),
+        })
+    }
+
+    /// Wraps a single already-initialised connection, used for both reads and writes.
+    /// Meant for in-memory databases in tests.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn from_connection(conn: Connection) -> Self {
+        DbPool {
+            path: None,
+            writer: Arc::new(Mutex::new(conn)),
+            idle_readers: std::sync::Mutex::new(Vec::new()),
+            reader_permits: Arc::new(Semaphore::new(1)),
+        }
+    }
+
+    /// Runs `f` on a read-only connection. Any number of reads (up to the reader limit) run
+    /// concurrently with each other and with a write.
+    pub async fn read<T, F>(self: &Arc<Self>, f: F) -> Result<T>
+    where
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let Some(path) = self.path.clone() else {
+            return self.write(move |conn| f(conn)).await;
+        };
+        let permit = self.reader_permits.clone().acquire_owned().await.expect("reader semaphore is never closed");
+        let pool = Arc::clone(self);
+        run_blocking(move || {
+            let _permit = permit;
+            let idle = pool.idle_readers.lock().unwrap_or_else(|e| e.into_inner()).pop();
+            let conn = match idle {
+                Some(conn) => conn,
+                None => open_reader(&path)?,
+            };
+            let result = f(&conn);
+            pool.idle_readers.lock().unwrap_or_else(|e| e.into_inner()).push(conn);
+            result
+        })
+        .await
+    }
+
+    /// Runs `f` on the writer connection. Writes queue up behind each other without holding a
+    /// blocking thread while they wait.
+    pub async fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut guard = Arc::clone(&self.writer).lock_owned().await;
+        run_blocking(move || f(&mut guard)).await
+    }
+
+    /// Checkpoints the WAL without blocking readers or writers, then lets SQLite refresh its
+    /// query planner statistics.
+    ///
+    /// # Returns
+    /// `(busy, wal_frames, checkpointed_frames)` as reported by `PRAGMA wal_checkpoint`.
+    pub async fn checkpoint(&self) -> Result<(i64, i64, i64)> {
+        self.write(|conn| {
+            let counts = conn.query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?;
+            conn.execute_batch("PRAGMA optimize;")?;
+            Ok(counts)
+        })
+        .await
+    }
+
+    fn describe(&self) -> String {
+        self.path.as_ref().map_or_else(|| "(in-memory)".to_string(), |p| p.display().to_string())
+    }
+}
+
+fn open_reader(path: &Path) -> Result<Connection> {
+    let conn = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI,
+    )?;
+    conn.busy_timeout(db_manage::BUSY_TIMEOUT)?;
+    Ok(conn)
+}
+
+/// Runs blocking SQLite work off the executor. A panic inside `f` is re-raised in the caller.
+async fn run_blocking<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(value) => value,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Starts the background checkpointer for `pool`. It stops on its own once the pool is dropped;
+/// SQLite then checkpoints and removes the WAL when the last connection closes.
+/// Must be called from within a Tokio runtime.
+pub fn spawn_checkpointer(pool: &Arc<DbPool>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    let pool: Weak<DbPool> = Arc::downgrade(pool);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await; // The first tick completes immediately.
+        loop {
+            ticker.tick().await;
+            let Some(pool) = pool.upgrade() else { break };
+            match pool.checkpoint().await {
+                Ok((busy, wal_frames, checkpointed)) if busy != 0 || checkpointed < wal_frames => println!(
+                    "[DB_CHECKPOINT] Partial checkpoint for '{}': {}/{} WAL frames written back.",
+                    pool.describe(), checkpointed, wal_frames
+                ),
+                Ok(_) => {}
+                Err(e) => eprintln!("[DB_CHECKPOINT] Checkpoint failed for '{}': {:?}", pool.describe(), e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("diranalyze_pool_{}_{}.sqlite3", name, std::process::id()));
+        remove_db_files(&path);
+        path
+    }
+
+    fn remove_db_files(path: &Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_readers_see_committed_writes_in_wal_mode() {
+        let path = temp_db_path("wal");
+        let pool = Arc::new(DbPool::open(&path, 3).unwrap());
+
+        let journal_mode: String = pool.read(|conn| conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))).await.unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        pool.write(|conn| conn.execute("INSERT INTO ProjectVersions (timestamp) VALUES ('t')", [])).await.unwrap();
+
+        // A long write in progress does not hold up readers.
+        let writer = Arc::clone(&pool);
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let slow_write = tokio::spawn(async move {
+            writer
+                .write(move |conn| {
+                    let tx = conn.transaction()?;
+                    tx.execute("INSERT INTO ProjectVersions (timestamp) VALUES ('u')", [])?;
+                    started_tx.send(()).unwrap();
+                    std::thread::sleep(Duration::from_millis(300));
+                    tx.commit()
+                })
+                .await
+        });
+        started_rx.await.unwrap();
+        let reads = (0..6).map(|_| {
+            let pool = Arc::clone(&pool);
+            tokio::spawn(async move {
+                pool.read(|conn| conn.query_row("SELECT COUNT(*) FROM ProjectVersions", [], |row| row.get::<_, i64>(0))).await
+            })
+        });
+        for read in reads {
+            assert_eq!(read.await.unwrap().unwrap(), 1);
+        }
+        assert!(!slow_write.is_finished());
+        slow_write.await.unwrap().unwrap();
+
+        let count: i64 = pool.read(|conn| conn.query_row("SELECT COUNT(*) FROM ProjectVersions", [], |row| row.get(0))).await.unwrap();
+        assert_eq!(count, 2);
+        let (busy, _, _) = pool.checkpoint().await.unwrap();
+        assert_eq!(busy, 0);
+
+        drop(pool);
+        remove_db_files(&path);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_pool_reads_through_writer() {
+        let conn = Connection::open_in_memory().unwrap();
+        db_manage::initialize_database(&conn).unwrap();
+        let pool = Arc::new(DbPool::from_connection(conn));
+        pool.write(|conn| conn.execute("INSERT INTO ProjectVersions (timestamp) VALUES ('t')", [])).await.unwrap();
+        let count: i64 = pool.read(|conn| conn.query_row("SELECT COUNT(*) FROM ProjectVersions", [], |row| row.get(0))).await.unwrap();
+        assert_eq!(count, 1);
+    }
+}
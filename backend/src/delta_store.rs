@@ -0,0 +1,249 @@
+// diranalyze/backend/src/delta_store.rs
+
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use chrono::Utc;
+use crate::blob_store;
+
+/// Longest run of deltas allowed before a file is stored as a full blob again.
+/// Bounds the work needed to reconstruct any single version of a file.
+pub const MAX_DELTA_CHAIN_LENGTH: i64 = 16;
+
+fn corrupt_delta(message: String) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, message.into())
+}
+
+/// Number of deltas that must be applied to rebuild `content_hash`: 0 for a full blob,
+/// `None` if the content is not stored at all.
+fn chain_depth(conn: &Connection, content_hash: &str) -> Result<Option<i64>> {
+    if blob_store::has_blob(conn, content_hash)? {
+        return Ok(Some(0));
+    }
+    conn.query_row(
+        "SELECT chain_depth FROM FileDiffs WHERE target_content_hash = ?1 LIMIT 1",
+        params![content_hash],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Returns whether the content for this hash can be produced, either from a blob or a delta chain.
+pub fn has_content(conn: &Connection, content_hash: &str) -> Result<bool> {
+    Ok(chain_depth(conn, content_hash)?.is_some())
+}
+
+/// Stores a file body for `file_path` in `target_version_id`.
+///
+/// If `source_hash` names the file's previous content and both bodies are UTF-8 text, a
+/// unified diff against it is written to `FileDiffs` instead of a full blob, provided the
+/// diff is smaller, round-trips exactly, and the chain stays under `MAX_DELTA_CHAIN_LENGTH`.
+/// Otherwise the body is written to `Blobs`, which also re-anchors the chain.
+///
+/// # Returns
+/// The SHA-256 hash of `content`.
+pub fn put_content(
+    conn: &Connection,
+    target_version_id: i64,
+    file_path: &str,
+    content: &[u8],
+    source_hash: Option<&str>,
+) -> Result<String> {
+    let target_hash = blob_store::sha256_hex(content);
+    if has_content(conn, &target_hash)? {
+        return Ok(target_hash);
+    }
+
+    if let Some(source_hash) = source_hash {
+        if let Some(diff_content) = build_delta(conn, source_hash, content)? {
+            let depth = chain_depth(conn, source_hash)?.unwrap_or_default() + 1;
+            conn.execute(
+                "INSERT INTO FileDiffs (target_project_version_id, file_path, diff_content, source_content_hash, target_content_hash, chain_depth, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    target_version_id,
+                    file_path,
+                    diff_content,
+                    source_hash,
+                    target_hash,
+                    depth,
+                    Utc::now().to_rfc3339()
+                ],
+            )?;
+            return Ok(target_hash);
+        }
+    }
+
+    blob_store::put_blob(conn, content)
+}
+
+/// Copies into `Blobs` every body that is stored only as a delta of one of `project_id`'s
+/// versions but is still needed by another project, either as a file of one of its versions or
+/// as the source of one of its deltas. `put_content` shares bodies across projects by hash, so
+/// this must run before the project's versions, and with them its `FileDiffs` rows, are deleted.
+///
+/// # Returns
+/// The number of bodies copied.
+pub fn preserve_shared_contents(conn: &Connection, project_id: i64) -> Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT d.target_content_hash FROM FileDiffs d
+         JOIN ProjectVersions v ON v.version_id = d.target_project_version_id
+         WHERE v.project_id = ?1
+           AND NOT EXISTS (SELECT 1 FROM Blobs b WHERE b.content_hash = d.target_content_hash)
+           AND (EXISTS (SELECT 1 FROM VersionFiles f
+                        JOIN ProjectVersions fv ON fv.version_id = f.project_version_id
+                        WHERE fv.project_id IS NOT ?1 AND f.content_hash = d.target_content_hash)
+                OR EXISTS (SELECT 1 FROM FileDiffs od
+                           JOIN ProjectVersions ov ON ov.version_id = od.target_project_version_id
+                           WHERE ov.project_id IS NOT ?1 AND od.source_content_hash = d.target_content_hash))",
+    )?;
+    let shared: Vec<String> = stmt.query_map(params![project_id], |row| row.get(0))?.collect::<Result<_>>()?;
+    for content_hash in &shared {
+        let content = get_content(conn, content_hash)?
+            .ok_or_else(|| corrupt_delta(format!("Delta for {} vanished while preserving it", content_hash)))?;
+        blob_store::put_blob(conn, &content)?;
+    }
+    Ok(shared.len())
+}
+
+/// Produces the diff text from `source_hash` to `content`, or `None` when a full blob should be
+/// stored instead.
+fn build_delta(conn: &Connection, source_hash: &str, content: &[u8]) -> Result<Option<String>> {
+    match chain_depth(conn, source_hash)? {
+        Some(depth) if depth < MAX_DELTA_CHAIN_LENGTH => {}
+        _ => return Ok(None),
+    }
+    let Ok(target_text) = std::str::from_utf8(content) else {
+        return Ok(None);
+    };
+    let Some(source_bytes) = get_content(conn, source_hash)? else {
+        return Ok(None);
+    };
+    let Ok(source_text) = String::from_utf8(source_bytes) else {
+        return Ok(None);
+    };
+
+    let diff_content = diffy::create_patch(&source_text, target_text).to_string();
+    if diff_content.len() >= content.len() {
+        return Ok(None);
+    }
+    // Only keep deltas we have proven to reproduce the exact bytes.
+    let round_trips = diffy::Patch::from_str(&diff_content)
+        .ok()
+        .and_then(|patch| diffy::apply(&source_text, &patch).ok())
+        .is_some_and(|rebuilt| rebuilt == target_text);
+    Ok(round_trips.then_some(diff_content))
+}
+
+/// Loads the content for `content_hash`, walking the delta chain back to the nearest full
+/// blob and validating every step against its `source_content_hash`/`target_content_hash`.
+///
+/// # Returns
+/// The content, `None` if it is not stored, or an error if the chain is broken.
+pub fn get_content(conn: &Connection, content_hash: &str) -> Result<Option<Vec<u8>>> {
+    // Walk back to a full blob, remembering the deltas to replay.
+    let mut pending: Vec<(String, String)> = Vec::new(); // (diff_content, target_content_hash)
+    let mut current_hash = content_hash.to_string();
+    let base = loop {
+        if let Some(content) = blob_store::get_blob(conn, &current_hash)? {
+            break content;
+        }
+        if pending.len() as i64 > MAX_DELTA_CHAIN_LENGTH {
+            return Err(corrupt_delta(format!("Delta chain for {} exceeds the maximum length", content_hash)));
+        }
+        let delta: Option<(String, String)> = conn
+            .query_row(
+                "SELECT diff_content, source_content_hash FROM FileDiffs WHERE target_content_hash = ?1 LIMIT 1",
+                params![current_hash],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        match delta {
+            Some((diff_content, source_hash)) => {
+                pending.push((diff_content, current_hash));
+                current_hash = source_hash;
+            }
+            None if pending.is_empty() => return Ok(None),
+            None => {
+                return Err(corrupt_delta(format!(
+                    "Delta chain for {} references missing content {}",
+                    content_hash, current_hash
+                )))
+            }
+        }
+    };
+
+    let mut content = base;
+    for (diff_content, target_hash) in pending.into_iter().rev() {
+        let source_text = String::from_utf8(content)
+            .map_err(|_| corrupt_delta(format!("Delta source for {} is not UTF-8 text", target_hash)))?;
+        let patch = diffy::Patch::from_str(&diff_content)
+            .map_err(|e| corrupt_delta(format!("Unreadable delta for {}: {}", target_hash, e)))?;
+        let rebuilt = diffy::apply(&source_text, &patch)
+            .map_err(|e| corrupt_delta(format!("Delta for {} does not apply: {}", target_hash, e)))?;
+        if blob_store::sha256_hex(rebuilt.as_bytes()) != target_hash {
+            return Err(corrupt_delta(format!("Delta for {} produced content with a different hash", target_hash)));
+        }
+        content = rebuilt.into_bytes();
+    }
+    Ok(Some(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_manage;
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().expect("Failed to open in-memory DB");
+        db_manage::initialize_database(&conn).expect("Failed to initialize test DB schema");
+        conn
+    }
+
+    fn insert_version(conn: &Connection) -> i64 {
+        conn.execute("INSERT INTO ProjectVersions (timestamp) VALUES ('2024-01-01T00:00:00Z')", []).unwrap();
+        conn.last_insert_rowid()
+    }
+
+    fn numbered_lines(edit: usize) -> String {
+        (0..200).map(|i| if i == edit { format!("line {} edited\n", i) } else { format!("line {}\n", i) }).collect()
+    }
+
+    #[test]
+    fn test_delta_chain_reconstructs_and_reanchors() {
+        let conn = setup_test_db();
+        let first = numbered_lines(usize::MAX);
+        let mut previous_hash = put_content(&conn, insert_version(&conn), "P/f.txt", first.as_bytes(), None).unwrap();
+        let mut hashes = vec![(previous_hash.clone(), first)];
+
+        for step in 0..(MAX_DELTA_CHAIN_LENGTH as usize + 2) {
+            let body = numbered_lines(step);
+            previous_hash = put_content(&conn, insert_version(&conn), "P/f.txt", body.as_bytes(), Some(&previous_hash)).unwrap();
+            hashes.push((previous_hash.clone(), body));
+        }
+
+        // Every version comes back byte-for-byte.
+        for (hash, body) in &hashes {
+            assert_eq!(get_content(&conn, hash).unwrap().as_deref(), Some(body.as_bytes()));
+        }
+
+        // One full blob at the start, one re-anchor once the chain hit its limit.
+        let blob_count: i64 = conn.query_row("SELECT COUNT(*) FROM Blobs", [], |row| row.get(0)).unwrap();
+        assert_eq!(blob_count, 2);
+        let max_depth: i64 = conn.query_row("SELECT MAX(chain_depth) FROM FileDiffs", [], |row| row.get(0)).unwrap();
+        assert_eq!(max_depth, MAX_DELTA_CHAIN_LENGTH);
+    }
+
+    #[test]
+    fn test_binary_content_is_stored_as_blob() {
+        let conn = setup_test_db();
+        let base = [0u8, 159, 146, 150].repeat(300);
+        let mut changed = base.clone();
+        changed[10] = 1;
+        let base_hash = put_content(&conn, insert_version(&conn), "P/img.bin", &base, None).unwrap();
+        let changed_hash = put_content(&conn, insert_version(&conn), "P/img.bin", &changed, Some(&base_hash)).unwrap();
+
+        let diff_count: i64 = conn.query_row("SELECT COUNT(*) FROM FileDiffs", [], |row| row.get(0)).unwrap();
+        assert_eq!(diff_count, 0);
+        assert!(blob_store::has_blob(&conn, &changed_hash).unwrap());
+        assert!(!has_content(&conn, "missing").unwrap());
+    }
+}
@@ -0,0 +1,129 @@
+// diranalyze/backend/src/live_events.rs
+
+use rusqlite::{Connection, Result};
+use tokio::sync::broadcast;
+use crate::db_manage::DbHandle;
+use crate::operation_log::{self, LogFilter, LoggedOperation};
+
+/// Events buffered per subscriber. A client that falls further behind is told how many it missed.
+pub const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Something that happened on the backend, pushed to every connected `/ws` client.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LiveEvent {
+    /// A row was appended to the `OperationLog` of the active database, hashes included.
+    OperationLogged { operation: Box<LoggedOperation> },
+    /// A new version was stored. `parent_version_id` is `None` for an initial snapshot.
+    SnapshotCreated {
+        project_id: Option<i64>,
+        version_id: i64,
+        parent_version_id: Option<i64>,
+        files_count: usize,
+    },
+    RestoreRecorded {
+        project_id: i64,
+        version_id: i64,
+        restored_version_id: i64,
+    },
+    /// A project was opened and its database is now the active one.
+    DatabaseOpened { project_root_name: String },
+}
+
+/// Fan-out of `LiveEvent`s to any number of subscribers. Cheap to clone; all clones share one channel.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<LiveEvent>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus { sender }
+    }
+
+    /// Sends `event` to current subscribers. Having none is not an error.
+    pub fn publish(&self, event: LiveEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Receives every event published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Runs `f` on the writer of `db` and, once it succeeded, publishes every `OperationLog` row
+    /// it appended. Writes are serialised, so the new rows are exactly those past the log head
+    /// seen before `f` ran.
+    pub async fn write_logged<T, F>(&self, db: &DbHandle, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (value, operations) = db
+            .write(move |conn| {
+                let last_log_id: i64 = conn.query_row("SELECT COALESCE(MAX(log_id), 0) FROM OperationLog", [], |row| row.get(0))?;
+                let value = f(conn)?;
+                // The write is already committed here, so a failed read only costs the live update.
+                let operations = operation_log::read_operations(conn, &LogFilter::default(), last_log_id, i64::MAX)
+                    .unwrap_or_else(|e| {
+                        eprintln!("--> LIVE_EVENTS: Could not read new OperationLog entries: {:?}", e);
+                        Vec::new()
+                    });
+                Ok((value, operations))
+            })
+            .await?;
+        for operation in operations {
+            self.publish(LiveEvent::OperationLogged { operation: Box::new(operation) });
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_manage;
+    use crate::db_pool::DbPool;
+    use crate::operation_log::{OperationEvent, OperationLogEntry};
+    use std::sync::Arc;
+
+    fn entry(project_name: &str) -> OperationLogEntry {
+        OperationLogEntry {
+            linked_project_version_id: None,
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            event: OperationEvent::ProjectSnapshotInitial { project_name: project_name.to_string(), files_count: 0, total_size: 0 },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_logged_publishes_only_new_operations() {
+        let conn = Connection::open_in_memory().unwrap();
+        db_manage::initialize_database(&conn).unwrap();
+        operation_log::log_operation(&conn, &entry("before")).unwrap();
+        let db: DbHandle = Arc::new(DbPool::from_connection(conn));
+        let bus = EventBus::new(EVENT_CHANNEL_CAPACITY);
+        let mut events = bus.subscribe();
+
+        let log_id = bus.write_logged(&db, |conn| operation_log::log_operation(conn, &entry("during"))).await.unwrap();
+        assert_eq!(log_id, 2);
+        match events.try_recv().unwrap() {
+            LiveEvent::OperationLogged { operation } => {
+                assert_eq!(operation.log_id, 2);
+                assert_eq!(operation.event.typed(), Some(&entry("during").event));
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert!(events.try_recv().is_err());
+
+        // A failed write publishes nothing.
+        let failed = bus
+            .write_logged(&db, |conn| {
+                operation_log::log_operation(conn, &entry("rolled back"))?;
+                Err::<(), _>(rusqlite::Error::QueryReturnedNoRows)
+            })
+            .await;
+        assert!(failed.is_err());
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,297 @@
+// diranalyze/backend/src/llm_audit.rs
+
+use futures_util::Stream;
+use rusqlite::{Connection, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use chrono::Utc;
+use crate::blob_store;
+use crate::llm_provider::{ChatRequest, ChatResponse, ChatStream, LlmError, Provider};
+use crate::llm_stream::ChatStreamEvent;
+use crate::operation_log::{self, OperationEvent, OperationLogEntry};
+
+/// How an LLM call ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LlmCallOutcome {
+    Completed(ChatResponse),
+    /// Answered from the response cache without contacting the provider.
+    Cached(ChatResponse),
+    Failed { status_code: Option<u16>, error: String },
+}
+
+impl LlmCallOutcome {
+    pub fn from_error(error: &LlmError) -> Self {
+        let status_code = match error {
+            LlmError::Upstream { status, .. } => Some(*status),
+            _ => None,
+        };
+        LlmCallOutcome::Failed { status_code, error: error.to_string() }
+    }
+}
+
+/// The canonical form of a request: the provider-neutral request with provider and model
+/// resolved and `stream` cleared, as compact JSON with sorted keys. The same question hashes the
+/// same whether it was streamed or not, and whichever way the client spelled the defaults.
+pub fn canonical_request(provider: &str, model: &str, request: &ChatRequest) -> String {
+    let canonical = ChatRequest {
+        provider: Some(provider.to_string()),
+        model: Some(model.to_string()),
+        stream: false,
+        ..request.clone()
+    };
+    serde_json::to_value(&canonical).expect("chat requests always serialize").to_string()
+}
+
+/// The canonical form of a response: the provider-neutral `ChatResponse` as compact JSON with sorted keys.
+pub fn canonical_response(response: &ChatResponse) -> String {
+    serde_json::to_value(response).expect("chat responses always serialize").to_string()
+}
+
+/// An LLM call in flight. Start it before the request is sent so the latency covers the whole call.
+#[derive(Debug, Clone)]
+pub struct LlmCallAudit {
+    provider: String,
+    model: String,
+    request_json: String,
+    streamed: bool,
+    started: Instant,
+}
+
+impl LlmCallAudit {
+    pub fn start(provider: &Provider, request: &ChatRequest) -> std::result::Result<Self, LlmError> {
+        let model = provider.resolve_model(request)?;
+        Ok(LlmCallAudit {
+            request_json: canonical_request(&provider.name, &model, request),
+            provider: provider.name.clone(),
+            model,
+            streamed: request.stream,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn request_sha256(&self) -> String {
+        blob_store::sha256_hex(self.request_json.as_bytes())
+    }
+
+    /// Stops the clock.
+    pub fn finish(self, outcome: LlmCallOutcome) -> LlmCallRecord {
+        let latency_ms = self.started.elapsed().as_millis() as u64;
+        LlmCallRecord { audit: self, outcome, latency_ms }
+    }
+}
+
+/// A finished LLM call, ready to be written as an `LLM_CALL` entry.
+#[derive(Debug, Clone)]
+pub struct LlmCallRecord {
+    audit: LlmCallAudit,
+    outcome: LlmCallOutcome,
+    latency_ms: u64,
+}
+
+impl LlmCallRecord {
+    pub fn audit(&self) -> &LlmCallAudit {
+        &self.audit
+    }
+
+    /// The reply the provider produced, if the call completed upstream (cache hits excluded).
+    pub fn fresh_response(&self) -> Option<&ChatResponse> {
+        match &self.outcome {
+            LlmCallOutcome::Completed(response) => Some(response),
+            _ => None,
+        }
+    }
+
+    pub fn event(&self, bodies_stored: bool) -> OperationEvent {
+        let audit = &self.audit;
+        let (model, response_sha256, status_code, error, usage) = match &self.outcome {
+            LlmCallOutcome::Completed(response) | LlmCallOutcome::Cached(response) => (
+                response.model.clone(),
+                Some(blob_store::sha256_hex(canonical_response(response).as_bytes())),
+                Some(200),
+                None,
+                response.usage,
+            ),
+            LlmCallOutcome::Failed { status_code, error } => (audit.model.clone(), None, *status_code, Some(error.clone()), None),
+        };
+        OperationEvent::LlmCall {
+            provider: audit.provider.clone(),
+            model,
+            request_sha256: audit.request_sha256(),
+            response_sha256,
+            status_code,
+            latency_ms: self.latency_ms,
+            error,
+            input_tokens: usage.map(|u| u.input_tokens),
+            output_tokens: usage.map(|u| u.output_tokens),
+            streamed: audit.streamed,
+            bodies_stored,
+            cached: matches!(self.outcome, LlmCallOutcome::Cached(_)),
+        }
+    }
+
+    /// Appends the `LLM_CALL` entry. With `store_bodies` the canonical request and response are
+    /// put in the blob store first, retrievable under the hashes the entry records.
+    pub fn write(&self, conn: &Connection, store_bodies: bool) -> Result<i64> {
+        if store_bodies {
+            blob_store::put_blob(conn, self.audit.request_json.as_bytes())?;
+            if let LlmCallOutcome::Completed(response) | LlmCallOutcome::Cached(response) = &self.outcome {
+                blob_store::put_blob(conn, canonical_response(response).as_bytes())?;
+            }
+        }
+        let entry = OperationLogEntry {
+            linked_project_version_id: None,
+            timestamp: Utc::now().to_rfc3339(),
+            event: self.event(store_bodies),
+        };
+        operation_log::log_operation(conn, &entry)
+    }
+}
+
+/// Wraps a chat stream so `on_finish` runs exactly once: with the assembled response, with the
+/// error that ended the stream, or as a failure when the stream is dropped early (for example
+/// because the client disconnected).
+pub fn audit_stream<F>(stream: ChatStream, on_finish: F) -> ChatStream
+where
+    F: FnOnce(LlmCallOutcome) + Send + 'static,
+{
+    Box::pin(AuditedStream { inner: stream, on_finish: Some(Box::new(on_finish)) })
+}
+
+struct AuditedStream {
+    inner: ChatStream,
+    on_finish: Option<Box<dyn FnOnce(LlmCallOutcome) + Send>>,
+}
+
+impl AuditedStream {
+    fn finish(&mut self, outcome: LlmCallOutcome) {
+        if let Some(on_finish) = self.on_finish.take() {
+            on_finish(outcome);
+        }
+    }
+}
+
+impl Stream for AuditedStream {
+    type Item = std::result::Result<ChatStreamEvent, LlmError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = futures_util::ready!(self.inner.as_mut().poll_next(cx));
+        match &item {
+            Some(Ok(ChatStreamEvent::Delta { .. })) => {}
+            Some(Ok(ChatStreamEvent::Done { response })) => self.finish(LlmCallOutcome::Completed(response.clone())),
+            Some(Err(e)) => self.finish(LlmCallOutcome::from_error(e)),
+            None => self.finish(LlmCallOutcome::Failed {
+                status_code: None,
+                error: "stream ended without a final response".to_string(),
+            }),
+        }
+        Poll::Ready(item)
+    }
+}
+
+impl Drop for AuditedStream {
+    fn drop(&mut self) {
+        self.finish(LlmCallOutcome::Failed { status_code: None, error: "stream was cancelled before it finished".to_string() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LlmProviderKind;
+    use crate::db_manage;
+    use crate::llm_provider::Usage;
+    use crate::operation_log::{read_operations, LogFilter};
+    use futures_util::StreamExt;
+    use std::sync::{Arc, Mutex};
+
+    fn provider() -> Provider {
+        Provider::test(LlmProviderKind::OpenAiCompatible, "http://localhost:1")
+    }
+
+    fn response() -> ChatResponse {
+        ChatResponse {
+            provider: "local".to_string(),
+            model: "m1-2024".to_string(),
+            content: "hello".to_string(),
+            finish_reason: Some("stop".to_string()),
+            usage: Some(Usage { input_tokens: 3, output_tokens: 1 }),
+        }
+    }
+
+    #[test]
+    fn test_canonical_request_ignores_streaming_and_defaults() {
+        let provider = provider();
+        let streamed_request = ChatRequest { stream: true, ..ChatRequest::user("hi") };
+        let streamed = LlmCallAudit::start(&provider, &streamed_request).unwrap();
+        let explicit = ChatRequest {
+            provider: Some(provider.name.clone()),
+            model: provider.config.default_model.clone(),
+            ..ChatRequest::user("hi")
+        };
+        assert_eq!(streamed.request_sha256(), LlmCallAudit::start(&provider, &explicit).unwrap().request_sha256());
+        assert_eq!(
+            canonical_request("local", "m1", &streamed_request),
+            r#"{"messages":[{"content":"hi","role":"user"}],"model":"m1","provider":"local"}"#
+        );
+    }
+
+    #[test]
+    fn test_record_writes_llm_call_and_optional_bodies() {
+        let conn = Connection::open_in_memory().unwrap();
+        db_manage::initialize_database(&conn).unwrap();
+        let audit = LlmCallAudit::start(&provider(), &ChatRequest { stream: true, ..ChatRequest::user("hi") }).unwrap();
+        let request_sha256 = audit.request_sha256();
+        audit.finish(LlmCallOutcome::Completed(response())).write(&conn, true).unwrap();
+        let failed = LlmCallAudit::start(&provider(), &ChatRequest::user("hi")).unwrap();
+        failed
+            .finish(LlmCallOutcome::from_error(&LlmError::Upstream { status: 429, body: "slow down".to_string(), retry_after: None }))
+            .write(&conn, false)
+            .unwrap();
+
+        let logged = read_operations(&conn, &LogFilter::default(), 0, 10).unwrap();
+        match logged[0].event.typed().unwrap() {
+            OperationEvent::LlmCall { model, request_sha256: hash, response_sha256, status_code, input_tokens, streamed, bodies_stored, .. } => {
+                assert_eq!(model, "m1-2024");
+                assert_eq!(hash, &request_sha256);
+                assert_eq!((*status_code, *input_tokens, *streamed, *bodies_stored), (Some(200), Some(3), true, true));
+                let stored = blob_store::get_blob(&conn, response_sha256.as_ref().unwrap()).unwrap().unwrap();
+                assert_eq!(serde_json::from_slice::<serde_json::Value>(&stored).unwrap()["content"], "hello");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(blob_store::has_blob(&conn, &request_sha256).unwrap());
+        match logged[1].event.typed().unwrap() {
+            OperationEvent::LlmCall { model, response_sha256, status_code, error, bodies_stored, .. } => {
+                assert_eq!(Some(model), provider().config.default_model.as_ref());
+                assert_eq!((response_sha256.is_none(), *status_code, *bodies_stored), (true, Some(429), false));
+                assert!(error.as_deref().unwrap().contains("slow down"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_stream_reports_completion_once_and_cancellation() {
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        let collect = |outcomes: &Arc<Mutex<Vec<LlmCallOutcome>>>| {
+            let outcomes = Arc::clone(outcomes);
+            move |outcome| outcomes.lock().unwrap().push(outcome)
+        };
+        let events = || {
+            futures_util::stream::iter(vec![
+                Ok(ChatStreamEvent::Delta { content: "hello".to_string() }),
+                Ok(ChatStreamEvent::Done { response: response() }),
+            ])
+        };
+        let stream = audit_stream(Box::pin(events()), collect(&outcomes));
+        assert_eq!(stream.collect::<Vec<_>>().await.len(), 2);
+        assert_eq!(outcomes.lock().unwrap().as_slice(), [LlmCallOutcome::Completed(response())]);
+
+        let mut stream = audit_stream(Box::pin(events()), collect(&outcomes));
+        stream.next().await.unwrap().unwrap();
+        drop(stream);
+        assert!(matches!(&outcomes.lock().unwrap()[1], LlmCallOutcome::Failed { error, .. } if error.contains("cancelled")));
+        assert_eq!(outcomes.lock().unwrap().len(), 2);
+    }
+}
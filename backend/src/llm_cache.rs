@@ -0,0 +1,199 @@
+// diranalyze/backend/src/llm_cache.rs
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::config::LlmConfig;
+use crate::llm_audit;
+use crate::llm_provider::ChatResponse;
+
+/// Looks up an unexpired response for `request_sha256` and counts the hit.
+pub fn take_hit(conn: &Connection, request_sha256: &str, now: DateTime<Utc>) -> Result<Option<ChatResponse>> {
+    let now = now.to_rfc3339();
+    let cached: Option<String> = conn
+        .query_row(
+            "SELECT response_json FROM LlmCache WHERE request_sha256 = ?1 AND expires_at > ?2",
+            params![request_sha256, now],
+            |row| row.get(0),
+        )
+        .optional()?;
+    // A row that no longer parses (an older response shape) is treated as a miss and refetched.
+    let Some(response) = cached.and_then(|json| serde_json::from_str::<ChatResponse>(&json).ok()) else {
+        return Ok(None);
+    };
+    conn.execute(
+        "UPDATE LlmCache SET hit_count = hit_count + 1, last_hit_at = ?2 WHERE request_sha256 = ?1",
+        params![request_sha256, now],
+    )?;
+    Ok(Some(response))
+}
+
+/// Stores `response` for `request_sha256`, replacing any earlier entry. `log_id` is the
+/// `LLM_CALL` entry that produced it, so a cached answer can be traced back to the real call.
+pub fn store(
+    conn: &Connection,
+    request_sha256: &str,
+    response: &ChatResponse,
+    ttl: Duration,
+    log_id: Option<i64>,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO LlmCache
+            (request_sha256, provider, response_json, created_at, expires_at, hit_count, last_hit_at, log_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, 0, NULL, ?6)",
+        params![
+            request_sha256,
+            response.provider,
+            llm_audit::canonical_response(response),
+            now.to_rfc3339(),
+            (now + ttl).to_rfc3339(),
+            log_id,
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn purge_expired(conn: &Connection, now: DateTime<Utc>) -> Result<usize> {
+    conn.execute("DELETE FROM LlmCache WHERE expires_at <= ?1", params![now.to_rfc3339()])
+}
+
+pub fn clear(conn: &Connection) -> Result<usize> {
+    conn.execute("DELETE FROM LlmCache", [])
+}
+
+/// What is in the cache table right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct CacheTableStats {
+    pub entries: u64,
+    pub expired_entries: u64,
+    pub total_hits: u64,
+}
+
+pub fn table_stats(conn: &Connection, now: DateTime<Utc>) -> Result<CacheTableStats> {
+    conn.query_row(
+        "SELECT COUNT(*), COUNT(CASE WHEN expires_at <= ?1 THEN 1 END), COALESCE(SUM(hit_count), 0) FROM LlmCache",
+        params![now.to_rfc3339()],
+        |row| {
+            Ok(CacheTableStats {
+                entries: row.get::<_, i64>(0)? as u64,
+                expired_entries: row.get::<_, i64>(1)? as u64,
+                total_hits: row.get::<_, i64>(2)? as u64,
+            })
+        },
+    )
+}
+
+/// How lookups went since the backend started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+pub struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+    pub bypassed: u64,
+    pub stored: u64,
+}
+
+/// The cache settings plus in-memory hit/miss counters.
+#[derive(Debug, Default)]
+pub struct LlmCache {
+    enabled: bool,
+    ttl_secs: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bypassed: AtomicU64,
+    stored: AtomicU64,
+}
+
+impl LlmCache {
+    pub fn new(enabled: bool, ttl_secs: u64) -> Self {
+        LlmCache { enabled, ttl_secs, ..Default::default() }
+    }
+
+    pub fn from_config(config: &LlmConfig) -> Self {
+        Self::new(config.cache_enabled, config.cache_ttl_secs)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn ttl_secs(&self) -> u64 {
+        self.ttl_secs
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::seconds(self.ttl_secs.min(i64::MAX as u64) as i64)
+    }
+
+    pub fn count_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_bypass(&self) {
+        self.bypassed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_store(&self) {
+        self.stored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counters(&self) -> CacheCounters {
+        CacheCounters {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bypassed: self.bypassed.load(Ordering::Relaxed),
+            stored: self.stored.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_manage;
+
+    fn response(content: &str) -> ChatResponse {
+        ChatResponse {
+            provider: "local".to_string(),
+            model: "m1".to_string(),
+            content: content.to_string(),
+            finish_reason: Some("stop".to_string()),
+            usage: None,
+        }
+    }
+
+    #[test]
+    fn test_cache_hits_until_expiry_and_counts_them() {
+        let conn = Connection::open_in_memory().unwrap();
+        db_manage::initialize_database(&conn).unwrap();
+        let now = Utc::now();
+        assert_eq!(take_hit(&conn, "abc", now).unwrap(), None);
+
+        store(&conn, "abc", &response("first"), Duration::seconds(60), None, now).unwrap();
+        store(&conn, "abc", &response("second"), Duration::seconds(60), None, now).unwrap();
+        assert_eq!(take_hit(&conn, "abc", now).unwrap(), Some(response("second")));
+        assert_eq!(take_hit(&conn, "abc", now + Duration::seconds(30)).unwrap(), Some(response("second")));
+        assert_eq!(take_hit(&conn, "abc", now + Duration::seconds(60)).unwrap(), None);
+
+        let later = now + Duration::seconds(61);
+        assert_eq!(table_stats(&conn, later).unwrap(), CacheTableStats { entries: 1, expired_entries: 1, total_hits: 2 });
+        assert_eq!(purge_expired(&conn, later).unwrap(), 1);
+        store(&conn, "def", &response("third"), Duration::seconds(60), None, now).unwrap();
+        assert_eq!(clear(&conn).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_counters() {
+        let cache = LlmCache::new(true, 30);
+        cache.count_hit();
+        cache.count_miss();
+        cache.count_miss();
+        cache.count_store();
+        assert_eq!(cache.counters(), CacheCounters { hits: 1, misses: 2, bypassed: 0, stored: 1 });
+        assert_eq!((cache.enabled(), cache.ttl().num_seconds()), (true, 30));
+    }
+}
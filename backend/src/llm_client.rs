@@ -0,0 +1,392 @@
+// diranalyze/backend/src/llm_client.rs
+
+use reqwest::header::HeaderMap;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::config::LlmRetryConfig;
+use crate::llm_provider::{ChatRequest, ChatResponse, ChatStream, LlmError, Provider};
+
+/// How long a provider asked us to wait: `retry-after-ms` (sent by OpenAI), else `Retry-After`
+/// as seconds or an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(str::trim);
+    if let Some(ms) = header("retry-after-ms").and_then(|value| value.parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+    let value = header("retry-after")?;
+    if let Ok(secs) = value.parse::<f64>() {
+        return Some(Duration::from_secs_f64(secs.max(0.0)));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some((at.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().unwrap_or(Duration::ZERO))
+}
+
+/// Whether a failed attempt is worth repeating: the provider could not be reached, timed out,
+/// is overloaded or rate limiting us. Anything else would fail the same way again.
+pub fn is_retryable(error: &LlmError) -> bool {
+    match error {
+        LlmError::Transport(_) => true,
+        // 529 is Anthropic's "overloaded".
+        LlmError::Upstream { status, .. } => matches!(status, 408 | 429 | 500 | 502 | 503 | 504 | 529),
+        _ => false,
+    }
+}
+
+/// Whether a failed request says something about the provider's health. Rate limiting does not:
+/// the provider is up, and `Retry-After` already paces us.
+fn is_provider_failure(error: &LlmError) -> bool {
+    match error {
+        LlmError::Transport(_) => true,
+        LlmError::Upstream { status, .. } => *status >= 500 || *status == 408,
+        _ => false,
+    }
+}
+
+/// How a request that got past `CircuitBreaker::check` ended, as far as the circuit cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallOutcome {
+    Succeeded,
+    /// See `is_provider_failure`.
+    ProviderFailed,
+    /// A client-side error or `429`: the provider answered, but that does not show it is healthy.
+    Inconclusive,
+}
+
+/// Exponential backoff between attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        RetryPolicy { max_attempts: max_attempts.max(1), initial_backoff, max_backoff }
+    }
+
+    pub fn from_config(config: &LlmRetryConfig) -> Self {
+        Self::new(
+            config.max_attempts,
+            Duration::from_millis(config.initial_backoff_ms),
+            Duration::from_millis(config.max_backoff_ms),
+        )
+    }
+
+    /// The wait before retrying after attempt number `attempt` (counting from 1) failed with
+    /// `error`, or `None` to give up. A `Retry-After` longer than the backoff cap gives up too,
+    /// rather than holding the request open for that long.
+    pub fn next_delay(&self, attempt: u32, error: &LlmError) -> Option<Duration> {
+        if attempt >= self.max_attempts || !is_retryable(error) {
+            return None;
+        }
+        match error.retry_after() {
+            Some(wait) => Some(wait).filter(|wait| *wait <= self.max_backoff),
+            None => {
+                let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+                Some(self.initial_backoff.saturating_mul(factor).min(self.max_backoff))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    /// The open period is over; the next call is let through as a trial, and others wait for it.
+    HalfOpen,
+}
+
+/// A provider's circuit as listed by `GET /api/llm/providers`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct CircuitStatus {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    /// Seconds until an open circuit lets a trial call through.
+    pub retry_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    /// Set while the half-open trial call is in flight. Other calls are refused until it is
+    /// recorded, or until this passes in case the trial was dropped without an outcome.
+    trial_until: Option<Instant>,
+}
+
+/// Stops calling a provider for a while after `failure_threshold` requests in a row failed on
+/// its side. Once the pause is over a single trial call is let through: its failure reopens the
+/// circuit, its success closes it.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    /// A `failure_threshold` of 0 never opens.
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        CircuitBreaker { failure_threshold, open_for, circuits: Mutex::new(HashMap::new()) }
+    }
+
+    /// Refuses the call while the provider's circuit is open, or while it is half-open and
+    /// another call is already the trial. Otherwise a half-open circuit makes this call the trial.
+    pub fn check(&self, provider: &str, now: Instant) -> Result<(), LlmError> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(provider) else {
+            return Ok(());
+        };
+        let refused_until = match (circuit.open_until, circuit.trial_until) {
+            (Some(until), _) if until > now => until,
+            (Some(_), Some(until)) if until > now => until,
+            (Some(_), _) => {
+                circuit.trial_until = Some(now + self.open_for);
+                return Ok(());
+            }
+            (None, _) => return Ok(()),
+        };
+        Err(LlmError::CircuitOpen { provider: provider.to_string(), retry_after: refused_until - now })
+    }
+
+    /// Counts how a request that got past `check` ended. Only a success resets the circuit; an
+    /// inconclusive outcome leaves it as it was, apart from ending a half-open trial so the next
+    /// call can try again.
+    pub fn record(&self, provider: &str, outcome: CallOutcome, now: Instant) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(provider.to_string()).or_default();
+        circuit.trial_until = None;
+        match outcome {
+            CallOutcome::Succeeded => {
+                *circuit = Circuit::default();
+                return;
+            }
+            CallOutcome::Inconclusive => return,
+            CallOutcome::ProviderFailed => {}
+        }
+        circuit.consecutive_failures += 1;
+        if self.failure_threshold > 0 && circuit.consecutive_failures >= self.failure_threshold {
+            if circuit.open_until.is_none_or(|until| until <= now) {
+                eprintln!(
+                    "--> LLM_CLIENT: Provider '{}' failed {} times in a row; pausing calls for {}s.",
+                    provider,
+                    circuit.consecutive_failures,
+                    self.open_for.as_secs()
+                );
+            }
+            circuit.open_until = Some(now + self.open_for);
+        }
+    }
+
+    pub fn status(&self, provider: &str, now: Instant) -> CircuitStatus {
+        let circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.get(provider);
+        let consecutive_failures = circuit.map_or(0, |circuit| circuit.consecutive_failures);
+        match circuit.and_then(|circuit| circuit.open_until) {
+            Some(until) if until > now => CircuitStatus {
+                state: CircuitState::Open,
+                consecutive_failures,
+                retry_after_secs: Some((until - now).as_secs_f64().ceil() as u64),
+            },
+            Some(_) => CircuitStatus { state: CircuitState::HalfOpen, consecutive_failures, retry_after_secs: None },
+            None => CircuitStatus { state: CircuitState::Closed, consecutive_failures, retry_after_secs: None },
+        }
+    }
+}
+
+/// The HTTP client for LLM providers, with timeouts, retries and a circuit breaker per provider.
+/// Only the opening of a request is retried; a stream that fails after it started is not.
+#[derive(Debug)]
+pub struct LlmClient {
+    http: Client,
+    retry: RetryPolicy,
+    breaker: CircuitBreaker,
+}
+
+impl LlmClient {
+    pub fn new(config: &LlmRetryConfig) -> reqwest::Result<Self> {
+        let http = Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+            .read_timeout(Duration::from_secs(config.read_timeout_secs))
+            .build()?;
+        Ok(LlmClient {
+            http,
+            retry: RetryPolicy::from_config(config),
+            breaker: CircuitBreaker::new(config.circuit_failure_threshold, Duration::from_secs(config.circuit_open_secs)),
+        })
+    }
+
+    pub fn circuit_status(&self, provider: &str) -> CircuitStatus {
+        self.breaker.status(provider, Instant::now())
+    }
+
+    pub async fn chat(&self, provider: &Provider, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        self.call(provider, |http| provider.chat(http, request)).await
+    }
+
+    pub async fn chat_stream(&self, provider: &Provider, request: &ChatRequest) -> Result<ChatStream, LlmError> {
+        self.call(provider, |http| provider.chat_stream(http, request)).await
+    }
+
+    async fn call<'a, T, F, Fut>(&'a self, provider: &Provider, mut attempt: F) -> Result<T, LlmError>
+    where
+        F: FnMut(&'a Client) -> Fut,
+        Fut: Future<Output = Result<T, LlmError>>,
+    {
+        self.breaker.check(&provider.name, Instant::now())?;
+        let mut attempts = 1;
+        loop {
+            let error = match attempt(&self.http).await {
+                Ok(value) => {
+                    self.breaker.record(&provider.name, CallOutcome::Succeeded, Instant::now());
+                    return Ok(value);
+                }
+                Err(e) => e,
+            };
+            let Some(delay) = self.retry.next_delay(attempts, &error) else {
+                let outcome =
+                    if is_provider_failure(&error) { CallOutcome::ProviderFailed } else { CallOutcome::Inconclusive };
+                self.breaker.record(&provider.name, outcome, Instant::now());
+                return Err(error);
+            };
+            eprintln!(
+                "--> LLM_CLIENT: Attempt {} to provider '{}' failed ({}); retrying in {} ms.",
+                attempts,
+                provider.name,
+                error,
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+            attempts += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LlmProviderKind;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    fn upstream(status: u16, retry_after: Option<Duration>) -> LlmError {
+        LlmError::Upstream { status, body: String::new(), retry_after }
+    }
+
+    #[test]
+    fn test_backoff_doubles_honours_retry_after_and_stops() {
+        let policy = RetryPolicy::new(4, Duration::from_millis(100), Duration::from_millis(250));
+        assert_eq!(policy.next_delay(1, &upstream(503, None)), Some(Duration::from_millis(100)));
+        assert_eq!(policy.next_delay(2, &upstream(503, None)), Some(Duration::from_millis(200)));
+        assert_eq!(policy.next_delay(3, &upstream(503, None)), Some(Duration::from_millis(250)));
+        assert_eq!(policy.next_delay(4, &upstream(503, None)), None);
+        assert_eq!(policy.next_delay(1, &upstream(429, Some(Duration::from_millis(50)))), Some(Duration::from_millis(50)));
+        assert_eq!(policy.next_delay(1, &upstream(429, Some(Duration::from_secs(60)))), None);
+        assert_eq!(policy.next_delay(1, &upstream(400, None)), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", "3".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+        headers.insert("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+        headers.insert("retry-after-ms", "1500".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_circuit_opens_after_threshold_and_recovers() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+        breaker.record("p", CallOutcome::ProviderFailed, now);
+        breaker.check("p", now).unwrap();
+        breaker.record("p", CallOutcome::ProviderFailed, now);
+        match breaker.check("p", now + Duration::from_secs(10)) {
+            Err(LlmError::CircuitOpen { retry_after, .. }) => assert_eq!(retry_after, Duration::from_secs(20)),
+            other => panic!("unexpected {:?}", other),
+        }
+        breaker.check("other", now).unwrap();
+
+        // After the pause one trial goes through and the rest wait for it; failing it reopens
+        // straight away.
+        let later = now + Duration::from_secs(31);
+        assert_eq!(breaker.status("p", later).state, CircuitState::HalfOpen);
+        breaker.check("p", later).unwrap();
+        assert!(matches!(breaker.check("p", later), Err(LlmError::CircuitOpen { .. })));
+        breaker.record("p", CallOutcome::ProviderFailed, later);
+        assert_eq!(breaker.status("p", later).state, CircuitState::Open);
+        breaker.record("p", CallOutcome::Succeeded, later + Duration::from_secs(31));
+        assert_eq!(breaker.status("p", later).state, CircuitState::Closed);
+
+        // A trial that never reports back stops blocking calls after another pause.
+        breaker.record("p", CallOutcome::ProviderFailed, now);
+        breaker.record("p", CallOutcome::ProviderFailed, now);
+        breaker.check("p", later).unwrap();
+        assert!(breaker.check("p", later + Duration::from_secs(29)).is_err());
+        breaker.check("p", later + Duration::from_secs(30)).unwrap();
+    }
+
+    #[test]
+    fn test_client_errors_and_rate_limits_leave_the_circuit_alone() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+        breaker.record("p", CallOutcome::ProviderFailed, now);
+        breaker.record("p", CallOutcome::Inconclusive, now);
+        assert_eq!(breaker.status("p", now).consecutive_failures, 1);
+        breaker.record("p", CallOutcome::ProviderFailed, now);
+        assert_eq!(breaker.status("p", now).state, CircuitState::Open);
+
+        // A 429 on the half-open trial neither closes the circuit nor blocks the next trial.
+        let later = now + Duration::from_secs(31);
+        breaker.check("p", later).unwrap();
+        breaker.record("p", CallOutcome::Inconclusive, later);
+        assert_eq!(breaker.status("p", later).state, CircuitState::HalfOpen);
+        breaker.check("p", later).unwrap();
+        breaker.record("p", CallOutcome::Succeeded, later);
+        assert_eq!(
+            breaker.status("p", later),
+            CircuitStatus { state: CircuitState::Closed, consecutive_failures: 0, retry_after_secs: None }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_retries_overloaded_provider() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&calls);
+        let app = axum::Router::new().route(
+            "/v1/chat/completions",
+            axum::routing::post(move || async move {
+                match counter.fetch_add(1, Ordering::SeqCst) {
+                    0 => (axum::http::StatusCode::SERVICE_UNAVAILABLE, "busy".to_string()),
+                    _ => (
+                        axum::http::StatusCode::OK,
+                        r#"{"choices":[{"message":{"content":"hi"},"finish_reason":"stop"}]}"#.to_string(),
+                    ),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = LlmRetryConfig { initial_backoff_ms: 1, circuit_failure_threshold: 1, ..LlmRetryConfig::default() };
+        let client = LlmClient::new(&config).unwrap();
+        let provider = Provider::test(LlmProviderKind::OpenAiCompatible, base_url);
+        let request = ChatRequest::user("hello");
+        assert_eq!(client.chat(&provider, &request).await.unwrap().content, "hi");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(client.circuit_status(&provider.name).state, CircuitState::Closed);
+
+        // Nothing listens here, so every attempt fails and the circuit opens.
+        let mut down = provider.clone();
+        down.config.base_url = "http://127.0.0.1:1/v1".to_string();
+        assert_eq!(client.chat(&down, &request).await.unwrap_err().code(), "upstream_unreachable");
+        let refused = client.chat(&down, &request).await.unwrap_err();
+        assert_eq!((refused.code(), refused.status_code()), ("circuit_open", axum::http::StatusCode::SERVICE_UNAVAILABLE));
+    }
+}
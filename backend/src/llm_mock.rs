@@ -0,0 +1,333 @@
+// diranalyze/backend/src/llm_mock.rs
+
+use regex::Regex;
+use rusqlite::Connection;
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+use crate::blob_store;
+use crate::config::LlmProviderKind;
+use crate::llm_provider::{ChatRequest, ChatResponse, ChatStream, LlmError, Provider, Usage};
+use crate::llm_stream::ChatStreamEvent;
+use crate::operation_log::{self, LogFilter, OperationEvent};
+use crate::token_budget;
+
+/// Served by a `mock` provider that names no fixtures of its own: a CAPCA patch for the AI
+/// Patcher demo, a rate-limited reply, and a catch-all greeting.
+const DEMO_FIXTURES: &str = include_str!("../fixtures/llm_mock/demo.json");
+
+/// A fixture file: `{ "fixtures": [...] }`. `GET /api/llm/recordings` answers in this shape too.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureFile {
+    pub fixtures: Vec<Fixture>,
+}
+
+/// One scripted or recorded reply and the requests it answers.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fixture {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Answers the request with this `request_hash`. It is not the `request_sha256` of an
+    /// `LLM_CALL` entry, which also covers provider and model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_hash: Option<String>,
+    /// Answers requests whose last message contains a match for this regex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_message: Option<String>,
+    /// Exactly one of `response` and `error` is given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<MockResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<MockError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockResponse {
+    pub content: String,
+    /// The provider's `default_model` or the requested model when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// `stop` when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    /// Estimated from the prompt and `content` when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+/// An upstream failure to simulate, returned as if the provider had answered with `status`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockError {
+    pub status: u16,
+    #[serde(default)]
+    pub body: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+}
+
+impl Fixture {
+    /// A fixture that replays `response` for `request`, as recorded in the log.
+    pub fn recorded(name: String, request: &ChatRequest, response: &ChatResponse) -> Self {
+        Fixture {
+            name: Some(name),
+            request_hash: Some(request_hash(request)),
+            last_message: None,
+            response: Some(MockResponse {
+                content: response.content.clone(),
+                model: Some(response.model.clone()),
+                finish_reason: response.finish_reason.clone(),
+                usage: response.usage,
+            }),
+            error: None,
+        }
+    }
+}
+
+/// The key recorded fixtures are matched by: the SHA-256 of the canonical request with provider
+/// and model left out as well as `stream`, so a call recorded against any provider replays on
+/// the mock.
+pub fn request_hash(request: &ChatRequest) -> String {
+    let neutral = ChatRequest { provider: None, model: None, stream: false, ..request.clone() };
+    let canonical = serde_json::to_value(&neutral).expect("chat requests always serialize").to_string();
+    blob_store::sha256_hex(canonical.as_bytes())
+}
+
+/// Fixtures in the order they were read, with their regexes compiled.
+#[derive(Debug)]
+pub struct MockFixtures {
+    fixtures: Vec<(Fixture, Option<Regex>)>,
+}
+
+/// Equal when the fixtures are; the regexes are compiled from them.
+impl PartialEq for MockFixtures {
+    fn eq(&self, other: &Self) -> bool {
+        self.fixtures.iter().map(|(fixture, _)| fixture).eq(other.fixtures.iter().map(|(fixture, _)| fixture))
+    }
+}
+
+impl Eq for MockFixtures {}
+
+impl MockFixtures {
+    /// Reads a fixture file, every `*.json` file of a directory in name order, or the built-in
+    /// demo fixtures when `path` is `None`.
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let sources = match path {
+            None => vec![("built-in demo fixtures".to_string(), DEMO_FIXTURES.to_string())],
+            Some(dir) if dir.is_dir() => {
+                let mut files = std::fs::read_dir(dir)
+                    .map_err(|e| format!("could not list '{}': {}", dir.display(), e))?
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|file| file.extension().is_some_and(|ext| ext == "json"))
+                    .collect::<Vec<_>>();
+                files.sort();
+                files.iter().map(|file| read_source(file)).collect::<Result<Vec<_>, _>>()?
+            }
+            Some(file) => vec![read_source(file)?],
+        };
+        let mut fixtures = Vec::new();
+        for (source, text) in sources {
+            let file: FixtureFile = serde_json::from_str(&text).map_err(|e| format!("{}: {}", source, e))?;
+            for (index, fixture) in file.fixtures.into_iter().enumerate() {
+                let label = || format!("{}: fixture {} ({})", source, index, fixture.name.as_deref().unwrap_or("unnamed"));
+                if fixture.response.is_some() == fixture.error.is_some() {
+                    return Err(format!("{} needs exactly one of 'response' and 'error'", label()));
+                }
+                let regex = match &fixture.last_message {
+                    Some(pattern) => Some(Regex::new(pattern).map_err(|e| format!("{}: {}", label(), e))?),
+                    None => None,
+                };
+                fixtures.push((fixture, regex));
+            }
+        }
+        Ok(MockFixtures { fixtures })
+    }
+
+    pub fn len(&self) -> usize {
+        self.fixtures.len()
+    }
+
+    /// The fixture recorded for this exact request if there is one, else the first scripted
+    /// fixture whose `last_message` matches. A fixture with neither matcher answers anything.
+    pub fn find(&self, request: &ChatRequest) -> Option<&Fixture> {
+        let hash = request_hash(request);
+        let last_message = request.messages.last().map_or("", |message| message.content.as_str());
+        let recorded = self.fixtures.iter().find(|(fixture, _)| fixture.request_hash.as_deref() == Some(hash.as_str()));
+        recorded
+            .or_else(|| {
+                self.fixtures.iter().find(|(fixture, regex)| {
+                    fixture.request_hash.is_none() && regex.as_ref().is_none_or(|regex| regex.is_match(last_message))
+                })
+            })
+            .map(|(fixture, _)| fixture)
+    }
+}
+
+fn read_source(file: &Path) -> Result<(String, String), String> {
+    let text = std::fs::read_to_string(file).map_err(|e| format!("could not read '{}': {}", file.display(), e))?;
+    Ok((file.display().to_string(), text))
+}
+
+/// Answers `request` from the fixtures the mock `provider` loaded at startup.
+pub fn reply(provider: &Provider, model: &str, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+    let fixtures = provider
+        .mock_fixtures()
+        .ok_or_else(|| LlmError::MockFixtures(format!("provider '{}' is not a mock", provider.name)))?;
+    let fixture = fixtures
+        .find(request)
+        .ok_or_else(|| LlmError::NoMockFixture { request_hash: request_hash(request) })?;
+    let response = match (&fixture.response, &fixture.error) {
+        (Some(response), _) => response,
+        (None, Some(error)) => {
+            return Err(LlmError::Upstream {
+                status: error.status,
+                body: error.body.clone(),
+                retry_after: error.retry_after_secs.map(Duration::from_secs),
+            })
+        }
+        (None, None) => unreachable!("fixtures are validated when loaded"),
+    };
+    let model = response.model.clone().unwrap_or_else(|| model.to_string());
+    let usage = response.usage.unwrap_or_else(|| Usage {
+        input_tokens: token_budget::estimate_prompt(LlmProviderKind::Mock, &model, &request.messages).prompt_tokens,
+        output_tokens: token_budget::estimate_text(LlmProviderKind::Mock, &model, &response.content),
+    });
+    Ok(ChatResponse {
+        provider: provider.name.clone(),
+        model,
+        content: response.content.clone(),
+        finish_reason: Some(response.finish_reason.clone().unwrap_or_else(|| "stop".to_string())),
+        usage: Some(usage),
+    })
+}
+
+/// `LLM_CALL` rows read per query while collecting recordings.
+const RECORDING_BATCH: i64 = 500;
+
+/// Turns the `LLM_CALL` entries matching `filter` whose bodies were stored into fixtures that
+/// replay them, oldest first. Failed calls, cached replies and repeats of an already recorded
+/// request are left out.
+pub fn recorded_fixtures(conn: &Connection, filter: &LogFilter) -> rusqlite::Result<FixtureFile> {
+    let filter = LogFilter { operation_type: Some("LLM_CALL".to_string()), ..filter.clone() };
+    let mut fixtures = Vec::new();
+    let mut seen = HashSet::new();
+    let mut cursor = 0;
+    loop {
+        let batch = operation_log::read_operations(conn, &filter, cursor, RECORDING_BATCH)?;
+        let Some(last) = batch.last() else { break };
+        cursor = last.log_id;
+        for operation in &batch {
+            let Some(OperationEvent::LlmCall {
+                provider,
+                model,
+                request_sha256,
+                response_sha256: Some(response_sha256),
+                bodies_stored: true,
+                cached: false,
+                ..
+            }) = operation.event.typed()
+            else {
+                continue;
+            };
+            let (Some(request), Some(response)) =
+                (blob_store::get_blob(conn, request_sha256)?, blob_store::get_blob(conn, response_sha256)?)
+            else {
+                continue;
+            };
+            let (Ok(request), Ok(response)) =
+                (serde_json::from_slice::<ChatRequest>(&request), serde_json::from_slice::<ChatResponse>(&response))
+            else {
+                continue;
+            };
+            let name = format!("LLM_CALL {} ({}/{})", operation.log_id, provider, model);
+            let fixture = Fixture::recorded(name, &request, &response);
+            if seen.insert(fixture.request_hash.clone()) {
+                fixtures.push(fixture);
+            }
+        }
+    }
+    Ok(FixtureFile { fixtures })
+}
+
+/// Streams a mock reply one word at a time, then `done`.
+pub fn stream(response: ChatResponse) -> ChatStream {
+    let mut events: Vec<Result<ChatStreamEvent, LlmError>> = response
+        .content
+        .split_inclusive(char::is_whitespace)
+        .map(|word| Ok(ChatStreamEvent::Delta { content: word.to_string() }))
+        .collect();
+    events.push(Ok(ChatStreamEvent::Done { response }));
+    Box::pin(futures_util::stream::iter(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LlmProviderConfig;
+    use futures_util::StreamExt;
+
+    fn mock(fixtures: Option<&Path>) -> Result<Provider, String> {
+        let config = Provider::test(LlmProviderKind::Mock, "").config;
+        Provider::new("mock".to_string(), LlmProviderConfig { fixtures: fixtures.map(Path::to_path_buf), ..config })
+    }
+
+    #[test]
+    fn test_demo_fixtures_script_capca_and_errors() {
+        let fixtures = MockFixtures::load(None).unwrap();
+        assert_eq!(fixtures.len(), 3);
+
+        let reply = reply(&mock(None).unwrap(), "mock-1", &ChatRequest::user("Give me a CAPCA patch")).unwrap();
+        let patch: serde_json::Value = serde_json::from_str(&reply.content).unwrap();
+        assert_eq!(patch[0]["operation"], "create_file_with_content");
+        assert_eq!((reply.model.as_str(), reply.finish_reason.as_deref()), ("mock-1", Some("stop")));
+        assert!(reply.usage.unwrap().input_tokens > 0);
+
+        match super::reply(&mock(None).unwrap(), "mock-1", &ChatRequest::user("mock: rate limit me")) {
+            Err(LlmError::Upstream { status: 429, retry_after, .. }) => assert_eq!(retry_after, Some(Duration::from_secs(1))),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(super::reply(&mock(None).unwrap(), "mock-1", &ChatRequest::user("anything else")).unwrap().content.starts_with("Hello"));
+    }
+
+    #[tokio::test]
+    async fn test_recorded_fixtures_win_over_scripted_ones() {
+        let dir = std::env::temp_dir().join(format!("diranalyze_mock_fixtures_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let recorded_for =
+            ChatRequest { provider: Some("openai".to_string()), model: Some("gpt-4o".to_string()), ..ChatRequest::user("hi") };
+        let recorded_response = ChatResponse {
+            provider: "openai".to_string(),
+            model: "gpt-4o-2024".to_string(),
+            content: "recorded answer".to_string(),
+            finish_reason: Some("stop".to_string()),
+            usage: Some(Usage { input_tokens: 9, output_tokens: 2 }),
+        };
+        let recorded = FixtureFile { fixtures: vec![Fixture::recorded("log 1".to_string(), &recorded_for, &recorded_response)] };
+        std::fs::write(dir.join("a_recorded.json"), serde_json::to_string(&recorded).unwrap()).unwrap();
+        std::fs::write(dir.join("b_scripted.json"), r#"{"fixtures":[{"last_message":"^h","response":{"content":"scripted"}}]}"#).unwrap();
+        std::fs::write(dir.join("notes.txt"), "ignored").unwrap();
+        let provider = mock(Some(&dir)).unwrap();
+
+        // Recorded against another provider and model, still replayed.
+        let reply = super::reply(&provider, "mock-1", &ChatRequest::user("hi")).unwrap();
+        assert_eq!((reply.content.as_str(), reply.provider.as_str(), reply.usage), ("recorded answer", "mock", recorded_response.usage));
+        assert_eq!(super::reply(&provider, "mock-1", &ChatRequest::user("hello")).unwrap().content, "scripted");
+        match super::reply(&provider, "mock-1", &ChatRequest::user("bye")) {
+            Err(LlmError::NoMockFixture { request_hash: hash }) => assert_eq!(hash, request_hash(&ChatRequest::user("bye"))),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let events: Vec<_> = stream(super::reply(&provider, "mock-1", &ChatRequest::user("hello there")).unwrap()).collect().await;
+        assert_eq!(events.len(), 2);
+
+        // Fixtures are read once: a later edit does not reach the loaded provider, and a broken
+        // file is refused when the provider is created.
+        std::fs::write(dir.join("c_broken.json"), r#"{"fixtures":[{"last_message":"(","response":{"content":"x"}}]}"#).unwrap();
+        assert_eq!(super::reply(&provider, "mock-1", &ChatRequest::user("hi")).unwrap().content, "recorded answer");
+        assert!(mock(Some(&dir)).unwrap_err().contains("c_broken.json"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,748 @@
+// diranalyze/backend/src/llm_provider.rs
+
+use futures_util::StreamExt;
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::config::{LlmConfig, LlmProviderConfig, LlmProviderKind};
+use crate::llm_client;
+use crate::llm_mock::{self, MockFixtures};
+use crate::llm_stream::{self, ChatStreamEvent, StreamAssembler, StreamDecoder};
+
+/// Sent as `anthropic-version` on every Anthropic request.
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Anthropic requires `max_tokens`; this is used when the request leaves it out.
+pub const DEFAULT_ANTHROPIC_MAX_TOKENS: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+/// The provider-neutral request accepted by `POST /api/llm_proxy`. Its fields are a subset of
+/// OpenAI's Chat Completions request, so existing OpenAI payloads keep working.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ChatRequest {
+    /// Name of a configured provider; the configured default when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    /// The provider's `default_model` when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// Relay the reply as it is generated instead of waiting for all of it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+}
+
+#[cfg(test)]
+impl ChatRequest {
+    /// A non-streaming request with one user message, for tests.
+    pub fn user(text: &str) -> Self {
+        ChatRequest {
+            provider: None,
+            model: None,
+            messages: vec![ChatMessage { role: Role::User, content: text.to_string() }],
+            max_tokens: None,
+            temperature: None,
+            stream: false,
+        }
+    }
+}
+
+/// A streamed reply: text deltas, then one `Done` carrying the assembled response. An error
+/// ends the stream.
+pub type ChatStream = Pin<Box<dyn futures_util::Stream<Item = Result<ChatStreamEvent, LlmError>> + Send>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+/// The provider-neutral reply returned by `POST /api/llm_proxy`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ChatResponse {
+    pub provider: String,
+    pub model: String,
+    pub content: String,
+    /// The provider's own stop reason (`stop`, `end_turn`, `length`, ...).
+    pub finish_reason: Option<String>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug)]
+pub enum LlmError {
+    UnknownProvider(String),
+    /// The request named no model and the provider has no `default_model`.
+    MissingModel(String),
+    MissingApiKey { provider: String, env_var: String },
+    Transport(reqwest::Error),
+    /// The provider answered with a non-success status, possibly asking to be retried later.
+    Upstream { status: u16, body: String, retry_after: Option<Duration> },
+    InvalidResponse(String),
+    /// The provider failed too often in a row, so calls to it are paused for `retry_after`.
+    CircuitOpen { provider: String, retry_after: Duration },
+    /// The mock provider's fixture files could not be read.
+    MockFixtures(String),
+    /// No mock fixture answers the request; `request_hash` is what a recorded one would need.
+    NoMockFixture { request_hash: String },
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::UnknownProvider(name) => write!(f, "unknown LLM provider '{}'", name),
+            LlmError::MissingModel(provider) => write!(f, "no model given and provider '{}' has no default_model", provider),
+            LlmError::MissingApiKey { provider, env_var } => {
+                write!(f, "API key for provider '{}' not found in environment variable {}", provider, env_var)
+            }
+            LlmError::Transport(e) => {
+                // reqwest keeps the useful part ("connection refused", "operation timed out") in the source chain.
+                write!(f, "request to LLM provider failed: {}", e)?;
+                let mut source = std::error::Error::source(e);
+                while let Some(cause) = source {
+                    write!(f, ": {}", cause)?;
+                    source = cause.source();
+                }
+                Ok(())
+            }
+            LlmError::Upstream { status, body, .. } => write!(f, "LLM provider returned {}: {}", status, body),
+            LlmError::InvalidResponse(message) => write!(f, "unexpected response from LLM provider: {}", message),
+            LlmError::CircuitOpen { provider, retry_after } => write!(
+                f,
+                "LLM provider '{}' is failing repeatedly; calls to it are paused for {}s",
+                provider,
+                retry_after.as_secs_f64().ceil()
+            ),
+            LlmError::MockFixtures(message) => write!(f, "invalid mock fixtures: {}", message),
+            LlmError::NoMockFixture { request_hash } => {
+                write!(f, "no mock fixture matches this request (request_hash {})", request_hash)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl LlmError {
+    /// The status the proxy answers with for this error.
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+        match self {
+            LlmError::UnknownProvider(_) | LlmError::MissingModel(_) => StatusCode::BAD_REQUEST,
+            LlmError::MissingApiKey { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            LlmError::Transport(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            LlmError::Upstream { status: 408, .. } => StatusCode::GATEWAY_TIMEOUT,
+            LlmError::Upstream { status: 429, .. } => StatusCode::TOO_MANY_REQUESTS,
+            LlmError::Transport(_) | LlmError::Upstream { .. } | LlmError::InvalidResponse(_) => StatusCode::BAD_GATEWAY,
+            LlmError::CircuitOpen { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            LlmError::MockFixtures(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            LlmError::NoMockFixture { .. } => StatusCode::NOT_FOUND,
+        }
+    }
+
+    /// The `error` field of the proxy's JSON error body.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LlmError::UnknownProvider(_) => "unknown_provider",
+            LlmError::MissingModel(_) => "missing_model",
+            LlmError::MissingApiKey { .. } => "missing_api_key",
+            LlmError::Transport(e) if e.is_timeout() => "upstream_timeout",
+            LlmError::Transport(_) => "upstream_unreachable",
+            LlmError::Upstream { status: 408, .. } => "upstream_timeout",
+            LlmError::Upstream { status: 429, .. } => "upstream_rate_limited",
+            LlmError::Upstream { status, .. } if *status >= 500 => "upstream_error",
+            LlmError::Upstream { .. } => "upstream_rejected",
+            LlmError::InvalidResponse(_) => "invalid_upstream_response",
+            LlmError::CircuitOpen { .. } => "circuit_open",
+            LlmError::MockFixtures(_) => "invalid_mock_fixtures",
+            LlmError::NoMockFixture { .. } => "no_mock_fixture",
+        }
+    }
+
+    /// How long the provider asked us to wait, or how long its circuit stays open.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            LlmError::Upstream { retry_after, .. } => *retry_after,
+            LlmError::CircuitOpen { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    /// The proxy's answer: `{ "error", "message", "upstream_status"?, "retry_after_secs"? }` with
+    /// the matching status, plus a `Retry-After` header when there is something to wait for.
+    pub fn to_response(&self) -> axum::response::Response {
+        use axum::response::IntoResponse;
+        let mut body = json!({ "error": self.code(), "message": self.to_string() });
+        if let LlmError::Upstream { status, .. } = self {
+            body["upstream_status"] = json!(status);
+        }
+        let retry_after_secs = self.retry_after().map(|wait| wait.as_secs_f64().ceil() as u64);
+        if let Some(secs) = retry_after_secs {
+            body["retry_after_secs"] = json!(secs);
+        }
+        let mut response = (self.status_code(), axum::Json(body)).into_response();
+        if let Some(secs) = retry_after_secs {
+            response.headers_mut().insert(reqwest::header::RETRY_AFTER, axum::http::HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+impl axum::response::IntoResponse for LlmError {
+    fn into_response(self) -> axum::response::Response {
+        self.to_response()
+    }
+}
+
+/// A named, configured LLM endpoint that translates `ChatRequest`s into its own API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provider {
+    pub name: String,
+    pub config: LlmProviderConfig,
+    /// `mock` only: the fixtures named by `config.fixtures`, read once by `new`.
+    mock_fixtures: Option<Arc<MockFixtures>>,
+}
+
+impl Provider {
+    /// Fails if the provider is a `mock` whose fixtures cannot be read.
+    pub fn new(name: String, config: LlmProviderConfig) -> Result<Self, String> {
+        let mock_fixtures = match config.kind {
+            LlmProviderKind::Mock => Some(Arc::new(MockFixtures::load(config.fixtures.as_deref())?)),
+            _ => None,
+        };
+        Ok(Provider { name, config, mock_fixtures })
+    }
+
+    pub fn kind(&self) -> LlmProviderKind {
+        self.config.kind
+    }
+
+    /// The fixtures a `mock` provider answers from.
+    pub fn mock_fixtures(&self) -> Option<&MockFixtures> {
+        self.mock_fixtures.as_deref()
+    }
+
+    /// The URL chat requests are posted to.
+    pub fn endpoint(&self) -> String {
+        let base_url = self.config.base_url.trim_end_matches('/');
+        let path = match self.kind() {
+            // The mock answers in-process and never gets this far.
+            LlmProviderKind::OpenAi | LlmProviderKind::OpenAiCompatible | LlmProviderKind::Mock => "/chat/completions",
+            LlmProviderKind::Anthropic => "/v1/messages",
+            LlmProviderKind::Ollama => "/api/chat",
+        };
+        format!("{}{}", base_url, path)
+    }
+
+    /// The model a request runs against.
+    pub fn resolve_model(&self, request: &ChatRequest) -> Result<String, LlmError> {
+        request
+            .model
+            .clone()
+            .or_else(|| self.config.default_model.clone())
+            .ok_or_else(|| LlmError::MissingModel(self.name.clone()))
+    }
+
+    /// Reads the API key named by `api_key_env`. Providers without one send no credentials.
+    fn api_key(&self) -> Result<Option<String>, LlmError> {
+        match &self.config.api_key_env {
+            Some(env_var) => std::env::var(env_var).map(Some).map_err(|_| LlmError::MissingApiKey {
+                provider: self.name.clone(),
+                env_var: env_var.clone(),
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Translates `request` into the provider's request body.
+    pub fn request_body(&self, model: &str, request: &ChatRequest) -> Value {
+        match self.kind() {
+            LlmProviderKind::OpenAi | LlmProviderKind::OpenAiCompatible | LlmProviderKind::Mock => {
+                let mut body = json!({ "model": model, "messages": request.messages });
+                if let Some(max_tokens) = request.max_tokens {
+                    body["max_tokens"] = json!(max_tokens);
+                }
+                if let Some(temperature) = request.temperature {
+                    body["temperature"] = json!(temperature);
+                }
+                if request.stream {
+                    body["stream"] = json!(true);
+                    // Only OpenAI itself is known to accept this; it adds usage to the last chunk.
+                    if self.kind() == LlmProviderKind::OpenAi {
+                        body["stream_options"] = json!({ "include_usage": true });
+                    }
+                }
+                body
+            }
+            LlmProviderKind::Anthropic => {
+                // System prompts are a top-level field in the Messages API, not a message role.
+                let (system, messages): (Vec<&ChatMessage>, Vec<&ChatMessage>) =
+                    request.messages.iter().partition(|m| m.role == Role::System);
+                let mut body = json!({
+                    "model": model,
+                    "messages": messages,
+                    "max_tokens": request.max_tokens.unwrap_or(DEFAULT_ANTHROPIC_MAX_TOKENS),
+                });
+                if !system.is_empty() {
+                    body["system"] = json!(system.iter().map(|m| m.content.as_str()).collect::<Vec<_>>().join("\n\n"));
+                }
+                if let Some(temperature) = request.temperature {
+                    body["temperature"] = json!(temperature);
+                }
+                if request.stream {
+                    body["stream"] = json!(true);
+                }
+                body
+            }
+            LlmProviderKind::Ollama => {
+                let mut options = serde_json::Map::new();
+                if let Some(max_tokens) = request.max_tokens {
+                    options.insert("num_predict".to_string(), json!(max_tokens));
+                }
+                if let Some(temperature) = request.temperature {
+                    options.insert("temperature".to_string(), json!(temperature));
+                }
+                let mut body = json!({ "model": model, "messages": request.messages, "stream": request.stream });
+                if !options.is_empty() {
+                    body["options"] = Value::Object(options);
+                }
+                body
+            }
+        }
+    }
+
+    /// Translates the provider's response body into a `ChatResponse`.
+    pub fn parse_response(&self, model: &str, body: &Value) -> Result<ChatResponse, LlmError> {
+        let missing = |field: &str| LlmError::InvalidResponse(format!("missing '{}' in {} response", field, self.name));
+        let text = |value: &Value| value.as_str().map(str::to_string);
+        let count = |value: &Value| value.as_u64().unwrap_or(0);
+
+        let (content, finish_reason, usage) = match self.kind() {
+            LlmProviderKind::OpenAi | LlmProviderKind::OpenAiCompatible | LlmProviderKind::Mock => {
+                let choice = body["choices"].get(0).ok_or_else(|| missing("choices"))?;
+                let content = text(&choice["message"]["content"]).ok_or_else(|| missing("choices[0].message.content"))?;
+                let usage = body.get("usage").map(|u| Usage {
+                    input_tokens: count(&u["prompt_tokens"]),
+                    output_tokens: count(&u["completion_tokens"]),
+                });
+                (content, text(&choice["finish_reason"]), usage)
+            }
+            LlmProviderKind::Anthropic => {
+                let blocks = body["content"].as_array().ok_or_else(|| missing("content"))?;
+                let content = blocks
+                    .iter()
+                    .filter(|block| block["type"] == "text")
+                    .filter_map(|block| block["text"].as_str())
+                    .collect::<String>();
+                let usage = body.get("usage").map(|u| Usage {
+                    input_tokens: count(&u["input_tokens"]),
+                    output_tokens: count(&u["output_tokens"]),
+                });
+                (content, text(&body["stop_reason"]), usage)
+            }
+            LlmProviderKind::Ollama => {
+                let content = text(&body["message"]["content"]).ok_or_else(|| missing("message.content"))?;
+                let usage = body.get("eval_count").map(|_| Usage {
+                    input_tokens: count(&body["prompt_eval_count"]),
+                    output_tokens: count(&body["eval_count"]),
+                });
+                (content, text(&body["done_reason"]), usage)
+            }
+        };
+        Ok(ChatResponse {
+            provider: self.name.clone(),
+            model: text(&body["model"]).unwrap_or_else(|| model.to_string()),
+            content,
+            finish_reason,
+            usage,
+        })
+    }
+
+    /// Sends `request` to the provider and waits for the complete reply.
+    pub async fn chat(&self, client: &Client, request: &ChatRequest) -> Result<ChatResponse, LlmError> {
+        let model = self.resolve_model(request)?;
+        let request = ChatRequest { stream: false, ..request.clone() };
+        if self.kind() == LlmProviderKind::Mock {
+            return llm_mock::reply(self, &model, &request);
+        }
+        let response = self.send(client, &model, &request).await?;
+        let body: Value = response.json().await.map_err(|e| LlmError::InvalidResponse(e.to_string()))?;
+        self.parse_response(&model, &body)
+    }
+
+    /// Sends `request` with streaming enabled. Fails before yielding anything if the provider
+    /// rejects the request; errors after that end the stream.
+    pub async fn chat_stream(&self, client: &Client, request: &ChatRequest) -> Result<ChatStream, LlmError> {
+        struct State {
+            body: Pin<Box<dyn futures_util::Stream<Item = reqwest::Result<axum::body::Bytes>> + Send>>,
+            decoder: StreamDecoder,
+            assembler: Option<StreamAssembler>,
+            pending: VecDeque<Result<ChatStreamEvent, LlmError>>,
+        }
+
+        let model = self.resolve_model(request)?;
+        let request = ChatRequest { stream: true, ..request.clone() };
+        if self.kind() == LlmProviderKind::Mock {
+            return llm_mock::reply(self, &model, &request).map(llm_mock::stream);
+        }
+        let response = self.send(client, &model, &request).await?;
+        // Some OpenAI-compatible servers ignore `stream` and answer in one piece.
+        let is_json = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+        if is_json {
+            let body: Value = response.json().await.map_err(|e| LlmError::InvalidResponse(e.to_string()))?;
+            return Ok(llm_stream::replay(self.parse_response(&model, &body)?));
+        }
+        let state = State {
+            body: Box::pin(response.bytes_stream()),
+            decoder: StreamDecoder::new(self.kind()),
+            assembler: Some(StreamAssembler::new(&self.name, self.kind(), &model)),
+            pending: VecDeque::new(),
+        };
+
+        let stream = futures_util::stream::unfold(state, |mut state| async move {
+            'next_chunk: loop {
+                if let Some(item) = state.pending.pop_front() {
+                    return Some((item, state));
+                }
+                // The assembler is gone once the stream has ended or failed.
+                let mut assembler = state.assembler.take()?;
+                let (payloads, ended) = match state.body.next().await {
+                    Some(Ok(bytes)) => (state.decoder.push(&bytes), false),
+                    Some(Err(e)) => return Some((Err(LlmError::Transport(e)), state)),
+                    None => (state.decoder.finish(), true),
+                };
+                for payload in payloads {
+                    match assembler.apply(&payload) {
+                        Ok(Some(content)) => state.pending.push_back(Ok(ChatStreamEvent::Delta { content })),
+                        Ok(None) => {}
+                        Err(e) => {
+                            state.pending.push_back(Err(e));
+                            continue 'next_chunk;
+                        }
+                    }
+                }
+                if ended && !assembler.finished() {
+                    let cut_off = LlmError::InvalidResponse("stream ended before the provider finished".to_string());
+                    state.pending.push_back(Err(cut_off));
+                } else if ended {
+                    state.pending.push_back(Ok(ChatStreamEvent::Done { response: assembler.finish() }));
+                } else {
+                    state.assembler = Some(assembler);
+                }
+            }
+        });
+        Ok(Box::pin(stream))
+    }
+
+    /// Posts the translated request with the provider's credentials and rejects non-success replies.
+    async fn send(&self, client: &Client, model: &str, request: &ChatRequest) -> Result<reqwest::Response, LlmError> {
+        let mut http_request = client.post(self.endpoint()).json(&self.request_body(model, request));
+        if let Some(api_key) = self.api_key()? {
+            http_request = match self.kind() {
+                LlmProviderKind::Anthropic => http_request.header("x-api-key", api_key),
+                _ => http_request.bearer_auth(api_key),
+            };
+        }
+        if self.kind() == LlmProviderKind::Anthropic {
+            http_request = http_request.header("anthropic-version", ANTHROPIC_VERSION);
+        }
+
+        let response = http_request.send().await.map_err(LlmError::Transport)?;
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = llm_client::retry_after(response.headers());
+            let body = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(LlmError::Upstream { status: status.as_u16(), body, retry_after });
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+impl Provider {
+    /// A keyless provider named after its kind, whose default model is `<name>-default`, for tests.
+    pub fn test(kind: LlmProviderKind, base_url: impl Into<String>) -> Self {
+        let name = serde_json::to_value(kind).unwrap().as_str().unwrap().to_string();
+        let config = LlmProviderConfig {
+            kind,
+            base_url: base_url.into(),
+            api_key_env: None,
+            default_model: Some(format!("{}-default", name)),
+            fixtures: None,
+        };
+        Provider::new(name, config).unwrap()
+    }
+}
+
+/// A provider as listed by `GET /api/llm/providers`. Never includes credentials.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ProviderInfo {
+    pub name: String,
+    pub kind: LlmProviderKind,
+    pub base_url: String,
+    pub default_model: Option<String>,
+    pub is_default: bool,
+}
+
+/// All configured providers, looked up by name.
+#[derive(Debug, Clone)]
+pub struct LlmProviders {
+    default_provider: String,
+    providers: BTreeMap<String, Provider>,
+}
+
+impl LlmProviders {
+    /// Fails if a `mock` provider's fixtures cannot be read; they are not read again later.
+    pub fn from_config(config: &LlmConfig) -> Result<Self, String> {
+        let providers = config
+            .providers
+            .iter()
+            .map(|(name, provider)| {
+                let provider = Provider::new(name.clone(), provider.clone())
+                    .map_err(|e| format!("mock LLM provider '{}': {}", name, e))?;
+                Ok((name.clone(), provider))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(LlmProviders { default_provider: config.default_provider.clone(), providers })
+    }
+
+    /// The provider called `name`, or the default one.
+    pub fn get(&self, name: Option<&str>) -> Result<&Provider, LlmError> {
+        let name = name.unwrap_or(&self.default_provider);
+        self.providers.get(name).ok_or_else(|| LlmError::UnknownProvider(name.to_string()))
+    }
+
+    pub fn list(&self) -> Vec<ProviderInfo> {
+        self.providers
+            .values()
+            .map(|p| ProviderInfo {
+                name: p.name.clone(),
+                kind: p.kind(),
+                base_url: p.config.base_url.clone(),
+                default_model: p.config.default_model.clone(),
+                is_default: p.name == self.default_provider,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::HeaderMap, routing::post, Json, Router};
+    use std::sync::{Arc, Mutex};
+
+    type Seen = Arc<Mutex<Vec<(String, HeaderMap, Value)>>>;
+
+    /// Serves canned replies in each provider's format on an ephemeral local port.
+    async fn spawn_mock_server(seen: Seen) -> String {
+        let record = |path: &'static str, reply: Value| {
+            let seen = Arc::clone(&seen);
+            post(move |headers: HeaderMap, Json(body): Json<Value>| async move {
+                seen.lock().unwrap().push((path.to_string(), headers, body));
+                Json(reply)
+            })
+        };
+        let app = Router::new()
+            .route("/v1/chat/completions", record("/v1/chat/completions", json!({
+                "model": "gpt-test-0613",
+                "choices": [{ "message": { "role": "assistant", "content": "hi from openai" }, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 12, "completion_tokens": 3 }
+            })))
+            .route("/v1/messages", record("/v1/messages", json!({
+                "model": "claude-test",
+                "content": [{ "type": "text", "text": "hi from " }, { "type": "text", "text": "anthropic" }],
+                "stop_reason": "end_turn",
+                "usage": { "input_tokens": 10, "output_tokens": 4 }
+            })))
+            .route("/api/chat", record("/api/chat", json!({
+                "model": "llama-test",
+                "message": { "role": "assistant", "content": "hi from ollama" },
+                "done_reason": "stop",
+                "prompt_eval_count": 8,
+                "eval_count": 5
+            })))
+            .route("/v1/fail/chat/completions", post(|| async {
+                (axum::http::StatusCode::TOO_MANY_REQUESTS, [(axum::http::header::RETRY_AFTER, "7")], "slow down")
+            }))
+            .route("/v1/stream/chat/completions", post(|Json(body): Json<Value>| async move {
+                assert_eq!(body["stream"], true);
+                let events = concat!(
+                    "data: {\"model\":\"gpt-stream\",\"choices\":[{\"delta\":{\"content\":\"one \"}}]}\n\n",
+                    "data: {\"choices\":[{\"delta\":{\"content\":\"two\"},\"finish_reason\":\"stop\"}]}\n\n",
+                    "data: [DONE]\n\n",
+                );
+                ([(axum::http::header::CONTENT_TYPE, "text/event-stream")], events)
+            }))
+            .route("/v1/broken/chat/completions", post(|| async {
+                "data: {\"choices\":[{\"delta\":{\"content\":\"partial\"}}]}\n\ndata: {\"error\":{\"message\":\"boom\"}}\n\n"
+            }))
+            .route("/v1/cut/chat/completions", post(|| async {
+                "data: {\"choices\":[{\"delta\":{\"content\":\"partial\"}}]}\n\n"
+            }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        base_url
+    }
+
+    fn request() -> ChatRequest {
+        let mut request = ChatRequest { max_tokens: Some(64), temperature: Some(0.5), ..ChatRequest::user("hello") };
+        request.messages.insert(0, ChatMessage { role: Role::System, content: "be brief".to_string() });
+        request
+    }
+
+    #[tokio::test]
+    async fn test_providers_translate_against_mock_server() {
+        std::env::set_var("DIRANALYZE_TEST_LLM_KEY", "sk-test");
+        let seen: Seen = Arc::default();
+        let base_url = spawn_mock_server(Arc::clone(&seen)).await;
+        let client = Client::new();
+
+        let keyed = |kind, base_url| {
+            let mut provider = Provider::test(kind, base_url);
+            provider.config.api_key_env = Some("DIRANALYZE_TEST_LLM_KEY".to_string());
+            provider
+        };
+        let openai = keyed(LlmProviderKind::OpenAi, format!("{}/v1", base_url));
+        let reply = openai.chat(&client, &request()).await.unwrap();
+        assert_eq!(reply.content, "hi from openai");
+        assert_eq!(reply.model, "gpt-test-0613");
+        assert_eq!(reply.usage, Some(Usage { input_tokens: 12, output_tokens: 3 }));
+
+        let anthropic = keyed(LlmProviderKind::Anthropic, format!("{}/", base_url));
+        let reply = anthropic.chat(&client, &request()).await.unwrap();
+        assert_eq!((reply.content.as_str(), reply.finish_reason.as_deref()), ("hi from anthropic", Some("end_turn")));
+
+        let ollama = Provider::test(LlmProviderKind::Ollama, base_url.clone());
+        let reply = ollama.chat(&client, &request()).await.unwrap();
+        assert_eq!(reply.usage, Some(Usage { input_tokens: 8, output_tokens: 5 }));
+
+        let compatible = Provider::test(LlmProviderKind::OpenAiCompatible, format!("{}/v1", base_url));
+        let reply = compatible.chat(&client, &ChatRequest { model: Some("qwen".to_string()), ..request() }).await.unwrap();
+        assert_eq!(reply.provider, "openai-compatible");
+
+        let seen = seen.lock().unwrap();
+        let (path, headers, body) = &seen[0];
+        assert_eq!(path, "/v1/chat/completions");
+        assert_eq!(headers["authorization"], "Bearer sk-test");
+        assert_eq!(body, &json!({
+            "model": "openai-default",
+            "messages": [{ "role": "system", "content": "be brief" }, { "role": "user", "content": "hello" }],
+            "max_tokens": 64,
+            "temperature": 0.5
+        }));
+        let (_, headers, body) = &seen[1];
+        assert_eq!(headers["x-api-key"], "sk-test");
+        assert_eq!(headers["anthropic-version"], ANTHROPIC_VERSION);
+        assert_eq!(body["system"], "be brief");
+        assert_eq!(body["messages"], json!([{ "role": "user", "content": "hello" }]));
+        let (_, _, body) = &seen[2];
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["options"], json!({ "num_predict": 64, "temperature": 0.5 }));
+        let (_, headers, body) = &seen[3];
+        assert!(headers.get("authorization").is_none());
+        assert_eq!(body["model"], "qwen");
+    }
+
+    #[tokio::test]
+    async fn test_errors_map_to_statuses() {
+        let base_url = spawn_mock_server(Arc::default()).await;
+        let client = Client::new();
+
+        let failing = Provider::test(LlmProviderKind::OpenAiCompatible, format!("{}/v1/fail", base_url));
+        match failing.chat(&client, &request()).await {
+            Err(e @ LlmError::Upstream { status: 429, .. }) => {
+                assert_eq!((e.status_code(), e.code()), (axum::http::StatusCode::TOO_MANY_REQUESTS, "upstream_rate_limited"));
+                assert_eq!(e.retry_after(), Some(Duration::from_secs(7)));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut keyless = Provider::test(LlmProviderKind::OpenAi, base_url);
+        keyless.config.api_key_env = Some("DIRANALYZE_TEST_UNSET_KEY".to_string());
+        assert!(matches!(keyless.chat(&client, &request()).await, Err(LlmError::MissingApiKey { .. })));
+
+        let mut default = Provider::test(LlmProviderKind::OpenAi, "http://unused");
+        default.config.default_model = None;
+        let providers = LlmProviders::from_config(&LlmConfig {
+            default_provider: "openai".to_string(),
+            providers: BTreeMap::from([("openai".to_string(), default.config.clone())]),
+            store_bodies: false,
+            max_request_tokens: None,
+            max_session_tokens: None,
+            prices: BTreeMap::new(),
+            daily_spend_cap_usd: None,
+            monthly_spend_cap_usd: None,
+            cache_enabled: false,
+            cache_ttl_secs: 60,
+            retry: Default::default(),
+        })
+        .unwrap();
+        assert_eq!(providers.get(None).unwrap(), &default);
+        assert_eq!(providers.get(Some("nope")).unwrap_err().status_code(), axum::http::StatusCode::BAD_REQUEST);
+        assert!(providers.list()[0].is_default);
+        assert!(matches!(default.resolve_model(&request()), Err(LlmError::MissingModel(_))));
+    }
+
+    #[tokio::test]
+    async fn test_chat_stream_relays_deltas_then_assembled_response() {
+        let base_url = spawn_mock_server(Arc::default()).await;
+        let client = Client::new();
+
+        let streaming = Provider::test(LlmProviderKind::OpenAiCompatible, format!("{}/v1/stream", base_url));
+        let events: Vec<_> = streaming.chat_stream(&client, &request()).await.unwrap().collect().await;
+        let events: Vec<ChatStreamEvent> = events.into_iter().map(Result::unwrap).collect();
+        assert_eq!(events[0], ChatStreamEvent::Delta { content: "one ".to_string() });
+        assert_eq!(events[1], ChatStreamEvent::Delta { content: "two".to_string() });
+        match &events[2] {
+            ChatStreamEvent::Done { response } => {
+                assert_eq!((response.content.as_str(), response.model.as_str()), ("one two", "gpt-stream"));
+                assert_eq!(response.finish_reason.as_deref(), Some("stop"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(events.len(), 3);
+
+        // A server that ignores `stream` still yields the reply as one delta.
+        let buffered = Provider::test(LlmProviderKind::OpenAi, format!("{}/v1", base_url));
+        let events: Vec<_> = buffered.chat_stream(&client, &request()).await.unwrap().collect().await;
+        assert_eq!(events[0].as_ref().unwrap(), &ChatStreamEvent::Delta { content: "hi from openai".to_string() });
+        assert!(matches!(&events[1], Ok(ChatStreamEvent::Done { response }) if response.content == "hi from openai"));
+
+        let broken = Provider::test(LlmProviderKind::OpenAiCompatible, format!("{}/v1/broken", base_url));
+        let events: Vec<_> = broken.chat_stream(&client, &request()).await.unwrap().collect().await;
+        assert!(matches!(&events[0], Ok(ChatStreamEvent::Delta { .. })));
+        assert!(matches!(&events[1], Err(LlmError::InvalidResponse(message)) if message.contains("boom")));
+        assert_eq!(events.len(), 2);
+
+        // A body that ends without `[DONE]` was cut off and is not a complete reply.
+        let cut = Provider::test(LlmProviderKind::OpenAiCompatible, format!("{}/v1/cut", base_url));
+        let events: Vec<_> = cut.chat_stream(&client, &request()).await.unwrap().collect().await;
+        assert!(matches!(&events[0], Ok(ChatStreamEvent::Delta { .. })));
+        assert!(matches!(&events[1], Err(LlmError::InvalidResponse(message)) if message.contains("before the provider finished")));
+        assert_eq!(events.len(), 2);
+    }
+}
@@ -0,0 +1,272 @@
+// diranalyze/backend/src/llm_stream.rs
+
+use serde_json::Value;
+use crate::config::LlmProviderKind;
+use crate::llm_provider::{ChatResponse, ChatStream, LlmError, Usage};
+
+/// One step of a streamed chat reply, in the same shape for every provider.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatStreamEvent {
+    /// Newly generated text, to be appended to what came before.
+    Delta { content: String },
+    /// The stream ended; `response` is the fully assembled reply.
+    Done { response: ChatResponse },
+}
+
+impl ChatStreamEvent {
+    /// The SSE event name this is sent under.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChatStreamEvent::Delta { .. } => "delta",
+            ChatStreamEvent::Done { .. } => "done",
+        }
+    }
+}
+
+/// Replays a complete reply as a stream: its text as one delta, then `done`.
+pub fn replay(response: ChatResponse) -> ChatStream {
+    let mut events = Vec::new();
+    if !response.content.is_empty() {
+        events.push(Ok(ChatStreamEvent::Delta { content: response.content.clone() }));
+    }
+    events.push(Ok(ChatStreamEvent::Done { response }));
+    Box::pin(futures_util::stream::iter(events))
+}
+
+/// Splits a streamed upstream body into payloads: the `data` of each server-sent event, or
+/// each line for Ollama, which streams newline-delimited JSON instead.
+#[derive(Debug, Default)]
+pub struct StreamDecoder {
+    ndjson: bool,
+    buffer: Vec<u8>,
+    data_lines: Vec<String>,
+}
+
+impl StreamDecoder {
+    pub fn new(kind: LlmProviderKind) -> Self {
+        StreamDecoder { ndjson: kind == LlmProviderKind::Ollama, ..StreamDecoder::default() }
+    }
+
+    /// Feeds the next chunk of the body and returns every payload it completed.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut payloads = Vec::new();
+        while let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            self.line(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']), &mut payloads);
+        }
+        payloads
+    }
+
+    /// Flushes whatever is left once the body has ended.
+    pub fn finish(&mut self) -> Vec<String> {
+        let mut payloads = Vec::new();
+        let rest = std::mem::take(&mut self.buffer);
+        self.line(String::from_utf8_lossy(&rest).trim_end_matches('\r'), &mut payloads);
+        self.line("", &mut payloads);
+        payloads
+    }
+
+    fn line(&mut self, line: &str, payloads: &mut Vec<String>) {
+        if self.ndjson {
+            if !line.trim().is_empty() {
+                payloads.push(line.to_string());
+            }
+        } else if line.is_empty() {
+            if !self.data_lines.is_empty() {
+                payloads.push(self.data_lines.join("\n"));
+                self.data_lines.clear();
+            }
+        } else if let Some(data) = line.strip_prefix("data:") {
+            self.data_lines.push(data.strip_prefix(' ').unwrap_or(data).to_string());
+        }
+        // `event:`, `id:`, `retry:` and `:` comment lines carry nothing the payload lacks.
+    }
+}
+
+/// Collects streamed payloads into the final `ChatResponse`.
+#[derive(Debug)]
+pub struct StreamAssembler {
+    provider: String,
+    kind: LlmProviderKind,
+    model: String,
+    content: String,
+    finish_reason: Option<String>,
+    usage: Option<Usage>,
+    finished: bool,
+}
+
+impl StreamAssembler {
+    pub fn new(provider: &str, kind: LlmProviderKind, model: &str) -> Self {
+        StreamAssembler {
+            provider: provider.to_string(),
+            kind,
+            model: model.to_string(),
+            content: String::new(),
+            finish_reason: None,
+            usage: None,
+            finished: false,
+        }
+    }
+
+    /// Applies one payload and returns the text it added, if any. Errors reported by the provider
+    /// inside the stream become `LlmError::InvalidResponse`.
+    pub fn apply(&mut self, payload: &str) -> Result<Option<String>, LlmError> {
+        if self.finished {
+            return Ok(None);
+        }
+        if payload == "[DONE]" {
+            self.finished = true;
+            return Ok(None);
+        }
+        let chunk: Value = serde_json::from_str(payload)
+            .map_err(|e| LlmError::InvalidResponse(format!("malformed stream chunk from {}: {}", self.provider, e)))?;
+        if let Some(error) = chunk.get("error").filter(|e| !e.is_null()) {
+            let message = error["message"].as_str().map_or_else(|| error.to_string(), str::to_string);
+            return Err(LlmError::InvalidResponse(format!("{} reported an error mid-stream: {}", self.provider, message)));
+        }
+        if let Some(model) = chunk["model"].as_str().or_else(|| chunk["message"]["model"].as_str()) {
+            self.model = model.to_string();
+        }
+        let count = |value: &Value| value.as_u64();
+
+        let delta = match self.kind {
+            LlmProviderKind::OpenAi | LlmProviderKind::OpenAiCompatible | LlmProviderKind::Mock => {
+                let choice = &chunk["choices"][0];
+                if let Some(reason) = choice["finish_reason"].as_str() {
+                    self.finish_reason = Some(reason.to_string());
+                }
+                if let Some(usage) = chunk.get("usage").filter(|u| !u.is_null()) {
+                    self.usage = Some(Usage {
+                        input_tokens: count(&usage["prompt_tokens"]).unwrap_or(0),
+                        output_tokens: count(&usage["completion_tokens"]).unwrap_or(0),
+                    });
+                }
+                choice["delta"]["content"].as_str().map(str::to_string)
+            }
+            LlmProviderKind::Anthropic => match chunk["type"].as_str() {
+                Some("message_start") => {
+                    let input_tokens = count(&chunk["message"]["usage"]["input_tokens"]).unwrap_or(0);
+                    self.usage = Some(Usage { input_tokens, output_tokens: 0 });
+                    None
+                }
+                Some("content_block_delta") => chunk["delta"]["text"].as_str().map(str::to_string),
+                Some("message_delta") => {
+                    if let Some(reason) = chunk["delta"]["stop_reason"].as_str() {
+                        self.finish_reason = Some(reason.to_string());
+                    }
+                    if let Some(output_tokens) = count(&chunk["usage"]["output_tokens"]) {
+                        let input_tokens = self.usage.map_or(0, |u| u.input_tokens);
+                        self.usage = Some(Usage { input_tokens, output_tokens });
+                    }
+                    None
+                }
+                Some("message_stop") => {
+                    self.finished = true;
+                    None
+                }
+                _ => None,
+            },
+            LlmProviderKind::Ollama => {
+                if chunk["done"] == true {
+                    self.finished = true;
+                    self.finish_reason = chunk["done_reason"].as_str().map(str::to_string);
+                    if let Some(output_tokens) = count(&chunk["eval_count"]) {
+                        let input_tokens = count(&chunk["prompt_eval_count"]).unwrap_or(0);
+                        self.usage = Some(Usage { input_tokens, output_tokens });
+                    }
+                }
+                chunk["message"]["content"].as_str().map(str::to_string)
+            }
+        };
+        let delta = delta.filter(|text| !text.is_empty());
+        if let Some(text) = &delta {
+            self.content.push_str(text);
+        }
+        Ok(delta)
+    }
+
+    /// Whether the provider's end-of-reply marker (`[DONE]`, `message_stop` or `done: true`) was
+    /// seen. A body that ends without it was cut off.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn finish(self) -> ChatResponse {
+        ChatResponse {
+            provider: self.provider,
+            model: self.model,
+            content: self.content,
+            finish_reason: self.finish_reason,
+            usage: self.usage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(kind: LlmProviderKind, body: &str) -> (Vec<String>, ChatResponse) {
+        let mut decoder = StreamDecoder::new(kind);
+        let mut assembler = StreamAssembler::new("test", kind, "requested-model");
+        let mut deltas = Vec::new();
+        // Feed byte by byte so payloads split across chunks are exercised.
+        let payloads = body.as_bytes().chunks(1).flat_map(|b| decoder.push(b)).collect::<Vec<_>>();
+        for payload in payloads.into_iter().chain(decoder.finish()) {
+            deltas.extend(assembler.apply(&payload).unwrap());
+        }
+        (deltas, assembler.finish())
+    }
+
+    #[test]
+    fn test_openai_sse_stream() {
+        let body = concat!(
+            ": keep-alive\n\n",
+            "data: {\"model\":\"gpt-x\",\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\r\n\r\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"lo ü\"},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":2}}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let (deltas, response) = assemble(LlmProviderKind::OpenAi, body);
+        assert_eq!(deltas, ["Hel", "lo ü"]);
+        assert_eq!(response.content, "Hello ü");
+        assert_eq!(response.model, "gpt-x");
+        assert_eq!(response.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(response.usage, Some(Usage { input_tokens: 3, output_tokens: 2 }));
+    }
+
+    #[test]
+    fn test_anthropic_sse_stream() {
+        let body = concat!(
+            "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"model\":\"claude-x\",\"usage\":{\"input_tokens\":9}}}\n\n",
+            "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi\"}}\n\n",
+            "event: ping\ndata: {\"type\":\"ping\"}\n\n",
+            "event: message_delta\ndata: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"},\"usage\":{\"output_tokens\":1}}\n\n",
+            "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n",
+        );
+        let (deltas, response) = assemble(LlmProviderKind::Anthropic, body);
+        assert_eq!(deltas, ["Hi"]);
+        assert_eq!(response.model, "claude-x");
+        assert_eq!(response.finish_reason.as_deref(), Some("end_turn"));
+        assert_eq!(response.usage, Some(Usage { input_tokens: 9, output_tokens: 1 }));
+    }
+
+    #[test]
+    fn test_ollama_ndjson_stream_and_errors() {
+        let body = concat!(
+            "{\"model\":\"llama\",\"message\":{\"role\":\"assistant\",\"content\":\"A\"},\"done\":false}\n",
+            "{\"model\":\"llama\",\"message\":{\"role\":\"assistant\",\"content\":\"B\"},\"done\":false}\n",
+            "{\"model\":\"llama\",\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,\"done_reason\":\"stop\",\"prompt_eval_count\":4,\"eval_count\":2}",
+        );
+        let (deltas, response) = assemble(LlmProviderKind::Ollama, body);
+        assert_eq!(deltas, ["A", "B"]);
+        assert_eq!(response.usage, Some(Usage { input_tokens: 4, output_tokens: 2 }));
+
+        let mut assembler = StreamAssembler::new("test", LlmProviderKind::Anthropic, "m");
+        let error = assembler.apply("{\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}");
+        assert!(matches!(error, Err(LlmError::InvalidResponse(message)) if message.contains("Overloaded")));
+    }
+}
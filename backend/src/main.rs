@@ -2,25 +2,44 @@
 
 use axum::{
     extract::{
+        DefaultBodyLimit,
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State as AxumState,
+        Path as AxumPath, Query, State as AxumState,
     },
-    response::IntoResponse,
+    body::Bytes,
+    http::header,
+    response::{IntoResponse, Response},
     routing::{get, get_service, post},
     Json, Router,
 };
-use reqwest::Client;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use serde_json::{json, Value};
 use std::net::SocketAddr;
 use std::path::PathBuf; // For path manipulation
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use tower_http::services::ServeDir;
-use rusqlite::{params, Connection as RusqliteConnection, Result as RusqliteResult};
+use version_store::{SqliteVersionStore, VersionStore};
 
 // --- Modules for database and version control ---
+mod blob_store;
+mod config;
 mod db_manage;
+mod db_pool;
+mod delta_store;
+mod live_events;
+mod llm_audit;
+mod llm_cache;
+mod llm_client;
+mod llm_mock;
+mod llm_provider;
+mod llm_stream;
+mod operation_log;
+mod projects;
+mod token_budget;
+mod usage_ledger;
 mod version_control;
+mod version_store;
+mod ws_protocol;
 
 // --- Structs for API requests ---
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -28,19 +47,150 @@ pub struct ScannedFileInfo {
     pub path: String,
     pub hash: String,
     pub size: i64,
+    /// Optional file body (base64). When present the backend keeps the bytes in its blob store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_base64: Option<String>,
 }
 
 #[derive(Debug, serde::Deserialize)]
 pub struct InitialSnapshotRequest {
     pub project_root_name: String,
+    /// Stable identifier of the project root chosen by the client. Projects are matched on this,
+    /// not on the folder name; when omitted the project is matched on `project_root_name`.
+    pub root_fingerprint: Option<String>,
     pub files: Vec<ScannedFileInfo>,
 }
 
+#[derive(Debug, serde::Deserialize)]
+pub struct CreateProjectRequest {
+    pub display_name: String,
+    pub root_fingerprint: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct UpdateProjectRequest {
+    pub display_name: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CreateSnapshotRequest {
+    /// The root the project was loaded from. In per-project mode it selects the database, as in
+    /// `InitialSnapshotRequest`; version ids are only unique within one database.
+    pub project_root_name: String,
+    pub parent_version_id: i64,
+    pub description: Option<String>,
+    pub files: Vec<ScannedFileInfo>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OpenProjectDatabaseRequest {
+    pub project_root_name: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RestoreRequest {
+    /// The version the working tree is currently at.
+    pub from_version_id: i64,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PaginationParams {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct LogQueryParams {
+    pub operation_type: Option<String>,
+    pub version_id: Option<i64>,
+    pub target_entity: Option<String>,
+    /// RFC 3339 timestamps; `since` is inclusive, `until` exclusive.
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// Only entries with a larger `log_id` are returned (the previous page's `next_cursor`).
+    pub cursor: Option<i64>,
+    pub limit: Option<i64>,
+    /// `jsonl` or `csv` streams every matching entry instead of returning one JSON page.
+    pub format: Option<String>,
+}
+
+/// Snapshot payloads may carry full file bodies, so allow more than axum's 2 MB default.
+const MAX_UPLOAD_BYTES: usize = 256 * 1024 * 1024;
+
+const DEFAULT_PAGE_LIMIT: i64 = 50;
+const MAX_PAGE_LIMIT: i64 = 500;
+/// Rows fetched per database read while streaming a log export.
+const LOG_EXPORT_BATCH: i64 = 500;
+/// Names the LLM session a request belongs to, for the per-session token budget.
+const LLM_SESSION_HEADER: &str = "x-diranalyze-session";
+/// Names the project an LLM request is made for, for the usage ledger.
+const LLM_PROJECT_HEADER: &str = "x-diranalyze-project";
+/// Set on proxied replies to the estimated prompt size.
+const PROMPT_TOKENS_HEADER: &str = "x-diranalyze-prompt-tokens";
+/// `bypass` on a request skips the response cache lookup; on a reply it says `hit`, `miss` or
+/// `bypass` when the cache is enabled.
+const LLM_CACHE_HEADER: &str = "x-diranalyze-cache";
+
 // --- Application State for Axum ---
 #[derive(Clone)]
 struct AppState {
-    http_client: Client,
-    db_pool: Arc<Mutex<RusqliteConnection>>,
+    llm_client: Arc<llm_client::LlmClient>,
+    db: Arc<db_manage::DbRegistry>,
+    events: live_events::EventBus,
+    llm: Arc<llm_provider::LlmProviders>,
+    llm_store_bodies: bool,
+    token_budget: Arc<token_budget::TokenBudget>,
+    cost_policy: Arc<usage_ledger::CostPolicy>,
+    llm_cache: Arc<llm_cache::LlmCache>,
+}
+
+impl AppState {
+    fn new(app_config: &config::AppConfig, db: Arc<db_manage::DbRegistry>) -> Self {
+        let retry = app_config.llm.retry;
+        let llm_client = Arc::new(llm_client::LlmClient::new(&retry).expect("Failed to build the LLM HTTP client"));
+        println!(
+            "[CONFIG] LLM calls: up to {} attempt(s), {}s connect / {}s read timeout.",
+            retry.max_attempts, retry.connect_timeout_secs, retry.read_timeout_secs
+        );
+        let events = live_events::EventBus::new(live_events::EVENT_CHANNEL_CAPACITY);
+        let llm = llm_provider::LlmProviders::from_config(&app_config.llm).unwrap_or_else(|e| {
+            eprintln!("[CONFIG] {}", e);
+            std::process::exit(2);
+        });
+        for name in app_config.llm.providers.keys() {
+            if let Some(fixtures) = llm.get(Some(name)).ok().and_then(llm_provider::Provider::mock_fixtures) {
+                println!("[CONFIG] Mock LLM provider '{}' has {} fixture(s).", name, fixtures.len());
+            }
+        }
+        let llm = Arc::new(llm);
+        println!("[CONFIG] Default LLM provider: {}", app_config.llm.default_provider);
+        if app_config.llm.store_bodies {
+            println!("[CONFIG] LLM request and response bodies will be kept in the blob store.");
+        }
+        let token_budget = Arc::new(token_budget::TokenBudget::from_config(&app_config.llm));
+        if let Some(limit) = token_budget.max_request_tokens() {
+            println!("[CONFIG] LLM prompts are limited to {} tokens per request.", limit);
+        }
+        if let Some(limit) = token_budget.max_session_tokens() {
+            println!("[CONFIG] LLM sessions are limited to {} tokens.", limit);
+        }
+        let cost_policy = Arc::new(usage_ledger::CostPolicy::from_config(&app_config.llm));
+        println!("[CONFIG] Prices configured for {} LLM model(s).", app_config.llm.prices.len());
+        let llm_cache = Arc::new(llm_cache::LlmCache::from_config(&app_config.llm));
+        if llm_cache.enabled() {
+            println!("[CONFIG] LLM responses are cached for {} seconds.", llm_cache.ttl_secs());
+        }
+        AppState {
+            llm_client,
+            db,
+            events,
+            llm,
+            llm_store_bodies: app_config.llm.store_bodies,
+            token_budget,
+            cost_policy,
+            llm_cache,
+        }
+    }
 }
 
 // --- Main Application ---
@@ -48,7 +198,13 @@ struct AppState {
 async fn main() {
     dotenvy::dotenv().expect(".env file not found");
 
-    let db_file_path_str = ".diranalyze_db.sqlite3"; // Defined once for consistency
+    let app_config = config::load().unwrap_or_else(|e| {
+        eprintln!("[CONFIG] {}", e);
+        std::process::exit(2);
+    });
+    let db_file_path_str = app_config.database.path.to_string_lossy().into_owned();
+    let db_file_path_str = db_file_path_str.as_str();
+    println!("[CONFIG] Database mode: {:?}", app_config.database.mode);
 
     // --- Step 1: Initialize and verify DB schema BEFORE server operations ---
     println!("\n--- DATABASE INITIALIZATION & VERIFICATION PHASE ---");
@@ -114,110 +270,1745 @@ async fn main() {
 
     // --- Step 2: Setup for the actual server (re-open connection) ---
     println!("[SERVER_SETUP] Opening new database connection for server operations at: '{}'", absolute_db_path_for_log);
-    let db_conn_for_server = db_manage::open_db_connection_with_path(db_file_path_str)
+    let db_registry = db_manage::DbRegistry::new(&app_config.database)
         .expect("Failed to re-open DB connection for server");
-    db_manage::initialize_database(&db_conn_for_server) // Call again to be sure server's connection sees it
-        .expect("Failed to re-initialize database for server use (this should be fine if first init worked)");
+    if db_registry.mode() == config::DbMode::PerProject {
+        println!("[SERVER_SETUP] Per-project databases will be created under '{}'", app_config.database.projects_dir.display());
+    }
     println!("[SERVER_SETUP] Database connection for server ready.");
-    let db_pool = Arc::new(Mutex::new(db_conn_for_server));
+    let db = Arc::new(db_registry);
 
-    let http_client = Client::new();
-    let app_state = AppState { http_client, db_pool };
-    let assets_dir = std::path::PathBuf::from("..");
-
-    let app = Router::new()
-        .route("/api/llm_proxy", post(llm_proxy_handler))
-        .route("/ws", get(websocket_handler))
-        .route("/api/snapshot/initial", post(handle_create_initial_snapshot))
-        .fallback_service(get_service(ServeDir::new(assets_dir)))
-        .with_state(app_state);
+    let app_state = AppState::new(&app_config, db);
+    let app = build_router(app_state, PathBuf::from(".."));
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 8000));
     println!("--> DirAnalyze backend serving on http://{}", addr);
-    println!("--> WebSocket endpoint available at ws://{}/ws", addr);
+    println!("--> WebSocket endpoint available at ws://{}/ws (live OperationLog and snapshot events)", addr);
     println!("--> Database should be fully initialized and accessible for server operations.");
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
 
+/// Every API route, with static files from `assets_dir` for everything else.
+fn build_router(app_state: AppState, assets_dir: PathBuf) -> Router {
+    Router::new()
+        .route("/api/llm_proxy", post(llm_proxy_handler))
+        .route("/api/llm/providers", get(handle_list_llm_providers))
+        .route("/api/llm/cache", get(handle_get_llm_cache).delete(handle_clear_llm_cache))
+        .route("/api/llm/recordings", get(handle_get_llm_recordings))
+        .route("/api/tokens/count", post(handle_count_tokens))
+        .route("/api/usage", get(handle_get_usage))
+        .route("/ws", get(websocket_handler))
+        .route("/api/databases", get(handle_list_databases))
+        .route("/api/databases/open", post(handle_open_project_database))
+        .route("/api/snapshot/initial", post(handle_create_initial_snapshot))
+        .route("/api/blobs", post(handle_upload_blob))
+        .route("/api/blobs/:content_hash", get(handle_get_blob))
+        .route("/api/projects", get(handle_list_projects).post(handle_create_project))
+        .route(
+            "/api/projects/:project_id",
+            get(handle_get_project).patch(handle_update_project).delete(handle_delete_project),
+        )
+        .route("/api/projects/:project_id/snapshots", post(handle_create_snapshot))
+        .route("/api/projects/:project_id/versions", get(handle_list_versions))
+        .route("/api/projects/:project_id/versions/:version_id", get(handle_get_version))
+        .route("/api/projects/:project_id/versions/:version_id/files", get(handle_get_version_files))
+        .route(
+            "/api/projects/:project_id/versions/:from_version_id/diff/:to_version_id",
+            get(handle_diff_versions),
+        )
+        .route("/api/projects/:project_id/versions/:version_id/restore-plan", post(handle_restore_plan))
+        .route("/api/projects/:project_id/versions/:version_id/restore", post(handle_record_restore))
+        .route("/api/log", get(handle_list_log))
+        .route("/api/log/verify", get(handle_verify_log))
+        .fallback_service(get_service(ServeDir::new(assets_dir)))
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES))
+        .with_state(app_state)
+}
+
 // --- API Handlers ---
-async fn llm_proxy_handler( /* ... same as before ... */ AxumState(state): AxumState<AppState>, Json(payload): Json<Value>) -> Result<Json<Value>, axum::http::StatusCode> {
-    let api_key = match std::env::var("OPENAI_API_KEY") { Ok(key) => key, Err(_) => { eprintln!("--> ERROR: OPENAI_API_KEY not found in .env file."); return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR); } };
-    let api_url = "https://api.openai.com/v1/chat/completions";
-    println!("--> LLM_PROXY: Forwarding request to LLM API...");
-    let response = state.http_client.post(api_url).bearer_auth(api_key).json(&payload).send().await;
-    match response {
-        Ok(res) => {
-            if res.status().is_success() { println!("--> LLM_PROXY: Success from LLM API"); let body: Value = res.json().await.unwrap_or_else(|_| json!({"error": "Failed to parse LLM response"})); Ok(Json(body)) }
-            else { let status = res.status(); let error_text = res.text().await.unwrap_or_else(|_| "Unknown error".to_string()); eprintln!("--> LLM_PROXY: Error from LLM API ({}): {}", status, error_text); Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR) }
+/// Sends a provider-neutral chat request to the provider it names (or the configured default)
+/// and answers with the provider-neutral reply.
+/// With `"stream": true` the reply is relayed as server-sent events: `delta` events with new
+/// text, then one `done` event with the assembled response, or an `error` event.
+/// Prompts over the token budget are refused with 413, and every call is refused with 402 once
+/// a spend cap is used up. With the response cache enabled, a repeated request is answered
+/// from the cache without contacting the provider or counting against budgets.
+async fn llm_proxy_handler(
+    AxumState(state): AxumState<AppState>,
+    headers: axum::http::HeaderMap,
+    Json(request): Json<llm_provider::ChatRequest>,
+) -> Result<Response, axum::http::StatusCode> {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+    use futures_util::StreamExt;
+
+    let provider = match state.llm.get(request.provider.as_deref()) {
+        Ok(provider) => provider,
+        Err(e) => {
+            eprintln!("--> LLM_PROXY: {}", e);
+            return Ok(e.into_response());
+        }
+    };
+    let project_id = llm_project(&headers)?;
+    if let Err(refused) = check_llm_project(&state, project_id).await {
+        eprintln!("--> LLM_PROXY: Refused: {}", refused);
+        return refused.into_response();
+    }
+    let bypass_cache = headers
+        .get(LLM_CACHE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("bypass"));
+    let mut reply_headers = axum::http::HeaderMap::new();
+    let lookup = cached_reply(&state, provider, &request, bypass_cache).await;
+    if let Some(status) = lookup.header_value() {
+        reply_headers.insert(LLM_CACHE_HEADER, axum::http::HeaderValue::from_static(status));
+    }
+
+    let stream = if let CacheLookup::Hit(response) = lookup {
+        println!("--> LLM_PROXY: Answered from the response cache for provider '{}'.", provider.name);
+        if !request.stream {
+            return Ok((reply_headers, Json(json!(response))).into_response());
+        }
+        llm_stream::replay(response)
+    } else {
+        let context = match admit_prompt(&state, provider, &request, llm_session(&headers), project_id).await {
+            Ok(context) => context,
+            Err(refused) => {
+                eprintln!("--> LLM_PROXY: Refused: {}", refused);
+                return refused.into_response();
+            }
+        };
+        println!(
+            "--> LLM_PROXY: Forwarding request to provider '{}' ({:?}), ~{} prompt tokens...",
+            provider.name,
+            provider.kind(),
+            context.prompt_tokens
+        );
+        reply_headers.insert(PROMPT_TOKENS_HEADER, axum::http::HeaderValue::from(context.prompt_tokens));
+
+        if !request.stream {
+            return match audited_chat(&state, provider, &request, context).await {
+                Ok(response) => Ok((reply_headers, Json(json!(response))).into_response()),
+                Err(e) => {
+                    eprintln!("--> LLM_PROXY: {}", e);
+                    Ok(e.into_response())
+                }
+            };
+        }
+
+        match audited_chat_stream(&state, provider, &request, context).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("--> LLM_PROXY: {}", e);
+                return Ok(e.into_response());
+            }
+        }
+    };
+    let events = stream.map(|item| {
+        let event = match item {
+            Ok(event) => Event::default().event(event.name()).json_data(&event),
+            Err(e) => {
+                eprintln!("--> LLM_PROXY: Stream failed: {}", e);
+                Event::default().event("error").json_data(json!({ "type": "error", "message": e.to_string() }))
+            }
+        };
+        Ok::<_, std::convert::Infallible>(event.expect("stream events always serialize"))
+    });
+    Ok((reply_headers, Sse::new(events).keep_alive(KeepAlive::default())).into_response())
+}
+
+/// What the response cache made of a request.
+enum CacheLookup {
+    Disabled,
+    Bypassed,
+    Miss,
+    Hit(llm_provider::ChatResponse),
+}
+
+impl CacheLookup {
+    /// The `x-diranalyze-cache` reply header, when the cache is enabled.
+    fn header_value(&self) -> Option<&'static str> {
+        match self {
+            CacheLookup::Disabled => None,
+            CacheLookup::Bypassed => Some("bypass"),
+            CacheLookup::Miss => Some("miss"),
+            CacheLookup::Hit(_) => Some("hit"),
+        }
+    }
+}
+
+/// Looks `request` up in the response cache. A hit is recorded as a `cached` `LLM_CALL` entry
+/// in the same write, so the log still shows every answer that was served. Requests whose model
+/// cannot be resolved are treated as misses and fail upstream as usual.
+async fn cached_reply(
+    state: &AppState,
+    provider: &llm_provider::Provider,
+    request: &llm_provider::ChatRequest,
+    bypass: bool,
+) -> CacheLookup {
+    let cache = &state.llm_cache;
+    if !cache.enabled() {
+        return CacheLookup::Disabled;
+    }
+    if bypass {
+        cache.count_bypass();
+        return CacheLookup::Bypassed;
+    }
+    let Ok(audit) = llm_audit::LlmCallAudit::start(provider, request) else {
+        cache.count_miss();
+        return CacheLookup::Miss;
+    };
+    let store_bodies = state.llm_store_bodies;
+    let db = state.db.active().await;
+    let result = state
+        .events
+        .write_logged(&db, move |conn| {
+            let Some(response) = llm_cache::take_hit(conn, &audit.request_sha256(), chrono::Utc::now())? else {
+                return Ok(None);
+            };
+            audit.finish(llm_audit::LlmCallOutcome::Cached(response.clone())).write(conn, store_bodies)?;
+            Ok(Some(response))
+        })
+        .await;
+    match result {
+        Ok(Some(response)) => {
+            cache.count_hit();
+            CacheLookup::Hit(response)
+        }
+        Ok(None) => {
+            cache.count_miss();
+            CacheLookup::Miss
+        }
+        Err(e) => {
+            eprintln!("--> LLM_PROXY: Response cache lookup failed: {:?}", e);
+            cache.count_miss();
+            CacheLookup::Miss
+        }
+    }
+}
+
+/// Why `check_llm_project` or `admit_prompt` refused to send a prompt.
+enum PromptRefused {
+    Llm(llm_provider::LlmError),
+    Budget(token_budget::BudgetExceeded),
+    SpendCap(usage_ledger::SpendCapReached),
+    UnknownProject(i64),
+    /// The database could not be read, so the project or spend caps could not be checked.
+    Ledger(rusqlite::Error),
+}
+
+impl std::fmt::Display for PromptRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PromptRefused::Llm(e) => write!(f, "{}", e),
+            PromptRefused::Budget(e) => write!(f, "{}", e),
+            PromptRefused::SpendCap(e) => write!(f, "{}", e),
+            PromptRefused::UnknownProject(id) => write!(f, "project {} does not exist", id),
+            PromptRefused::Ledger(e) => write!(f, "could not check the project or LLM spend caps: {:?}", e),
         }
-        Err(e) => { eprintln!("--> LLM_PROXY: Failed to send request to LLM API: {}", e); Err(axum::http::StatusCode::BAD_GATEWAY) }
     }
 }
 
+impl PromptRefused {
+    /// The HTTP answer: 413 or 402 with a JSON body saying which limit was hit.
+    fn into_response(self) -> Result<Response, axum::http::StatusCode> {
+        let (status, error, mut body) = match &self {
+            PromptRefused::Llm(e) => return Ok(e.to_response()),
+            PromptRefused::UnknownProject(_) => return Err(axum::http::StatusCode::NOT_FOUND),
+            PromptRefused::Ledger(_) => return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR),
+            PromptRefused::Budget(e) => (axum::http::StatusCode::PAYLOAD_TOO_LARGE, "token_budget_exceeded", json!(e)),
+            PromptRefused::SpendCap(e) => (axum::http::StatusCode::PAYMENT_REQUIRED, "spend_cap_reached", json!(e)),
+        };
+        body["error"] = json!(error);
+        body["message"] = json!(self.to_string());
+        Ok((status, Json(body)).into_response())
+    }
+
+    /// The `/ws` error frame code and message.
+    fn ws_error(self) -> (ws_protocol::ErrorCode, String) {
+        let code = match &self {
+            PromptRefused::Llm(e) => ws_protocol::ErrorCode::from_status(e.status_code()),
+            PromptRefused::Budget(_) => ws_protocol::ErrorCode::BudgetExceeded,
+            PromptRefused::SpendCap(_) => ws_protocol::ErrorCode::SpendCapReached,
+            PromptRefused::UnknownProject(_) => ws_protocol::ErrorCode::NotFound,
+            PromptRefused::Ledger(_) => ws_protocol::ErrorCode::Internal,
+        };
+        (code, self.to_string())
+    }
+}
+
+/// Who an admitted LLM call is accounted to, and the prompt size it was admitted with.
+#[derive(Debug, Clone)]
+struct LlmCallContext {
+    session: Option<String>,
+    project_id: Option<i64>,
+    prompt_tokens: u64,
+}
+
+/// The LLM session named by the request headers, if any.
+fn llm_session(headers: &axum::http::HeaderMap) -> Option<String> {
+    headers
+        .get(LLM_SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|session| !session.is_empty())
+        .map(str::to_string)
+}
+
+/// The project id named by the request headers, if any. A header that is not an id is a 400.
+fn llm_project(headers: &axum::http::HeaderMap) -> Result<Option<i64>, axum::http::StatusCode> {
+    headers
+        .get(LLM_PROJECT_HEADER)
+        .map(|value| value.to_str().ok().and_then(|v| v.trim().parse().ok()).ok_or(axum::http::StatusCode::BAD_REQUEST))
+        .transpose()
+}
+
+/// Refuses a call naming a project that does not exist. Runs before the cache lookup, so a bad
+/// project header is answered the same way whether or not the reply is cached.
+async fn check_llm_project(state: &AppState, project_id: Option<i64>) -> Result<(), PromptRefused> {
+    let Some(project_id) = project_id else {
+        return Ok(());
+    };
+    let db = state.db.active().await;
+    let project = db.read(move |conn| projects::get_project(conn, project_id)).await.map_err(PromptRefused::Ledger)?;
+    match project {
+        Some(_) => Ok(()),
+        None => Err(PromptRefused::UnknownProject(project_id)),
+    }
+}
+
+/// Refuses the call if a spend cap is used up, then estimates the prompt of `request` and admits
+/// it against the token budget, counting it towards `session`. The project is checked earlier by
+/// `check_llm_project`.
+async fn admit_prompt(
+    state: &AppState,
+    provider: &llm_provider::Provider,
+    request: &llm_provider::ChatRequest,
+    session: Option<String>,
+    project_id: Option<i64>,
+) -> Result<LlmCallContext, PromptRefused> {
+    let model = provider.resolve_model(request).map_err(PromptRefused::Llm)?;
+    let policy = Arc::clone(&state.cost_policy);
+    let db = state.db.active().await;
+    let reached = db.read(move |conn| policy.exhausted_cap(conn, chrono::Utc::now())).await.map_err(PromptRefused::Ledger)?;
+    if let Some(reached) = reached {
+        return Err(PromptRefused::SpendCap(reached));
+    }
+    let estimate = token_budget::estimate_prompt(provider.kind(), &model, &request.messages);
+    state.token_budget.admit(session.as_deref(), estimate.prompt_tokens).map_err(PromptRefused::Budget)?;
+    Ok(LlmCallContext { session, project_id, prompt_tokens: estimate.prompt_tokens })
+}
+
+/// Works out what a completed call used and cost, and counts its reply against the session's
+/// token budget. Counts the provider did not report are estimated.
+fn bill_reply(
+    state: &AppState,
+    provider: &llm_provider::Provider,
+    context: &LlmCallContext,
+    response: &llm_provider::ChatResponse,
+) -> usage_ledger::UsageEntry {
+    let (input_tokens, output_tokens, tokens_estimated) = match response.usage {
+        Some(usage) => (usage.input_tokens, usage.output_tokens, false),
+        None => (
+            context.prompt_tokens,
+            token_budget::estimate_text(provider.kind(), &response.model, &response.content),
+            true,
+        ),
+    };
+    state.token_budget.charge(context.session.as_deref(), output_tokens);
+    usage_ledger::UsageEntry {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        provider: provider.name.clone(),
+        model: response.model.clone(),
+        project_id: context.project_id,
+        session: context.session.clone(),
+        input_tokens,
+        output_tokens,
+        tokens_estimated,
+        cost_usd: state.cost_policy.cost(&response.model, input_tokens, output_tokens),
+        log_id: None,
+    }
+}
+
+/// Gives the prompt tokens `admit_prompt` counted back to the session of a call that failed.
+fn refund_prompt(state: &AppState, context: &LlmCallContext) {
+    state.token_budget.refund(context.session.as_deref(), context.prompt_tokens);
+}
+
+/// Sends a non-streaming chat request and records it as an `LLM_CALL` entry, successful or not.
+async fn audited_chat(
+    state: &AppState,
+    provider: &llm_provider::Provider,
+    request: &llm_provider::ChatRequest,
+    context: LlmCallContext,
+) -> Result<llm_provider::ChatResponse, llm_provider::LlmError> {
+    let audit = llm_audit::LlmCallAudit::start(provider, request).inspect_err(|_| refund_prompt(state, &context))?;
+    let result = state.llm_client.chat(provider, request).await;
+    let (outcome, usage) = match &result {
+        Ok(response) => (
+            llm_audit::LlmCallOutcome::Completed(response.clone()),
+            Some(bill_reply(state, provider, &context, response)),
+        ),
+        Err(e) => {
+            refund_prompt(state, &context);
+            (llm_audit::LlmCallOutcome::from_error(e), None)
+        }
+    };
+    record_llm_call(state, audit.finish(outcome), usage).await;
+    result
+}
+
+/// Opens a streamed chat reply that records its `LLM_CALL` entry once the stream ends, fails or
+/// is dropped by the client.
+async fn audited_chat_stream(
+    state: &AppState,
+    provider: &llm_provider::Provider,
+    request: &llm_provider::ChatRequest,
+    context: LlmCallContext,
+) -> Result<llm_provider::ChatStream, llm_provider::LlmError> {
+    let audit = llm_audit::LlmCallAudit::start(provider, request).inspect_err(|_| refund_prompt(state, &context))?;
+    match state.llm_client.chat_stream(provider, request).await {
+        Ok(stream) => {
+            let (state, provider) = (state.clone(), provider.clone());
+            Ok(llm_audit::audit_stream(stream, move |outcome| {
+                let usage = match &outcome {
+                    llm_audit::LlmCallOutcome::Completed(response) => Some(bill_reply(&state, &provider, &context, response)),
+                    llm_audit::LlmCallOutcome::Failed { .. } => {
+                        refund_prompt(&state, &context);
+                        None
+                    }
+                    llm_audit::LlmCallOutcome::Cached(_) => None,
+                };
+                let record = audit.finish(outcome);
+                tokio::spawn(async move { record_llm_call(&state, record, usage).await });
+            }))
+        }
+        Err(e) => {
+            refund_prompt(state, &context);
+            record_llm_call(state, audit.finish(llm_audit::LlmCallOutcome::from_error(&e)), None).await;
+            Err(e)
+        }
+    }
+}
+
+/// Appends the `LLM_CALL` entry, and the `UsageLedger` row of a completed call, to the active
+/// database and pushes the entry to `/ws` subscribers. With the cache enabled, a completed reply
+/// is also cached under the request hash, linked to the entry.
+/// A failure here is logged but never fails the chat request itself.
+async fn record_llm_call(state: &AppState, record: llm_audit::LlmCallRecord, usage: Option<usage_ledger::UsageEntry>) {
+    let store_bodies = state.llm_store_bodies;
+    let cache_ttl = Some(state.llm_cache.ttl()).filter(|_| state.llm_cache.enabled() && record.fresh_response().is_some());
+    let db = state.db.active().await;
+    let result = state
+        .events
+        .write_logged(&db, move |conn| {
+            let log_id = record.write(conn, store_bodies)?;
+            if let (Some(ttl), Some(response)) = (cache_ttl, record.fresh_response()) {
+                let key = record.audit().request_sha256();
+                llm_cache::store(conn, &key, response, ttl, Some(log_id), chrono::Utc::now())?;
+            }
+            let cost_usd = match usage {
+                Some(usage) => {
+                    usage_ledger::record_usage(conn, &usage_ledger::UsageEntry { log_id: Some(log_id), ..usage.clone() })?;
+                    usage.cost_usd
+                }
+                None => None,
+            };
+            Ok((log_id, cost_usd))
+        })
+        .await;
+    match result {
+        Ok((log_id, cost_usd)) => {
+            if cache_ttl.is_some() {
+                state.llm_cache.count_store();
+            }
+            println!(
+                "--> LLM_PROXY: Recorded LLM_CALL as OperationLog entry {} (cost {})",
+                log_id,
+                cost_usd.map_or_else(|| "unpriced".to_string(), |cost| format!("${:.6}", cost))
+            )
+        }
+        Err(e) => eprintln!("--> LLM_PROXY: Failed to record LLM_CALL: {:?}", e),
+    }
+}
+
+/// The configured providers, each with the state of its circuit breaker.
+async fn handle_list_llm_providers(AxumState(state): AxumState<AppState>) -> Json<Value> {
+    let providers: Vec<Value> = state
+        .llm
+        .list()
+        .into_iter()
+        .map(|provider| {
+            let circuit = state.llm_client.circuit_status(&provider.name);
+            let mut info = json!(provider);
+            info["circuit"] = json!(circuit);
+            info
+        })
+        .collect();
+    Json(json!({ "providers": providers }))
+}
+
+/// Response cache settings, what the `LlmCache` table of the active database holds, and the
+/// hit/miss counts since startup.
+async fn handle_get_llm_cache(AxumState(state): AxumState<AppState>) -> Result<Json<Value>, axum::http::StatusCode> {
+    let db = state.db.active().await;
+    let stats = db.read(|conn| llm_cache::table_stats(conn, chrono::Utc::now())).await.map_err(|e| {
+        eprintln!("--> API_LLM_CACHE: Error reading LlmCache: {:?}", e);
+        axum::http::StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let cache = &state.llm_cache;
+    Ok(Json(json!({
+        "enabled": cache.enabled(),
+        "ttl_secs": cache.ttl_secs(),
+        "entries": stats.entries,
+        "expired_entries": stats.expired_entries,
+        "total_hits": stats.total_hits,
+        "since_startup": cache.counters(),
+    })))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ClearLlmCacheParams {
+    /// Only remove entries whose TTL has run out.
+    #[serde(default)]
+    expired_only: bool,
+}
+
+/// Empties the response cache of the active database. The `LLM_CALL` entries stay in the log.
+async fn handle_clear_llm_cache(
+    AxumState(state): AxumState<AppState>,
+    Query(params): Query<ClearLlmCacheParams>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let db = state.db.active().await;
+    let result = db
+        .write(move |conn| match params.expired_only {
+            true => llm_cache::purge_expired(conn, chrono::Utc::now()),
+            false => llm_cache::clear(conn),
+        })
+        .await;
+    match result {
+        Ok(removed) => {
+            println!("--> API_LLM_CACHE: Removed {} cached response(s).", removed);
+            Ok(Json(json!({ "removed": removed })))
+        }
+        Err(e) => {
+            eprintln!("--> API_LLM_CACHE: Error clearing LlmCache: {:?}", e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Turns the `LLM_CALL` entries of the active database whose bodies were stored into a mock
+/// fixture file, so a session recorded against a real provider can be replayed offline.
+async fn handle_get_llm_recordings(
+    AxumState(state): AxumState<AppState>,
+    Query(params): Query<LogQueryParams>,
+) -> Result<Json<llm_mock::FixtureFile>, axum::http::StatusCode> {
+    let filter = log_filter(&params)?;
+    let db = state.db.active().await;
+    match db.read(move |conn| llm_mock::recorded_fixtures(conn, &filter)).await {
+        Ok(recordings) => {
+            println!("--> API_LLM_RECORDINGS: Exported {} recorded LLM call(s) as fixtures.", recordings.fixtures.len());
+            Ok(Json(recordings))
+        }
+        Err(e) => {
+            eprintln!("--> API_LLM_RECORDINGS: Error reading recorded LLM calls: {:?}", e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Estimates the prompt a chat request would send, and whether the token budget would let it
+/// through, without sending it or counting it against the session.
+async fn handle_count_tokens(
+    AxumState(state): AxumState<AppState>,
+    headers: axum::http::HeaderMap,
+    Json(request): Json<llm_provider::ChatRequest>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let resolved = state
+        .llm
+        .get(request.provider.as_deref())
+        .and_then(|provider| Ok((provider, provider.resolve_model(&request)?)));
+    let (provider, model) = resolved.map_err(|e| {
+        eprintln!("--> API_TOKENS: {}", e);
+        e.status_code()
+    })?;
+    let session = llm_session(&headers);
+    let estimate = token_budget::estimate_prompt(provider.kind(), &model, &request.messages);
+    let budget = &state.token_budget;
+    let refusal = budget.check(session.as_deref(), estimate.prompt_tokens).err();
+    Ok(Json(json!({
+        "provider": provider.name,
+        "model": model,
+        "encoding": estimate.encoding,
+        "prompt_tokens": estimate.prompt_tokens,
+        "exact": estimate.exact,
+        "prompt_cost_usd": state.cost_policy.cost(&model, estimate.prompt_tokens, 0),
+        "budget": {
+            "max_request_tokens": budget.max_request_tokens(),
+            "max_session_tokens": budget.max_session_tokens(),
+            "session_used": budget.session_used(session.as_deref()),
+            "fits": refusal.is_none(),
+            "refusal": refusal.map(|e| e.to_string()),
+        },
+    })))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct UsageQueryParams {
+    /// RFC 3339 timestamps; `since` is inclusive, `until` exclusive.
+    since: Option<String>,
+    until: Option<String>,
+}
+
+/// Token usage and cost from the `UsageLedger` of the active database: overall totals, rollups
+/// per UTC day, project and model, and spending against the caps.
+async fn handle_get_usage(
+    AxumState(state): AxumState<AppState>,
+    Query(params): Query<UsageQueryParams>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let bound = |value: &Option<String>| match value {
+        Some(value) => normalize_log_timestamp(value).map(Some).ok_or(axum::http::StatusCode::BAD_REQUEST),
+        None => Ok(None),
+    };
+    let filter = usage_ledger::UsageFilter { since: bound(&params.since)?, until: bound(&params.until)? };
+    let policy = Arc::clone(&state.cost_policy);
+    let db = state.db.active().await;
+    let result = db
+        .read(move |conn| {
+            use usage_ledger::RollupGroup;
+            Ok(json!({
+                "totals": usage_ledger::usage_totals(conn, &filter)?,
+                "by_day": usage_ledger::usage_rollups(conn, &filter, RollupGroup::Day)?,
+                "by_project": usage_ledger::usage_rollups(conn, &filter, RollupGroup::Project)?,
+                "by_model": usage_ledger::usage_rollups(conn, &filter, RollupGroup::Model)?,
+                "spend": policy.spend_status(conn, chrono::Utc::now())?,
+            }))
+        })
+        .await;
+    result.map(Json).map_err(|e| {
+        eprintln!("--> API_USAGE: Error reading UsageLedger: {:?}", e);
+        axum::http::StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn handle_list_databases(
+    AxumState(state): AxumState<AppState>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    match state.db.list().await {
+        Ok(databases) => Ok(Json(json!({ "mode": state.db.mode(), "databases": databases }))),
+        Err(e) => {
+            eprintln!("--> API_DATABASES: Error listing databases: {}", e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_open_project_database(
+    AxumState(state): AxumState<AppState>,
+    Json(payload): Json<OpenProjectDatabaseRequest>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    match state.db.open_project(&payload.project_root_name).await {
+        Ok(_) => {
+            println!("--> API_DATABASES: Active project is now '{}'", payload.project_root_name);
+            state.events.publish(live_events::LiveEvent::DatabaseOpened { project_root_name: payload.project_root_name.clone() });
+            Ok(Json(json!({
+                "message": "Project database is now active",
+                "project_root_name": payload.project_root_name,
+                "mode": state.db.mode()
+            })))
+        }
+        Err(e) => {
+            eprintln!("--> API_DATABASES: Could not open database for '{}': {}", payload.project_root_name, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Converts API file entries into the version_control representation, decoding any uploaded
+/// bodies and verifying their hashes. Mismatches are rejected with a 422 listing every offending path.
+fn to_version_control_files(
+    files: Vec<ScannedFileInfo>,
+) -> Result<Vec<version_control::ScannedFileInfo>, (axum::http::StatusCode, Json<Value>)> {
+    let mut converted = files
+        .into_iter()
+        .map(|f| {
+            let content = match f.content_base64 {
+                Some(encoded) => Some(BASE64_STANDARD.decode(encoded).map_err(|e| {
+                    eprintln!("--> API_SNAPSHOT: Invalid base64 content for '{}': {}", f.path, e);
+                    (
+                        axum::http::StatusCode::BAD_REQUEST,
+                        Json(json!({ "error": "invalid_content_base64", "path": f.path, "message": e.to_string() })),
+                    )
+                })?),
+                None => None,
+            };
+            if let Some(content) = content.as_ref().filter(|content| content.len() as i64 != f.size) {
+                eprintln!("--> API_SNAPSHOT: Size of '{}' is {} but its content has {} bytes", f.path, f.size, content.len());
+                return Err((
+                    axum::http::StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "error": "content_size_mismatch",
+                        "path": f.path,
+                        "message": "Uploaded content does not match the provided size",
+                        "provided_size": f.size,
+                        "content_size": content.len(),
+                    })),
+                ));
+            }
+            Ok(version_control::ScannedFileInfo { path: f.path, hash: f.hash, size: f.size, content })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Err(mismatches) = version_control::verify_and_normalize_hashes(&mut converted) {
+        eprintln!("--> API_SNAPSHOT: Rejecting snapshot, {} file(s) failed hash verification.", mismatches.len());
+        return Err((
+            axum::http::StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({
+                "error": "content_hash_mismatch",
+                "message": "Uploaded content does not match the provided hash",
+                "mismatches": mismatches
+            })),
+        ));
+    }
+    Ok(converted)
+}
+
 async fn handle_create_initial_snapshot(
     AxumState(state): AxumState<AppState>,
     Json(payload): Json<InitialSnapshotRequest>,
-) -> Result<Json<Value>, axum::http::StatusCode> {
+) -> Result<Json<Value>, Response> {
     println!("--> API_SNAPSHOT: Received request for project: {}", payload.project_root_name);
     println!("--> API_SNAPSHOT: Files to snapshot: {} files", payload.files.len());
 
-    let files_to_snapshot_for_vc_mod: Vec<version_control::ScannedFileInfo> = payload.files.into_iter().map(|f| {
-        version_control::ScannedFileInfo { path: f.path, hash: f.hash, size: f.size }
-    }).collect();
+    let files_to_snapshot_for_vc_mod = to_version_control_files(payload.files).map_err(IntoResponse::into_response)?;
 
-    let mut conn_guard = state.db_pool.lock().await;
-    println!("--> API_SNAPSHOT: Acquired DB lock.");
+    // Loading a project makes it the active one; in per-project mode this selects its own database.
+    let db = state.db.open_project(&payload.project_root_name).await.map_err(|e| {
+        eprintln!("--> API_SNAPSHOT: Could not open database for project '{}': {}", payload.project_root_name, e);
+        axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
 
-    let version_id_result = version_control::create_initial_project_snapshot(
-        &mut conn_guard,
-        &payload.project_root_name,
-        &files_to_snapshot_for_vc_mod,
-    );
+    let project_root_name = payload.project_root_name;
+    let fingerprint = payload.root_fingerprint;
+    let files_count = files_to_snapshot_for_vc_mod.len();
+    let conflict_name = project_root_name.clone();
+    let version_id_result = state
+        .events
+        .write_logged(&db, move |conn| {
+            let project = match &fingerprint {
+                Some(fingerprint) => projects::find_or_create_project(conn, &project_root_name, fingerprint)?,
+                None => match projects::find_or_create_named_project(conn, &project_root_name)? {
+                    Some(project) => project,
+                    None => return Ok(None),
+                },
+            };
+            let version_id = version_control::create_initial_project_snapshot(
+                &mut SqliteVersionStore::new(conn),
+                project.project_id,
+                &project_root_name,
+                &files_to_snapshot_for_vc_mod,
+            )?;
+            Ok(Some((project.project_id, version_id)))
+        })
+        .await;
 
     match version_id_result {
-        Ok(version_id) => {
-            println!("--> API_SNAPSHOT: Successfully created initial snapshot. Version ID: {}", version_id);
-            
-            // Attempt PRAGMAs after successful operation, before lock is released.
-            // These might help ensure data is visible externally, though their effect can vary.
-            // WAL mode needs to be enabled for PRAGMA wal_checkpoint to be most effective.
-            // For default rollback journal, these are less critical but won't hurt.
-            match conn_guard.execute("PRAGMA wal_checkpoint(TRUNCATE);", []) { // TRUNCATE is more aggressive
-                Ok(updated_rows) => println!("--> API_SNAPSHOT: Attempted PRAGMA wal_checkpoint(TRUNCATE); Rows updated (usually 0 unless in WAL and checkpointing): {}", updated_rows),
-                Err(e) => println!("--> API_SNAPSHOT: Error/Info attempting PRAGMA wal_checkpoint(TRUNCATE) (may be harmless if not in WAL mode): {:?}", e),
+        Ok(None) => {
+            eprintln!("--> API_SNAPSHOT: Several projects are named '{}'; a root_fingerprint is required.", conflict_name);
+            Err((
+                axum::http::StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "ambiguous_project_root",
+                    "message": "Other projects with this name have their own root_fingerprint; send one to pick the project",
+                    "project_root_name": conflict_name,
+                })),
+            )
+                .into_response())
+        }
+        Ok(Some((project_id, version_id))) => {
+            println!("--> API_SNAPSHOT: Successfully created initial snapshot. Project ID: {}, Version ID: {}", project_id, version_id);
+            state.events.publish(live_events::LiveEvent::SnapshotCreated {
+                project_id: Some(project_id),
+                version_id,
+                parent_version_id: None,
+                files_count,
+            });
+            Ok(Json(json!({
+                "message": "Initial snapshot created successfully",
+                "project_id": project_id,
+                "version_id": version_id
+            })))
+        }
+        Err(e) => {
+            eprintln!("--> API_SNAPSHOT: Error creating initial snapshot: {:?}", e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+async fn handle_create_snapshot(
+    AxumState(state): AxumState<AppState>,
+    AxumPath(project_id): AxumPath<i64>,
+    Json(payload): Json<CreateSnapshotRequest>,
+) -> Result<Json<Value>, Response> {
+    println!(
+        "--> API_SNAPSHOT: Received snapshot request for project {} with parent version: {}",
+        project_id, payload.parent_version_id
+    );
+    println!("--> API_SNAPSHOT: Files to snapshot: {} files", payload.files.len());
+
+    let parent_version_id = payload.parent_version_id;
+    let description = payload
+        .description
+        .unwrap_or_else(|| format!("Snapshot derived from version {}", parent_version_id));
+    let files_to_snapshot_for_vc_mod = to_version_control_files(payload.files).map_err(IntoResponse::into_response)?;
+
+    let files_count = files_to_snapshot_for_vc_mod.len();
+    let db = match state.db.open_known_project(&payload.project_root_name).await {
+        Ok(Some(db)) => db,
+        Ok(None) => {
+            eprintln!("--> API_SNAPSHOT: No database for project root '{}'.", payload.project_root_name);
+            return Err(axum::http::StatusCode::NOT_FOUND.into_response());
+        }
+        Err(e) => {
+            eprintln!("--> API_SNAPSHOT: Could not open database for project '{}': {}", payload.project_root_name, e);
+            return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    let result = state
+        .events
+        .write_logged(&db, move |conn| {
+            ensure_in_project(conn, project_id, &[parent_version_id])?;
+            let mut store = SqliteVersionStore::new(conn);
+            version_control::create_snapshot(&mut store, parent_version_id, &description, &files_to_snapshot_for_vc_mod)
+        })
+        .await;
+
+    match result {
+        Ok((version_id, counts)) => {
+            println!(
+                "--> API_SNAPSHOT: Created version {} (parent {}): +{} -{} ~{}",
+                version_id, parent_version_id, counts.added, counts.removed, counts.modified
+            );
+            state.events.publish(live_events::LiveEvent::SnapshotCreated {
+                project_id: Some(project_id),
+                version_id,
+                parent_version_id: Some(parent_version_id),
+                files_count,
+            });
+            Ok(Json(json!({
+                "message": "Snapshot created successfully",
+                "project_id": project_id,
+                "version_id": version_id,
+                "parent_version_id": parent_version_id,
+                "added": counts.added,
+                "removed": counts.removed,
+                "modified": counts.modified
+            })))
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            eprintln!("--> API_SNAPSHOT: Parent version {} does not exist in project {}.", parent_version_id, project_id);
+            Err(axum::http::StatusCode::NOT_FOUND.into_response())
+        }
+        Err(e) => {
+            eprintln!("--> API_SNAPSHOT: Error creating snapshot: {:?}", e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+async fn handle_upload_blob(
+    AxumState(state): AxumState<AppState>,
+    body: Bytes,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let size = body.len();
+    let db = state.db.active().await;
+    let result = db.write(move |conn| blob_store::put_blob(conn, &body)).await;
+
+    match result {
+        Ok(content_hash) => Ok(Json(json!({ "content_hash": content_hash, "size": size }))),
+        Err(e) => {
+            eprintln!("--> API_BLOBS: Error storing blob: {:?}", e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_get_blob(
+    AxumState(state): AxumState<AppState>,
+    AxumPath(content_hash): AxumPath<String>,
+) -> Result<impl IntoResponse, axum::http::StatusCode> {
+    let normalized_hash = blob_store::normalize_content_hash(&content_hash);
+    let db = state.db.active().await;
+    let result = db.read(move |conn| SqliteVersionStore::new(conn).get_content(&normalized_hash)).await;
+
+    match result {
+        Ok(Some(content)) => Ok(([(header::CONTENT_TYPE, "application/octet-stream")], content)),
+        Ok(None) => Err(axum::http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("--> API_BLOBS: Error reading blob {}: {:?}", content_hash, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Checks that the project exists and owns every listed version. Anything else is reported as
+/// `QueryReturnedNoRows` (a 404), so versions of other projects are not reachable through a
+/// project's routes.
+fn ensure_in_project(conn: &rusqlite::Connection, project_id: i64, version_ids: &[i64]) -> rusqlite::Result<()> {
+    let in_scope = projects::get_project(conn, project_id)?.is_some()
+        && projects::versions_belong_to_project(conn, project_id, version_ids)?;
+    if in_scope { Ok(()) } else { Err(rusqlite::Error::QueryReturnedNoRows) }
+}
+
+async fn handle_list_projects(
+    AxumState(state): AxumState<AppState>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let db = state.db.active().await;
+    let result = db.read(projects::list_projects).await;
+
+    match result {
+        Ok(projects) => Ok(Json(json!({ "projects": projects }))),
+        Err(e) => {
+            eprintln!("--> API_PROJECTS: Error listing projects: {:?}", e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_create_project(
+    AxumState(state): AxumState<AppState>,
+    Json(payload): Json<CreateProjectRequest>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let fingerprint = payload
+        .root_fingerprint
+        .unwrap_or_else(|| projects::default_fingerprint(&payload.display_name));
+    let db = state.db.active().await;
+    let display_name = payload.display_name;
+    let project_fingerprint = fingerprint.clone();
+    let result = db
+        .write(move |conn| projects::create_project(conn, &display_name, &project_fingerprint))
+        .await;
+
+    match result {
+        Ok(project) => {
+            println!("--> API_PROJECTS: Created project {} '{}'", project.project_id, project.display_name);
+            Ok(Json(json!(project)))
+        }
+        Err(rusqlite::Error::SqliteFailure(err, _)) if err.code == rusqlite::ErrorCode::ConstraintViolation => {
+            eprintln!("--> API_PROJECTS: A project with fingerprint '{}' already exists.", fingerprint);
+            Err(axum::http::StatusCode::CONFLICT)
+        }
+        Err(e) => {
+            eprintln!("--> API_PROJECTS: Error creating project: {:?}", e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_get_project(
+    AxumState(state): AxumState<AppState>,
+    AxumPath(project_id): AxumPath<i64>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let db = state.db.active().await;
+    let result = db.read(move |conn| projects::get_project(conn, project_id)).await;
+
+    match result {
+        Ok(Some(project)) => Ok(Json(json!(project))),
+        Ok(None) => Err(axum::http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("--> API_PROJECTS: Error fetching project {}: {:?}", project_id, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_update_project(
+    AxumState(state): AxumState<AppState>,
+    AxumPath(project_id): AxumPath<i64>,
+    Json(payload): Json<UpdateProjectRequest>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let db = state.db.active().await;
+    let result = db
+        .write(move |conn| {
+            projects::rename_project(conn, project_id, &payload.display_name)?;
+            projects::get_project(conn, project_id)
+        })
+        .await;
+
+    match result {
+        Ok(Some(project)) => Ok(Json(json!(project))),
+        Ok(None) => Err(axum::http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("--> API_PROJECTS: Error updating project {}: {:?}", project_id, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_delete_project(
+    AxumState(state): AxumState<AppState>,
+    AxumPath(project_id): AxumPath<i64>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let db = state.db.active().await;
+    let result = db.write(move |conn| projects::delete_project(conn, project_id)).await;
+
+    match result {
+        Ok(true) => {
+            println!("--> API_PROJECTS: Deleted project {} and its versions", project_id);
+            Ok(Json(json!({ "message": "Project deleted", "project_id": project_id })))
+        }
+        Ok(false) => Err(axum::http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("--> API_PROJECTS: Error deleting project {}: {:?}", project_id, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_list_versions(
+    AxumState(state): AxumState<AppState>,
+    AxumPath(project_id): AxumPath<i64>,
+    Query(params): Query<PaginationParams>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let db = state.db.active().await;
+    let result = db
+        .read(move |conn| {
+            ensure_in_project(conn, project_id, &[])?;
+            let store = SqliteVersionStore::new(conn);
+            let total = store.count_versions(project_id)?;
+            let versions = store.list_versions(project_id, limit, offset)?;
+            Ok((total, versions))
+        })
+        .await;
+
+    match result {
+        Ok((total, versions)) => Ok(Json(json!({
+            "project_id": project_id,
+            "versions": versions,
+            "total": total,
+            "limit": limit,
+            "offset": offset
+        }))),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(axum::http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("--> API_VERSIONS: Error listing versions of project {}: {:?}", project_id, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_get_version(
+    AxumState(state): AxumState<AppState>,
+    AxumPath((project_id, version_id)): AxumPath<(i64, i64)>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let db = state.db.active().await;
+    let result = db
+        .read(move |conn| {
+            ensure_in_project(conn, project_id, &[version_id])?;
+            SqliteVersionStore::new(conn).get_version(version_id)
+        })
+        .await;
+
+    match result {
+        Ok(Some(version)) => Ok(Json(json!(version))),
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => Err(axum::http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("--> API_VERSIONS: Error fetching version {}: {:?}", version_id, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_get_version_files(
+    AxumState(state): AxumState<AppState>,
+    AxumPath((project_id, version_id)): AxumPath<(i64, i64)>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let db = state.db.active().await;
+    let result = db
+        .read(move |conn| {
+            ensure_in_project(conn, project_id, &[version_id])?;
+            SqliteVersionStore::new(conn).get_version_files(version_id)
+        })
+        .await;
+
+    match result {
+        Ok(files) => Ok(Json(json!({ "version_id": version_id, "files": files }))),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(axum::http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("--> API_VERSIONS: Error fetching files for version {}: {:?}", version_id, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_diff_versions(
+    AxumState(state): AxumState<AppState>,
+    AxumPath((project_id, from_version_id, to_version_id)): AxumPath<(i64, i64, i64)>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let db = state.db.active().await;
+    let result = db
+        .read(move |conn| {
+            ensure_in_project(conn, project_id, &[from_version_id, to_version_id])?;
+            version_control::diff_versions(&SqliteVersionStore::new(conn), from_version_id, to_version_id)
+        })
+        .await;
+
+    match result {
+        Ok(diff) => Ok(Json(json!(diff))),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(axum::http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("--> API_VERSIONS: Error diffing versions {} -> {}: {:?}", from_version_id, to_version_id, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_restore_plan(
+    AxumState(state): AxumState<AppState>,
+    AxumPath((project_id, version_id)): AxumPath<(i64, i64)>,
+    Json(payload): Json<RestoreRequest>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let from_version_id = payload.from_version_id;
+    let db = state.db.active().await;
+    let result = db
+        .read(move |conn| {
+            ensure_in_project(conn, project_id, &[from_version_id, version_id])?;
+            version_control::plan_restore(&SqliteVersionStore::new(conn), from_version_id, version_id)
+        })
+        .await;
+
+    match result {
+        Ok(plan) => {
+            println!(
+                "--> API_RESTORE: Planned restore {} -> {}: {} writes, {} deletions, {} new directories",
+                from_version_id, version_id, plan.writes.len(), plan.deletions.len(), plan.directories_to_create.len()
+            );
+            Ok(Json(json!(plan)))
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(axum::http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("--> API_RESTORE: Error planning restore {} -> {}: {:?}", from_version_id, version_id, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_record_restore(
+    AxumState(state): AxumState<AppState>,
+    AxumPath((project_id, version_id)): AxumPath<(i64, i64)>,
+    Json(payload): Json<RestoreRequest>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let from_version_id = payload.from_version_id;
+    let db = state.db.active().await;
+    let result = state
+        .events
+        .write_logged(&db, move |conn| {
+            ensure_in_project(conn, project_id, &[from_version_id, version_id])?;
+            version_control::record_restore(&mut SqliteVersionStore::new(conn), from_version_id, version_id)
+        })
+        .await;
+
+    match result {
+        Ok(new_version_id) => {
+            println!("--> API_RESTORE: Recorded restore to version {} as new version {}", version_id, new_version_id);
+            state.events.publish(live_events::LiveEvent::RestoreRecorded {
+                project_id,
+                version_id: new_version_id,
+                restored_version_id: version_id,
+            });
+            Ok(Json(json!({
+                "message": "Restore recorded successfully",
+                "project_id": project_id,
+                "version_id": new_version_id,
+                "parent_version_id": from_version_id,
+                "restored_version_id": version_id
+            })))
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(axum::http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("--> API_RESTORE: Error recording restore to version {}: {:?}", version_id, e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Parses an RFC 3339 query bound into the UTC form `OperationLog.timestamp` is stored in,
+/// so the bounds compare correctly as strings.
+fn normalize_log_timestamp(value: &str) -> Option<String> {
+    chrono::DateTime::parse_from_rfc3339(value).ok().map(|t| t.with_timezone(&chrono::Utc).to_rfc3339())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogExportFormat {
+    JsonLines,
+    Csv,
+}
+
+/// Lists OperationLog entries oldest first, one page at a time, or streams every matching
+/// entry as JSON Lines / CSV when `format` is given.
+async fn handle_list_log(
+    AxumState(state): AxumState<AppState>,
+    Query(params): Query<LogQueryParams>,
+) -> Result<Response, axum::http::StatusCode> {
+    let filter = log_filter(&params)?;
+    let cursor = params.cursor.unwrap_or(0).max(0);
+    let export_format = match params.format.as_deref() {
+        None | Some("json") => None,
+        Some("jsonl") => Some(LogExportFormat::JsonLines),
+        Some("csv") => Some(LogExportFormat::Csv),
+        Some(other) => {
+            eprintln!("--> API_LOG: Unsupported export format '{}'", other);
+            return Err(axum::http::StatusCode::BAD_REQUEST);
+        }
+    };
+    let db = state.db.active().await;
+
+    if let Some(export_format) = export_format {
+        println!("--> API_LOG: Streaming OperationLog export as {:?}", export_format);
+        return Ok(stream_log_export(db, filter, cursor, export_format));
+    }
+
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+    read_log_page(&db, filter, cursor, limit).await.map(|page| Json(page).into_response())
+}
+
+/// Builds the `LogFilter` for a log query. Unparsable time bounds are a 400.
+fn log_filter(params: &LogQueryParams) -> Result<operation_log::LogFilter, axum::http::StatusCode> {
+    let bound = |value: &Option<String>| match value {
+        Some(value) => normalize_log_timestamp(value).map(Some).ok_or(axum::http::StatusCode::BAD_REQUEST),
+        None => Ok(None),
+    };
+    Ok(operation_log::LogFilter {
+        operation_type: params.operation_type.clone(),
+        linked_project_version_id: params.version_id,
+        target_entity: params.target_entity.clone(),
+        since: bound(&params.since)?,
+        until: bound(&params.until)?,
+    })
+}
+
+/// One page of `GET /api/log`: `{ entries, next_cursor, limit }`.
+async fn read_log_page(
+    db: &db_manage::DbHandle,
+    filter: operation_log::LogFilter,
+    cursor: i64,
+    limit: i64,
+) -> Result<Value, axum::http::StatusCode> {
+    let result = db.read(move |conn| operation_log::read_operations(conn, &filter, cursor, limit)).await;
+
+    match result {
+        Ok(entries) => {
+            let next_cursor = if entries.len() as i64 == limit { entries.last().map(|e| e.log_id) } else { None };
+            Ok(json!({
+                "entries": entries,
+                "next_cursor": next_cursor,
+                "limit": limit
+            }))
+        }
+        Err(e) => {
+            eprintln!("--> API_LOG: Error reading OperationLog: {:?}", e);
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Streams the matching log in batches, so large logs are never held in memory at once.
+/// A database error after the first batch ends the stream with an error, which aborts the
+/// response instead of silently truncating it.
+fn stream_log_export(
+    db: db_manage::DbHandle,
+    filter: operation_log::LogFilter,
+    cursor: i64,
+    export_format: LogExportFormat,
+) -> Response {
+    struct ExportState {
+        db: db_manage::DbHandle,
+        filter: operation_log::LogFilter,
+        cursor: i64,
+        header_pending: bool,
+        done: bool,
+    }
+
+    let initial = ExportState { db, filter, cursor, header_pending: export_format == LogExportFormat::Csv, done: false };
+    let body = futures_util::stream::unfold(initial, move |mut export| async move {
+        if export.done {
+            return None;
+        }
+        let filter = export.filter.clone();
+        let after = export.cursor;
+        let batch = export.db.read(move |conn| operation_log::read_operations(conn, &filter, after, LOG_EXPORT_BATCH)).await;
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                eprintln!("--> API_LOG: Error reading OperationLog during export: {:?}", e);
+                export.done = true;
+                return Some((Err(std::io::Error::other(e)), export));
             }
-            match conn_guard.execute("PRAGMA optimize;", []) {
-                 Ok(updated_rows) => println!("--> API_SNAPSHOT: Attempted PRAGMA optimize; Rows updated (usually 0): {}", updated_rows),
-                Err(e) => println!("--> API_SNAPSHOT: Error attempting PRAGMA optimize: {:?}", e),
+        };
+        export.done = (batch.len() as i64) < LOG_EXPORT_BATCH;
+        if let Some(last) = batch.last() {
+            export.cursor = last.log_id;
+        }
+        let chunk = match export_format {
+            LogExportFormat::JsonLines => batch.iter().try_fold(Vec::new(), |mut out, operation| {
+                operation_log::write_jsonl_record(&mut out, operation)?;
+                Ok(out)
+            }),
+            LogExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                let header_pending = std::mem::take(&mut export.header_pending);
+                let written = (if header_pending { writer.write_record(operation_log::CSV_HEADER) } else { Ok(()) })
+                    .and_then(|_| batch.iter().try_for_each(|operation| operation_log::write_csv_record(&mut writer, operation)));
+                written
+                    .map_err(std::io::Error::other)
+                    .and_then(|_| writer.into_inner().map_err(|e| std::io::Error::other(e.to_string())))
             }
-            println!("--> API_SNAPSHOT: Releasing DB lock.");
-            // conn_guard is dropped here, releasing the lock.
-            
-            Ok(Json(json!({ "message": "Initial snapshot created successfully", "version_id": version_id })))
+        };
+        if chunk.is_err() {
+            export.done = true;
+        }
+        Some((chunk.map(Bytes::from), export))
+    });
+
+    let (content_type, file_name) = match export_format {
+        LogExportFormat::JsonLines => ("application/x-ndjson", "operation_log.jsonl"),
+        LogExportFormat::Csv => ("text/csv; charset=utf-8", "operation_log.csv"),
+    };
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name)),
+        ],
+        axum::body::Body::from_stream(body),
+    )
+        .into_response()
+}
+
+async fn handle_verify_log(
+    AxumState(state): AxumState<AppState>,
+) -> Result<Json<Value>, axum::http::StatusCode> {
+    let db = state.db.active().await;
+    let result = db.read(operation_log::verify_log_chain).await;
+
+    match result {
+        Ok(report) => {
+            match &report.first_broken {
+                Some(broken) => eprintln!("--> API_LOG: OperationLog hash chain is broken at log_id {}", broken.log_id),
+                None => println!("--> API_LOG: OperationLog hash chain intact ({} entries)", report.entries_checked),
+            }
+            Ok(Json(json!(report)))
         }
         Err(e) => {
-            eprintln!("--> API_SNAPSHOT: Error creating initial snapshot: {:?}", e);
-            // Lock is also dropped here if we return early.
+            eprintln!("--> API_LOG: Error verifying OperationLog: {:?}", e);
             Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
 }
 
-async fn websocket_handler( /* ... same as before ... */ ws: WebSocketUpgrade, AxumState(_state): AxumState<AppState>) -> impl IntoResponse {
+async fn websocket_handler(ws: WebSocketUpgrade, AxumState(state): AxumState<AppState>) -> impl IntoResponse {
     println!("--> WS: Upgrade request received.");
-    ws.on_upgrade(handle_socket)
+    // Subscribe before the upgrade so nothing published while it completes is missed.
+    let events = state.events.subscribe();
+    ws.on_upgrade(move |socket| handle_socket(socket, state, events))
 }
-async fn handle_socket(mut socket: WebSocket) { /* ... same as before ... */ 
-    println!("--> WS: Client connected");
-    while let Some(msg) = socket.recv().await {
-        match msg {
-            Ok(Message::Text(t)) => { println!("--> WS: Received text message: {}", t); if socket.send(Message::Text(format!("Echo from backend: {}", t))).await.is_err() { println!("--> WS: Client disconnected (send error)."); break; } }
-            Ok(Message::Binary(b)) => { println!("--> WS: Received binary message: {} bytes", b.len()); if socket.send(Message::Binary(b)).await.is_err() { println!("--> WS: Client disconnected (send error)."); break; } }
-            Ok(Message::Close(_)) => { println!("--> WS: Client sent close message."); break; }
-            Err(e) => { println!("--> WS: Error: {}", e); break; }
-            _ => { println!("--> WS: Received other message type."); }
+
+/// RPC methods available over `/ws`, advertised in the server's `hello`.
+const WS_METHODS: &[&str] = &[
+    "list_databases",
+    "list_projects",
+    "get_project",
+    "list_versions",
+    "get_log",
+    "verify_log",
+    "chat",
+];
+
+/// Runs one connection of the `/ws` protocol (see `ws_protocol`). Requests are executed on their
+/// own tasks and answer through `outbox`, so a slow request never holds up events or other requests.
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: AppState,
+    mut events: tokio::sync::broadcast::Receiver<live_events::LiveEvent>,
+) {
+    use axum::extract::ws::CloseFrame;
+    use tokio::sync::broadcast::error::RecvError;
+    use ws_protocol::{Action, ServerMessage};
+
+    // Each connection is its own LLM session for the token budget.
+    static NEXT_LLM_SESSION: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    let llm_session = format!("ws-{}", NEXT_LLM_SESSION.fetch_add(1, std::sync::atomic::Ordering::Relaxed));
+    println!("--> WS: Client connected (LLM session '{}')", llm_session);
+    let mut session = ws_protocol::Session::new(WS_METHODS);
+    let (outbox_tx, mut outbox) = tokio::sync::mpsc::unbounded_channel::<ServerMessage>();
+    let mut heartbeat = tokio::time::interval(ws_protocol::HEARTBEAT_INTERVAL);
+    heartbeat.tick().await; // The first tick completes immediately.
+    let mut last_seen = tokio::time::Instant::now();
+    let close = |code: u16, reason: &'static str| Message::Close(Some(CloseFrame { code, reason: reason.into() }));
+    // Requests in flight. Whatever is still running when the connection ends is aborted, so a
+    // streamed chat stops pulling tokens nobody will read.
+    let mut requests = tokio::task::JoinSet::new();
+
+    loop {
+        let (outgoing, then_close) = tokio::select! {
+            msg = socket.recv() => {
+                last_seen = tokio::time::Instant::now();
+                match msg {
+                    Some(Ok(Message::Text(text))) => match session.handle_text(&text) {
+                        Action::Reply(reply) => (Message::Text(reply.to_text()), None),
+                        Action::Dispatch { id, method, params } => {
+                            let (state, outbox, llm_session) = (state.clone(), outbox_tx.clone(), llm_session.clone());
+                            requests.spawn(async move {
+                                let reply = match dispatch_ws_request(&state, &id, &method, params, &outbox, &llm_session).await {
+                                    Ok(result) => ServerMessage::Response { id, result },
+                                    Err((code, message)) => ServerMessage::error(Some(id), code, message),
+                                };
+                                let _ = outbox.send(reply);
+                            });
+                            continue;
+                        }
+                        Action::Close { message, code, reason } => (Message::Text(message.to_text()), Some(close(code, reason))),
+                    },
+                    Some(Ok(Message::Binary(_))) => {
+                        let reply = ServerMessage::error(None, ws_protocol::ErrorCode::InvalidMessage, "binary frames are not supported");
+                        (Message::Text(reply.to_text()), None)
+                    }
+                    Some(Ok(Message::Close(frame))) => {
+                        println!("--> WS: Client sent close message: {:?}", frame);
+                        // The close reply is queued by the WebSocket itself; flushing sends it.
+                        let _ = futures_util::SinkExt::flush(&mut socket).await;
+                        break;
+                    }
+                    Some(Ok(_)) => continue, // Pings are answered by axum; pongs only refresh `last_seen`.
+                    Some(Err(e)) => { println!("--> WS: Error: {}", e); break; }
+                    None => break,
+                }
+            },
+            Some(reply) = outbox.recv() => (Message::Text(reply.to_text()), None),
+            Some(_) = requests.join_next() => continue,
+            event = events.recv() => match event {
+                Ok(event) => match session.event_frame(event) {
+                    Some(frame) => (Message::Text(frame.to_text()), None),
+                    None => continue,
+                },
+                Err(RecvError::Lagged(skipped)) if session.has_subscriptions() => {
+                    println!("--> WS: Client lagged behind, {} event(s) dropped.", skipped);
+                    (Message::Text(ServerMessage::Lagged { skipped }.to_text()), None)
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => (close(ws_protocol::CLOSE_GOING_AWAY, "server shutting down"), None),
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > ws_protocol::HEARTBEAT_TIMEOUT {
+                    println!("--> WS: No heartbeat from client, closing.");
+                    (close(ws_protocol::CLOSE_GOING_AWAY, "heartbeat timeout"), None)
+                } else {
+                    (Message::Ping(Vec::new()), None)
+                }
+            },
+        };
+
+        let closing = matches!(outgoing, Message::Close(_));
+        if socket.send(outgoing).await.is_err() {
+            println!("--> WS: Client disconnected (send error).");
+            break;
+        }
+        if let Some(frame) = then_close {
+            let _ = socket.send(frame).await;
+            break;
+        }
+        if closing {
+            break;
         }
     }
+    if !requests.is_empty() {
+        println!("--> WS: Aborting {} request(s) still in flight.", requests.len());
+        requests.abort_all();
+    }
     println!("--> WS: Connection closed.");
-}
\ No newline at end of file
+}
+
+/// Params of the `chat` method: a proxy request plus the project it is accounted to, and
+/// whether to skip the response cache lookup.
+#[derive(Debug, serde::Deserialize)]
+struct WsChatParams {
+    #[serde(flatten)]
+    request: llm_provider::ChatRequest,
+    project_id: Option<i64>,
+    #[serde(default)]
+    bypass_cache: bool,
+}
+
+/// Project id plus the usual paging parameters, for RPC methods scoped to a project.
+#[derive(Debug, serde::Deserialize)]
+struct WsProjectParams {
+    project_id: i64,
+    #[serde(flatten)]
+    page: PaginationParams,
+}
+
+/// Runs one `/ws` request. Methods reuse the HTTP handlers, so both APIs answer identically;
+/// an HTTP error status becomes the matching error code.
+async fn dispatch_ws_request(
+    state: &AppState,
+    id: &str,
+    method: &str,
+    params: Value,
+    outbox: &tokio::sync::mpsc::UnboundedSender<ws_protocol::ServerMessage>,
+    llm_session: &str,
+) -> Result<Value, (ws_protocol::ErrorCode, String)> {
+    use ws_protocol::ErrorCode;
+
+    fn parse<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, (ErrorCode, String)> {
+        let params = if params.is_null() { json!({}) } else { params };
+        serde_json::from_value(params).map_err(|e| (ErrorCode::InvalidParams, e.to_string()))
+    }
+    let from_status = |status: axum::http::StatusCode| {
+        (ErrorCode::from_status(status), status.canonical_reason().unwrap_or("request failed").to_string())
+    };
+    let state = AxumState(state.clone());
+
+    println!("--> WS: Request {} '{}'", id, method);
+    let result = match method {
+        "list_databases" => handle_list_databases(state).await.map(|Json(v)| v),
+        "list_projects" => handle_list_projects(state).await.map(|Json(v)| v),
+        "get_project" => {
+            let params: WsProjectParams = parse(params)?;
+            handle_get_project(state, AxumPath(params.project_id)).await.map(|Json(v)| v)
+        }
+        "list_versions" => {
+            let params: WsProjectParams = parse(params)?;
+            handle_list_versions(state, AxumPath(params.project_id), Query(params.page)).await.map(|Json(v)| v)
+        }
+        "get_log" => {
+            let params: LogQueryParams = parse(params)?;
+            if params.format.is_some() {
+                return Err((ErrorCode::InvalidParams, "exports are only available over HTTP".to_string()));
+            }
+            let filter = log_filter(&params).map_err(from_status)?;
+            let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+            let db = state.db.active().await;
+            read_log_page(&db, filter, params.cursor.unwrap_or(0).max(0), limit).await
+        }
+        "verify_log" => {
+            let (progress, request_id) = (outbox.clone(), id.to_string());
+            let db = state.db.active().await;
+            let result = db
+                .read(move |conn| {
+                    operation_log::verify_log_chain_with_progress(conn, |completed, total| {
+                        let _ = progress.send(ws_protocol::ServerMessage::Progress { id: request_id.clone(), completed, total });
+                    })
+                })
+                .await;
+            result.map(|report| json!(report)).map_err(|e| {
+                eprintln!("--> WS: Error verifying OperationLog: {:?}", e);
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR
+            })
+        }
+        "chat" => {
+            let WsChatParams { request, project_id, bypass_cache } = parse(params)?;
+            let llm_error = |e: llm_provider::LlmError| {
+                eprintln!("--> WS: LLM request {} failed: {}", id, e);
+                (ErrorCode::from_status(e.status_code()), e.to_string())
+            };
+            let provider = state.llm.get(request.provider.as_deref()).map_err(llm_error)?;
+            check_llm_project(&state, project_id).await.map_err(|refused| {
+                eprintln!("--> WS: LLM request {} refused: {}", id, refused);
+                refused.ws_error()
+            })?;
+            let mut stream = if let CacheLookup::Hit(response) = cached_reply(&state, provider, &request, bypass_cache).await {
+                if !request.stream {
+                    return Ok(json!(response));
+                }
+                llm_stream::replay(response)
+            } else {
+                let context = admit_prompt(&state, provider, &request, Some(llm_session.to_string()), project_id)
+                    .await
+                    .map_err(|refused| {
+                        eprintln!("--> WS: LLM request {} refused: {}", id, refused);
+                        refused.ws_error()
+                    })?;
+                if !request.stream {
+                    let response = audited_chat(&state, provider, &request, context).await.map_err(llm_error)?;
+                    return Ok(json!(response));
+                }
+                audited_chat_stream(&state, provider, &request, context).await.map_err(llm_error)?
+            };
+            while let Some(event) = futures_util::StreamExt::next(&mut stream).await {
+                match event.map_err(llm_error)? {
+                    llm_stream::ChatStreamEvent::Delta { content } => {
+                        // The connection is gone. Dropping the stream records the call as cancelled.
+                        if outbox.send(ws_protocol::ServerMessage::Delta { id: id.to_string(), content }).is_err() {
+                            return Err((ErrorCode::Internal, "client disconnected".to_string()));
+                        }
+                    }
+                    llm_stream::ChatStreamEvent::Done { response } => return Ok(json!(response)),
+                }
+            }
+            return Err((ErrorCode::Upstream, "LLM stream ended without a final response".to_string()));
+        }
+        other => return Err((ErrorCode::UnknownMethod, format!("unknown method '{}'", other))),
+    };
+    result.map_err(from_status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Serves the API on an ephemeral port against a fresh database, with the built-in `mock`
+    /// provider as the default. `args` are appended to the command line.
+    async fn serve(name: &str, args: &[&str]) -> String {
+        let dir = std::env::temp_dir().join(format!("diranalyze_api_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (db_path, projects_dir) = (dir.join("test.sqlite3"), dir.join("projects"));
+        let defaults = [
+            "backend",
+            "--db-path",
+            db_path.to_str().unwrap(),
+            "--projects-dir",
+            projects_dir.to_str().unwrap(),
+            "--llm-provider",
+            "mock",
+            "--llm-store-bodies",
+            "true",
+            "--llm-max-attempts",
+            "1",
+        ];
+        let cli = config::CliArgs::try_parse_from(defaults.iter().chain(args)).unwrap();
+        let app_config = config::load_from(cli).unwrap();
+        let db = Arc::new(db_manage::DbRegistry::new(&app_config.database).unwrap());
+        let app = build_router(AppState::new(&app_config, db), dir);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    #[test]
+    fn test_uploaded_content_must_match_its_size() {
+        let file = |size| ScannedFileInfo {
+            path: "a.txt".to_string(),
+            hash: String::new(),
+            size,
+            content_base64: Some(BASE64_STANDARD.encode("hello")),
+        };
+        let converted = to_version_control_files(vec![file(5)]).unwrap();
+        assert_eq!(converted[0].content.as_deref(), Some(&b"hello"[..]));
+        let (status, Json(body)) = to_version_control_files(vec![file(6)]).unwrap_err();
+        assert_eq!(status, axum::http::StatusCode::BAD_REQUEST);
+        assert_eq!((body["error"].as_str(), body["content_size"].as_u64()), (Some("content_size_mismatch"), Some(5)));
+    }
+
+    #[tokio::test]
+    async fn test_roots_without_fingerprints_keep_their_project_across_changes() {
+        let url = serve("named_roots", &[]).await;
+        let client = reqwest::Client::new();
+        let snapshot = |name: &str, fingerprint: Option<&str>, hash: &str| {
+            let mut body = json!({ "project_root_name": name, "files": [{ "path": "main.rs", "hash": hash, "size": 1 }] });
+            if let Some(fingerprint) = fingerprint {
+                body["root_fingerprint"] = json!(fingerprint);
+            }
+            client.post(format!("{}/api/snapshot/initial", url)).json(&body).send()
+        };
+        let project = |response: reqwest::Response| async move { response.json::<Value>().await.unwrap()["project_id"].clone() };
+
+        let src = project(snapshot("src", None, "aa").await.unwrap()).await;
+        assert_eq!(project(snapshot("src", None, "bb").await.unwrap()).await, src);
+
+        // Same-named roots with their own fingerprints are separate, and a bare name is ambiguous.
+        let work = project(snapshot("app", Some("fp-work"), "aa").await.unwrap()).await;
+        let home = project(snapshot("app", Some("fp-home"), "aa").await.unwrap()).await;
+        assert_ne!(work, home);
+        let ambiguous = snapshot("app", None, "aa").await.unwrap();
+        assert_eq!(ambiguous.status(), reqwest::StatusCode::CONFLICT);
+        assert_eq!(ambiguous.json::<Value>().await.unwrap()["error"], "ambiguous_project_root");
+    }
+
+    #[tokio::test]
+    async fn test_child_snapshots_go_to_their_projects_database() {
+        let url = serve("child_snapshots", &["--db-mode", "per-project"]).await;
+        let client = reqwest::Client::new();
+        let files = |hash: &str| json!([{ "path": "main.rs", "hash": hash, "size": 1 }]);
+        for root in ["alpha", "beta"] {
+            let body = json!({ "project_root_name": root, "files": files("aa") });
+            let response = client.post(format!("{}/api/snapshot/initial", url)).json(&body).send().await.unwrap();
+            let created: Value = response.json().await.unwrap();
+            assert_eq!((created["project_id"].as_i64(), created["version_id"].as_i64()), (Some(1), Some(1)));
+        }
+        let child = |root: &str, project_id: i64| {
+            let body = json!({ "project_root_name": root, "parent_version_id": 1, "files": files("bb") });
+            client.post(format!("{}/api/projects/{}/snapshots", url, project_id)).json(&body).send()
+        };
+
+        // Beta is active, but the snapshot names alpha and lands in alpha's database.
+        assert!(child("alpha", 1).await.unwrap().status().is_success());
+        let versions = |project_id: i64| client.get(format!("{}/api/projects/{}/versions", url, project_id)).send();
+        let alpha: Value = versions(1).await.unwrap().json().await.unwrap();
+        assert_eq!(alpha["total"], 2);
+        client.post(format!("{}/api/databases/open", url)).json(&json!({ "project_root_name": "beta" })).send().await.unwrap();
+        let beta: Value = versions(1).await.unwrap().json().await.unwrap();
+        assert_eq!(beta["total"], 1);
+
+        assert_eq!(child("gamma", 1).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(child("beta", 2).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_llm_proxy_answers_offline_from_the_mock_provider() {
+        let url = serve("mock_proxy", &[]).await;
+        let client = reqwest::Client::new();
+        let proxy = |request: llm_provider::ChatRequest| client.post(format!("{}/api/llm_proxy", url)).json(&request).send();
+
+        let capca = llm_provider::ChatRequest::user("Write a CAPCA patch that adds release notes");
+        let reply: llm_provider::ChatResponse = proxy(capca.clone()).await.unwrap().json().await.unwrap();
+        assert_eq!((reply.provider.as_str(), reply.model.as_str()), ("mock", config::DEFAULT_MOCK_MODEL));
+        let patch: Value = serde_json::from_str(&reply.content).unwrap();
+        assert_eq!(patch[0]["file"], "DEMO_NOTES.md");
+
+        let hello = llm_provider::ChatRequest { stream: true, ..llm_provider::ChatRequest::user("hello there") };
+        let streamed = proxy(hello).await.unwrap().text().await.unwrap();
+        assert!(streamed.contains("event: delta") && streamed.contains("event: done"), "{}", streamed);
+
+        let limited = proxy(llm_provider::ChatRequest::user("mock: rate limit this one")).await.unwrap();
+        assert_eq!(limited.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.headers()["retry-after"], "1");
+        assert_eq!(limited.json::<Value>().await.unwrap()["error"], "upstream_rate_limited");
+
+        let log: Value = client.get(format!("{}/api/log?operation_type=LLM_CALL", url)).send().await.unwrap().json().await.unwrap();
+        let statuses: Vec<&Value> = log["entries"].as_array().unwrap().iter().map(|entry| &entry["event"]["status_code"]).collect();
+        assert!(statuses.contains(&&json!(429)), "{}", log);
+
+        // The completed call comes back as a fixture that replays it exactly.
+        let recordings: llm_mock::FixtureFile =
+            client.get(format!("{}/api/llm/recordings", url)).send().await.unwrap().json().await.unwrap();
+        let recorded = &recordings.fixtures[0];
+        assert_eq!(recorded.request_hash, Some(llm_mock::request_hash(&capca)));
+        assert_eq!(recorded.response.as_ref().unwrap().content, reply.content);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_project_is_refused_on_cache_hits_too() {
+        let url = serve("unknown_project", &["--llm-cache", "true"]).await;
+        let client = reqwest::Client::new();
+        let proxy = |project: Option<&str>| {
+            let request = client.post(format!("{}/api/llm_proxy", url)).json(&llm_provider::ChatRequest::user("cache me"));
+            match project {
+                Some(project) => request.header(LLM_PROJECT_HEADER, project),
+                None => request,
+            }
+            .send()
+        };
+
+        assert_eq!(proxy(Some("999")).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(proxy(None).await.unwrap().headers()[LLM_CACHE_HEADER], "miss");
+        assert_eq!(proxy(None).await.unwrap().headers()[LLM_CACHE_HEADER], "hit");
+        assert_eq!(proxy(Some("999")).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,825 @@
+// diranalyze/backend/src/operation_log.rs
+
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use std::fmt;
+use crate::blob_store;
+
+/// `prev_hash` of the first entry in a log.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Outcome of scanning outgoing content for secrets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretGateOutcome {
+    /// Nothing was found; the content went out unchanged.
+    Allowed,
+    /// Findings were masked before the content went out.
+    Redacted,
+    /// The content was withheld.
+    Blocked,
+}
+
+/// Everything that can be recorded in `OperationLog`. The variant name becomes the
+/// `operation_type` column; the fields are stored as `details_json`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "operation_type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OperationEvent {
+    /// The first version of a project was recorded.
+    ProjectSnapshotInitial {
+        project_name: String,
+        files_count: usize,
+        total_size: i64,
+    },
+    /// A version was derived from an existing one, usually after patches were applied.
+    ProjectSnapshotPatch {
+        parent_version_id: i64,
+        description: String,
+        files_count: usize,
+        total_size: i64,
+        added: usize,
+        removed: usize,
+        modified: usize,
+    },
+    /// A working tree was restored to an earlier version.
+    ProjectRestore {
+        from_version_id: i64,
+        restored_version_id: i64,
+        files_written: usize,
+        files_deleted: usize,
+    },
+    /// A request was sent to an LLM provider through the proxy.
+    LlmCall {
+        provider: String,
+        model: String,
+        /// SHA-256 of the canonical (provider-neutral) request.
+        request_sha256: String,
+        /// SHA-256 of the canonical response, if one was received.
+        #[serde(default)]
+        response_sha256: Option<String>,
+        /// HTTP status returned upstream, if the request got that far.
+        #[serde(default)]
+        status_code: Option<u16>,
+        latency_ms: u64,
+        #[serde(default)]
+        error: Option<String>,
+        #[serde(default)]
+        input_tokens: Option<u64>,
+        #[serde(default)]
+        output_tokens: Option<u64>,
+        /// Whether the reply was relayed as a stream.
+        #[serde(default)]
+        streamed: bool,
+        /// Whether the canonical bodies were kept in the blob store under their hashes.
+        #[serde(default)]
+        bodies_stored: bool,
+        /// Whether the reply came from the response cache instead of the provider.
+        #[serde(default)]
+        cached: bool,
+    },
+    /// A patch changed a single file. Hashes are `None` where the file did not exist.
+    PatchApplied {
+        file_path: String,
+        #[serde(default)]
+        hash_before: Option<String>,
+        #[serde(default)]
+        hash_after: Option<String>,
+        hunks_applied: usize,
+    },
+    /// Outgoing content was checked for secrets.
+    SecretGateDecision {
+        /// What was scanned, e.g. `llm_request` or a file path.
+        target: String,
+        decision: SecretGateOutcome,
+        findings: usize,
+        /// Names of the detectors that matched.
+        #[serde(default)]
+        detectors: Vec<String>,
+    },
+}
+
+/// Why an event was refused by `log_operation`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidOperationEvent(pub String);
+
+impl fmt::Display for InvalidOperationEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid operation event: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidOperationEvent {}
+
+fn is_sha256_hex(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+impl OperationEvent {
+    /// The `operation_type` column value, e.g. `PROJECT_SNAPSHOT_INITIAL`.
+    pub fn operation_type(&self) -> &'static str {
+        match self {
+            OperationEvent::ProjectSnapshotInitial { .. } => "PROJECT_SNAPSHOT_INITIAL",
+            OperationEvent::ProjectSnapshotPatch { .. } => "PROJECT_SNAPSHOT_PATCH",
+            OperationEvent::ProjectRestore { .. } => "PROJECT_RESTORE",
+            OperationEvent::LlmCall { .. } => "LLM_CALL",
+            OperationEvent::PatchApplied { .. } => "PATCH_APPLIED",
+            OperationEvent::SecretGateDecision { .. } => "SECRET_GATE_DECISION",
+        }
+    }
+
+    /// The `target_entity` column value: what the operation acted on.
+    pub fn target_entity(&self) -> String {
+        match self {
+            OperationEvent::ProjectSnapshotInitial { project_name, .. } => project_name.clone(),
+            OperationEvent::ProjectSnapshotPatch { description, .. } => description.clone(),
+            OperationEvent::ProjectRestore { restored_version_id, .. } => format!("Restored to version {}", restored_version_id),
+            OperationEvent::LlmCall { provider, model, .. } => format!("{}/{}", provider, model),
+            OperationEvent::PatchApplied { file_path, .. } => file_path.clone(),
+            OperationEvent::SecretGateDecision { target, .. } => target.clone(),
+        }
+    }
+
+    /// The `content_hash_before`/`content_hash_after` column values.
+    fn content_hashes(&self) -> (Option<&str>, Option<&str>) {
+        match self {
+            OperationEvent::LlmCall { request_sha256, response_sha256, .. } => {
+                (Some(request_sha256.as_str()), response_sha256.as_deref())
+            }
+            OperationEvent::PatchApplied { hash_before, hash_after, .. } => (hash_before.as_deref(), hash_after.as_deref()),
+            _ => (None, None),
+        }
+    }
+
+    /// Checks the invariants the rest of the log relies on: names are non-empty, hashes are
+    /// lowercase SHA-256 hex, and counts agree with the outcome they describe.
+    pub fn validate(&self) -> std::result::Result<(), InvalidOperationEvent> {
+        let require = |ok: bool, message: &str| if ok { Ok(()) } else { Err(InvalidOperationEvent(message.to_string())) };
+        let (hash_before, hash_after) = self.content_hashes();
+        for hash in [hash_before, hash_after].into_iter().flatten() {
+            require(is_sha256_hex(hash), &format!("'{}' is not a lowercase SHA-256 hex digest", hash))?;
+        }
+        match self {
+            OperationEvent::ProjectSnapshotInitial { project_name, .. } => require(!project_name.is_empty(), "project_name is empty"),
+            OperationEvent::ProjectSnapshotPatch { files_count, added, .. } => {
+                require(added <= files_count, "more files added than the snapshot contains")
+            }
+            OperationEvent::ProjectRestore { .. } => Ok(()),
+            OperationEvent::LlmCall { provider, model, .. } => {
+                require(!provider.is_empty(), "provider is empty")?;
+                require(!model.is_empty(), "model is empty")
+            }
+            OperationEvent::PatchApplied { file_path, hash_before, hash_after, .. } => {
+                require(!file_path.is_empty(), "file_path is empty")?;
+                require(hash_before.is_some() || hash_after.is_some(), "a patch must have a before or after state")
+            }
+            OperationEvent::SecretGateDecision { target, decision, findings, .. } => {
+                require(!target.is_empty(), "target is empty")?;
+                match decision {
+                    SecretGateOutcome::Allowed => require(*findings == 0, "an allowed decision cannot have findings"),
+                    SecretGateOutcome::Redacted | SecretGateOutcome::Blocked => {
+                        require(*findings > 0, "a redacted or blocked decision needs at least one finding")
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An operation to append to `OperationLog`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct OperationLogEntry {
+    pub linked_project_version_id: Option<i64>,
+    pub timestamp: String,
+    pub event: OperationEvent,
+}
+
+/// The event of an `OperationLog` row as read back. Rows written by older builds or by hand
+/// may not fit `OperationEvent`; they come back as stored instead of failing the whole read.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(untagged)]
+pub enum LoggedEvent {
+    Typed(OperationEvent),
+    Untyped { operation_type: String, details_json: Option<String> },
+}
+
+impl LoggedEvent {
+    pub fn typed(&self) -> Option<&OperationEvent> {
+        match self {
+            LoggedEvent::Typed(event) => Some(event),
+            LoggedEvent::Untyped { .. } => None,
+        }
+    }
+}
+
+/// An `OperationLog` row read back by `read_operations`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct LoggedOperation {
+    pub log_id: i64,
+    pub linked_project_version_id: Option<i64>,
+    pub timestamp: String,
+    pub event: LoggedEvent,
+    /// `None` only for rows edited by hand; `verify_log_chain` reports them as broken.
+    pub prev_hash: Option<String>,
+    pub entry_hash: Option<String>,
+    /// The row's columns exactly as stored, for exports that must reproduce `entry_hash`.
+    #[serde(skip)]
+    stored: ChainedRow,
+}
+
+/// The stored columns of an `OperationLog` row that the hash chain covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChainedRow {
+    linked_project_version_id: Option<i64>,
+    timestamp: String,
+    operation_type: String,
+    target_entity: Option<String>,
+    content_hash_before: Option<String>,
+    content_hash_after: Option<String>,
+    details_json: Option<String>,
+}
+
+impl ChainedRow {
+    const COLUMNS: &'static str = "linked_project_version_id, timestamp, operation_type, target_entity,
+                                   content_hash_before, content_hash_after, details_json";
+
+    /// Reads the row from `COLUMNS` selected starting at column `first`.
+    fn from_row(row: &rusqlite::Row, first: usize) -> Result<Self> {
+        Ok(ChainedRow {
+            linked_project_version_id: row.get(first)?,
+            timestamp: row.get(first + 1)?,
+            operation_type: row.get(first + 2)?,
+            target_entity: row.get(first + 3)?,
+            content_hash_before: row.get(first + 4)?,
+            content_hash_after: row.get(first + 5)?,
+            details_json: row.get(first + 6)?,
+        })
+    }
+
+    /// SHA-256 over the canonical JSON of the row and `prev_hash`: a compact object with the
+    /// keys in a fixed order and `details_json` kept as the exact stored string.
+    fn entry_hash(&self, prev_hash: &str) -> String {
+        let canonical = serde_json::json!({
+            "content_hash_after": self.content_hash_after,
+            "content_hash_before": self.content_hash_before,
+            "details_json": self.details_json,
+            "linked_project_version_id": self.linked_project_version_id,
+            "operation_type": self.operation_type,
+            "prev_hash": prev_hash,
+            "target_entity": self.target_entity,
+            "timestamp": self.timestamp,
+        });
+        blob_store::sha256_hex(canonical.to_string().as_bytes())
+    }
+}
+
+/// The `entry_hash` of the newest row, or `GENESIS_HASH` for an empty log.
+fn chain_head(conn: &Connection) -> Result<String> {
+    let head: Option<Option<String>> = conn
+        .query_row("SELECT entry_hash FROM OperationLog ORDER BY log_id DESC LIMIT 1", [], |row| row.get(0))
+        .optional()?;
+    Ok(head.flatten().unwrap_or_else(|| GENESIS_HASH.to_string()))
+}
+
+/// Validates `entry` and appends it to `OperationLog`, chained to the previous row. This is the
+/// only place rows are written, so `operation_type`, `target_entity` and the hash columns always
+/// agree with `details_json`. Callers must not append concurrently on separate connections,
+/// which the single writer connection per database guarantees.
+///
+/// # Returns
+/// The new `log_id`. An invalid event is rejected with `rusqlite::Error::ToSqlConversionFailure`
+/// wrapping an `InvalidOperationEvent`.
+pub fn log_operation(conn: &Connection, entry: &OperationLogEntry) -> Result<i64> {
+    entry.event.validate().map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+    let mut details = serde_json::to_value(&entry.event).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    if let Some(fields) = details.as_object_mut() {
+        fields.remove("operation_type");
+    }
+    let (hash_before, hash_after) = entry.event.content_hashes();
+    let row = ChainedRow {
+        linked_project_version_id: entry.linked_project_version_id,
+        timestamp: entry.timestamp.clone(),
+        operation_type: entry.event.operation_type().to_string(),
+        target_entity: Some(entry.event.target_entity()),
+        content_hash_before: hash_before.map(str::to_string),
+        content_hash_after: hash_after.map(str::to_string),
+        details_json: Some(details.to_string()),
+    };
+    let prev_hash = chain_head(conn)?;
+    let entry_hash = row.entry_hash(&prev_hash);
+    conn.execute(
+        &format!("INSERT INTO OperationLog ({}, prev_hash, entry_hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)", ChainedRow::COLUMNS),
+        params![
+            row.linked_project_version_id,
+            row.timestamp,
+            row.operation_type,
+            row.target_entity,
+            row.content_hash_before,
+            row.content_hash_after,
+            row.details_json,
+            prev_hash,
+            entry_hash
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Chains every row that has no `entry_hash` yet, in `log_id` order. Used by the migration that
+/// introduced the chain, so rows written before it are covered too.
+pub fn backfill_chain(conn: &Connection) -> Result<()> {
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let rows: Vec<(i64, Option<String>, ChainedRow)> = {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT log_id, entry_hash, {} FROM OperationLog ORDER BY log_id ASC",
+            ChainedRow::COLUMNS
+        ))?;
+        let mapped = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, ChainedRow::from_row(row, 2)?)))?;
+        mapped.collect::<Result<_>>()?
+    };
+    let mut update = conn.prepare("UPDATE OperationLog SET prev_hash = ?1, entry_hash = ?2 WHERE log_id = ?3")?;
+    for (log_id, existing_hash, row) in rows {
+        prev_hash = match existing_hash {
+            Some(hash) => hash,
+            None => {
+                let entry_hash = row.entry_hash(&prev_hash);
+                update.execute(params![prev_hash, entry_hash, log_id])?;
+                entry_hash
+            }
+        };
+    }
+    Ok(())
+}
+
+/// Why the chain is broken at a given row.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChainBreak {
+    /// `prev_hash` does not name the row before it: a row was removed, inserted or reordered.
+    PrevHashMismatch { expected: String, found: Option<String> },
+    /// The row's contents no longer hash to its `entry_hash`: the row was edited.
+    EntryHashMismatch { expected: String, found: Option<String> },
+}
+
+/// The first row at which `verify_log_chain` found the chain broken.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct BrokenLink {
+    pub log_id: i64,
+    #[serde(flatten)]
+    pub reason: ChainBreak,
+}
+
+/// Result of walking the whole `OperationLog` chain.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct LogChainReport {
+    pub intact: bool,
+    /// Rows checked before stopping; all rows when the chain is intact.
+    pub entries_checked: i64,
+    /// `entry_hash` of the last intact row. Publishing it lets a reviewer detect later removal
+    /// of rows from the end of the log, which the chain alone cannot reveal.
+    pub head_hash: String,
+    pub first_broken: Option<BrokenLink>,
+}
+
+/// Recomputes every row's hash in `log_id` order and stops at the first broken link.
+pub fn verify_log_chain(conn: &Connection) -> Result<LogChainReport> {
+    verify_log_chain_with_progress(conn, |_, _| {})
+}
+
+/// Entries verified between two calls of the progress callback.
+pub const VERIFY_PROGRESS_INTERVAL: i64 = 1000;
+
+/// Same as `verify_log_chain`, calling `on_progress(entries_checked, total_entries)` every
+/// `VERIFY_PROGRESS_INTERVAL` entries.
+pub fn verify_log_chain_with_progress<F: FnMut(i64, i64)>(conn: &Connection, mut on_progress: F) -> Result<LogChainReport> {
+    let total: i64 = conn.query_row("SELECT COUNT(*) FROM OperationLog", [], |row| row.get(0))?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT log_id, prev_hash, entry_hash, {} FROM OperationLog ORDER BY log_id ASC",
+        ChainedRow::COLUMNS
+    ))?;
+    let mut rows = stmt.query([])?;
+
+    let mut expected_prev = GENESIS_HASH.to_string();
+    let mut entries_checked = 0;
+    while let Some(row) = rows.next()? {
+        let log_id: i64 = row.get(0)?;
+        let prev_hash: Option<String> = row.get(1)?;
+        let entry_hash: Option<String> = row.get(2)?;
+        let computed = ChainedRow::from_row(row, 3)?.entry_hash(&expected_prev);
+
+        let reason = if prev_hash.as_deref() != Some(expected_prev.as_str()) {
+            Some(ChainBreak::PrevHashMismatch { expected: expected_prev.clone(), found: prev_hash })
+        } else if entry_hash.as_deref() != Some(computed.as_str()) {
+            Some(ChainBreak::EntryHashMismatch { expected: computed.clone(), found: entry_hash })
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Ok(LogChainReport {
+                intact: false,
+                entries_checked,
+                head_hash: expected_prev,
+                first_broken: Some(BrokenLink { log_id, reason }),
+            });
+        }
+        expected_prev = computed;
+        entries_checked += 1;
+        if entries_checked % VERIFY_PROGRESS_INTERVAL == 0 {
+            on_progress(entries_checked, total);
+        }
+    }
+    Ok(LogChainReport { intact: true, entries_checked, head_hash: expected_prev, first_broken: None })
+}
+
+/// Reads a row selected by `read_operations`. Its event is typed when the stored
+/// `operation_type` and `details_json` fit an `OperationEvent`, and kept as stored otherwise.
+fn logged_operation_from_row(row: &rusqlite::Row) -> Result<LoggedOperation> {
+    let stored = ChainedRow::from_row(row, 3)?;
+    let details = match stored.details_json.as_deref() {
+        Some(text) => serde_json::from_str(text).ok(),
+        None => Some(serde_json::json!({})),
+    };
+    let typed = details.and_then(|mut details: serde_json::Value| {
+        let fields = details.as_object_mut()?;
+        fields.insert("operation_type".to_string(), serde_json::Value::String(stored.operation_type.clone()));
+        serde_json::from_value(details).ok()
+    });
+    let event = match typed {
+        Some(event) => LoggedEvent::Typed(event),
+        None => LoggedEvent::Untyped { operation_type: stored.operation_type.clone(), details_json: stored.details_json.clone() },
+    };
+    Ok(LoggedOperation {
+        log_id: row.get(0)?,
+        linked_project_version_id: stored.linked_project_version_id,
+        timestamp: stored.timestamp.clone(),
+        event,
+        prev_hash: row.get(1)?,
+        entry_hash: row.get(2)?,
+        stored,
+    })
+}
+
+/// Restricts which rows `read_operations` returns. Every field that is set must match.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogFilter {
+    pub operation_type: Option<String>,
+    pub linked_project_version_id: Option<i64>,
+    pub target_entity: Option<String>,
+    /// Inclusive lower bound, an RFC 3339 timestamp in UTC as written by the backend.
+    pub since: Option<String>,
+    /// Exclusive upper bound, same format as `since`.
+    pub until: Option<String>,
+}
+
+impl LogFilter {
+    fn where_clause(&self, after_log_id: i64) -> (String, Vec<rusqlite::types::Value>) {
+        use rusqlite::types::Value;
+        let mut conditions = vec!["log_id > ?".to_string()];
+        let mut values = vec![Value::Integer(after_log_id)];
+        let mut push = |condition: &str, value: Value| {
+            conditions.push(condition.to_string());
+            values.push(value);
+        };
+        if let Some(operation_type) = &self.operation_type {
+            push("operation_type = ?", Value::Text(operation_type.clone()));
+        }
+        if let Some(version_id) = self.linked_project_version_id {
+            push("linked_project_version_id = ?", Value::Integer(version_id));
+        }
+        if let Some(target_entity) = &self.target_entity {
+            push("target_entity = ?", Value::Text(target_entity.clone()));
+        }
+        if let Some(since) = &self.since {
+            push("timestamp >= ?", Value::Text(since.clone()));
+        }
+        if let Some(until) = &self.until {
+            push("timestamp < ?", Value::Text(until.clone()));
+        }
+        (conditions.join(" AND "), values)
+    }
+}
+
+/// Reads up to `limit` operations matching `filter` with a `log_id` greater than `after_log_id`,
+/// oldest first. Passing the last `log_id` of one page as `after_log_id` yields the next page.
+/// Rows whose details do not match their `operation_type` come back as `LoggedEvent::Untyped`.
+pub fn read_operations(conn: &Connection, filter: &LogFilter, after_log_id: i64, limit: i64) -> Result<Vec<LoggedOperation>> {
+    let (where_clause, mut values) = filter.where_clause(after_log_id);
+    values.push(rusqlite::types::Value::Integer(limit));
+    let mut stmt = conn.prepare(&format!(
+        "SELECT log_id, prev_hash, entry_hash, {} FROM OperationLog WHERE {} ORDER BY log_id ASC LIMIT ?",
+        ChainedRow::COLUMNS,
+        where_clause
+    ))?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(values), logged_operation_from_row)?;
+    rows.collect()
+}
+
+/// Column names of the CSV export, in the order `write_csv_record` writes them.
+pub const CSV_HEADER: [&str; 10] = [
+    "log_id",
+    "timestamp",
+    "operation_type",
+    "target_entity",
+    "linked_project_version_id",
+    "content_hash_before",
+    "content_hash_after",
+    "details_json",
+    "prev_hash",
+    "entry_hash",
+];
+
+/// Appends one operation as a CSV record with the columns exactly as stored, so the export
+/// reproduces every `entry_hash`. An empty field stands for NULL.
+pub fn write_csv_record<W: std::io::Write>(writer: &mut csv::Writer<W>, operation: &LoggedOperation) -> csv::Result<()> {
+    let stored = &operation.stored;
+    writer.write_record([
+        operation.log_id.to_string(),
+        stored.timestamp.clone(),
+        stored.operation_type.clone(),
+        stored.target_entity.clone().unwrap_or_default(),
+        stored.linked_project_version_id.map(|id| id.to_string()).unwrap_or_default(),
+        stored.content_hash_before.clone().unwrap_or_default(),
+        stored.content_hash_after.clone().unwrap_or_default(),
+        stored.details_json.clone().unwrap_or_default(),
+        operation.prev_hash.clone().unwrap_or_default(),
+        operation.entry_hash.clone().unwrap_or_default(),
+    ])
+}
+
+/// One line of the JSON Lines export: the entry as `/api/log` returns it, plus the remaining
+/// stored columns, so the export reproduces every `entry_hash` like the CSV one does.
+#[derive(serde::Serialize)]
+struct JsonLinesRecord<'a> {
+    #[serde(flatten)]
+    operation: &'a LoggedOperation,
+    operation_type: &'a str,
+    target_entity: Option<&'a str>,
+    content_hash_before: Option<&'a str>,
+    content_hash_after: Option<&'a str>,
+    details_json: Option<&'a str>,
+}
+
+/// Appends one operation as a JSON Lines record, newline included.
+pub fn write_jsonl_record<W: std::io::Write>(writer: &mut W, operation: &LoggedOperation) -> std::io::Result<()> {
+    let stored = &operation.stored;
+    let record = JsonLinesRecord {
+        operation,
+        operation_type: &stored.operation_type,
+        target_entity: stored.target_entity.as_deref(),
+        content_hash_before: stored.content_hash_before.as_deref(),
+        content_hash_after: stored.content_hash_after.as_deref(),
+        details_json: stored.details_json.as_deref(),
+    };
+    serde_json::to_writer(&mut *writer, &record)?;
+    writer.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_manage;
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().expect("Failed to open in-memory DB");
+        db_manage::initialize_database(&conn).expect("Failed to initialize test DB schema");
+        conn
+    }
+
+    fn entry(event: OperationEvent) -> OperationLogEntry {
+        OperationLogEntry { linked_project_version_id: None, timestamp: "2024-01-01T00:00:00Z".to_string(), event }
+    }
+
+    #[test]
+    fn test_events_round_trip_through_the_table() {
+        let conn = setup_test_db();
+        let hash = crate::blob_store::sha256_hex(b"x");
+        let events = vec![
+            OperationEvent::ProjectSnapshotInitial { project_name: "P".to_string(), files_count: 2, total_size: 7 },
+            OperationEvent::LlmCall {
+                provider: "openai".to_string(),
+                model: "gpt-4o".to_string(),
+                request_sha256: hash.clone(),
+                response_sha256: None,
+                status_code: Some(502),
+                latency_ms: 120,
+                error: Some("bad gateway".to_string()),
+                input_tokens: None,
+                output_tokens: None,
+                streamed: false,
+                bodies_stored: false,
+                cached: false,
+            },
+            OperationEvent::PatchApplied { file_path: "P/a.txt".to_string(), hash_before: None, hash_after: Some(hash.clone()), hunks_applied: 1 },
+            OperationEvent::SecretGateDecision {
+                target: "llm_request".to_string(),
+                decision: SecretGateOutcome::Blocked,
+                findings: 1,
+                detectors: vec!["aws_access_key".to_string()],
+            },
+        ];
+        for event in &events {
+            log_operation(&conn, &entry(event.clone())).unwrap();
+        }
+
+        let read: Vec<OperationEvent> = read_operations(&conn, &LogFilter::default(), 0, 10).unwrap().into_iter().map(|op| op.event.typed().cloned().unwrap()).collect();
+        assert_eq!(read, events);
+        assert_eq!(read_operations(&conn, &LogFilter::default(), 2, 10).unwrap()[0].log_id, 3);
+
+        let (operation_type, target, after, details): (String, String, Option<String>, String) = conn
+            .query_row(
+                "SELECT operation_type, target_entity, content_hash_after, details_json FROM OperationLog WHERE log_id = 3",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!((operation_type.as_str(), target.as_str()), ("PATCH_APPLIED", "P/a.txt"));
+        assert_eq!(after, Some(hash));
+        assert!(!details.contains("operation_type"));
+    }
+
+    #[test]
+    fn test_invalid_events_are_rejected() {
+        let conn = setup_test_db();
+        let invalid = [
+            OperationEvent::PatchApplied { file_path: "P/a.txt".to_string(), hash_before: Some("ABC".to_string()), hash_after: None, hunks_applied: 1 },
+            OperationEvent::SecretGateDecision { target: "llm_request".to_string(), decision: SecretGateOutcome::Blocked, findings: 0, detectors: vec![] },
+            OperationEvent::ProjectSnapshotInitial { project_name: String::new(), files_count: 0, total_size: 0 },
+        ];
+        for event in invalid {
+            assert!(matches!(log_operation(&conn, &entry(event)), Err(rusqlite::Error::ToSqlConversionFailure(_))));
+        }
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM OperationLog", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_reads_rows_written_before_typed_events() {
+        let conn = setup_test_db();
+        conn.execute(
+            "INSERT INTO OperationLog (linked_project_version_id, timestamp, operation_type, target_entity, details_json)
+             VALUES (NULL, '2024-01-01T00:00:00Z', 'PROJECT_RESTORE', 'Restored to version 1',
+                     '{\"from_version_id\":2,\"restored_version_id\":1,\"files_written\":3,\"files_deleted\":0}')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO OperationLog (timestamp, operation_type, details_json) VALUES ('2024-01-01T00:00:00Z', 'SOMETHING_ELSE', '{}')",
+            [],
+        )
+        .unwrap();
+        // As the migration that introduced the chain would have done.
+        backfill_chain(&conn).unwrap();
+
+        let operations = read_operations(&conn, &LogFilter::default(), 0, 10).unwrap();
+        assert!(matches!(
+            operations[0].event,
+            LoggedEvent::Typed(OperationEvent::ProjectRestore { restored_version_id: 1, files_written: 3, .. })
+        ));
+        // An operation type this build does not know comes back as stored.
+        assert_eq!(
+            operations[1].event,
+            LoggedEvent::Untyped { operation_type: "SOMETHING_ELSE".to_string(), details_json: Some("{}".to_string()) }
+        );
+        assert_eq!(serde_json::to_value(&operations[1]).unwrap()["event"]["operation_type"], "SOMETHING_ELSE");
+    }
+
+    #[test]
+    fn test_hash_chain_detects_edits_and_removals() {
+        let conn = setup_test_db();
+        for i in 0..4 {
+            let event = OperationEvent::ProjectSnapshotInitial { project_name: format!("P{}", i), files_count: i, total_size: 0 };
+            log_operation(&conn, &entry(event)).unwrap();
+        }
+        let report = verify_log_chain(&conn).unwrap();
+        assert!(report.intact);
+        assert_eq!(report.entries_checked, 4);
+        assert_eq!(read_operations(&conn, &LogFilter::default(), 0, 10).unwrap()[3].entry_hash, Some(report.head_hash));
+        assert_eq!(read_operations(&conn, &LogFilter::default(), 0, 1).unwrap()[0].prev_hash.as_deref(), Some(GENESIS_HASH));
+
+        // Editing a row breaks its own hash.
+        conn.execute("UPDATE OperationLog SET target_entity = 'forged' WHERE log_id = 3", []).unwrap();
+        let report = verify_log_chain(&conn).unwrap();
+        assert!(!report.intact);
+        assert_eq!(report.entries_checked, 2);
+        let broken = report.first_broken.unwrap();
+        assert_eq!(broken.log_id, 3);
+        assert!(matches!(broken.reason, ChainBreak::EntryHashMismatch { .. }));
+        conn.execute("UPDATE OperationLog SET target_entity = 'P2' WHERE log_id = 3", []).unwrap();
+        assert!(verify_log_chain(&conn).unwrap().intact);
+
+        // Removing a row breaks the link of the row after it.
+        conn.execute("DELETE FROM OperationLog WHERE log_id = 2", []).unwrap();
+        let broken = verify_log_chain(&conn).unwrap().first_broken.unwrap();
+        assert_eq!(broken.log_id, 3);
+        assert!(matches!(broken.reason, ChainBreak::PrevHashMismatch { .. }));
+    }
+
+    #[test]
+    fn test_filters_and_csv_export() {
+        let conn = setup_test_db();
+        let at = |timestamp: &str, project_name: &str| OperationLogEntry {
+            linked_project_version_id: None,
+            timestamp: timestamp.to_string(),
+            event: OperationEvent::ProjectSnapshotInitial { project_name: project_name.to_string(), files_count: 0, total_size: 0 },
+        };
+        log_operation(&conn, &at("2024-01-01T00:00:00+00:00", "A")).unwrap();
+        log_operation(&conn, &at("2024-02-01T00:00:00+00:00", "B, \"quoted\"")).unwrap();
+        log_operation(&conn, &at("2024-03-01T00:00:00+00:00", "A")).unwrap();
+        let restore = OperationLogEntry {
+            linked_project_version_id: Some(7),
+            timestamp: "2024-03-02T00:00:00+00:00".to_string(),
+            event: OperationEvent::ProjectRestore { from_version_id: 6, restored_version_id: 1, files_written: 0, files_deleted: 0 },
+        };
+        log_operation(&conn, &restore).unwrap();
+        // Written by hand: key order and spacing that re-serializing the event would not keep.
+        conn.execute(
+            "INSERT INTO OperationLog (timestamp, operation_type, target_entity, details_json)
+             VALUES ('2024-03-03T00:00:00+00:00', 'PROJECT_RESTORE', 'by hand',
+                     '{\"restored_version_id\": 1, \"from_version_id\": 7, \"files_written\": 0, \"files_deleted\": 0}')",
+            [],
+        )
+        .unwrap();
+        backfill_chain(&conn).unwrap();
+
+        let ids = |filter: &LogFilter, after: i64, limit: i64| -> Vec<i64> {
+            read_operations(&conn, filter, after, limit).unwrap().iter().map(|op| op.log_id).collect()
+        };
+        let only_a = LogFilter { target_entity: Some("A".to_string()), ..LogFilter::default() };
+        assert_eq!(ids(&only_a, 0, 10), vec![1, 3]);
+        assert_eq!(ids(&only_a, 1, 10), vec![3]);
+        let february_on = LogFilter {
+            operation_type: Some("PROJECT_SNAPSHOT_INITIAL".to_string()),
+            since: Some("2024-02-01T00:00:00+00:00".to_string()),
+            until: Some("2024-03-01T00:00:00+00:00".to_string()),
+            ..LogFilter::default()
+        };
+        assert_eq!(ids(&february_on, 0, 10), vec![2]);
+        let version_7 = LogFilter { linked_project_version_id: Some(7), ..LogFilter::default() };
+        assert_eq!(ids(&version_7, 0, 10), vec![4]);
+        assert_eq!(ids(&LogFilter::default(), 0, 2), vec![1, 2]);
+
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(CSV_HEADER).unwrap();
+        for operation in read_operations(&conn, &LogFilter::default(), 0, 10).unwrap() {
+            write_csv_record(&mut writer, &operation).unwrap();
+        }
+        let exported = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let mut reader = csv::Reader::from_reader(exported.as_bytes());
+        let records: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 5);
+        assert_eq!(&records[1][3], "B, \"quoted\"");
+        assert_eq!(&records[3][4], "7");
+
+        // The exported columns alone verify the whole chain.
+        let optional = |field: &str| Some(field.to_string()).filter(|field| !field.is_empty());
+        let mut prev_hash = GENESIS_HASH.to_string();
+        for record in &records {
+            let row = ChainedRow {
+                linked_project_version_id: optional(&record[4]).map(|id| id.parse().unwrap()),
+                timestamp: record[1].to_string(),
+                operation_type: record[2].to_string(),
+                target_entity: optional(&record[3]),
+                content_hash_before: optional(&record[5]),
+                content_hash_after: optional(&record[6]),
+                details_json: optional(&record[7]),
+            };
+            assert_eq!(&record[8], prev_hash);
+            assert_eq!(row.entry_hash(&prev_hash), &record[9]);
+            prev_hash = record[9].to_string();
+        }
+
+        // So do the JSON Lines records.
+        let mut exported = Vec::new();
+        for operation in read_operations(&conn, &LogFilter::default(), 0, 10).unwrap() {
+            write_jsonl_record(&mut exported, &operation).unwrap();
+        }
+        let mut prev_hash = GENESIS_HASH.to_string();
+        for line in String::from_utf8(exported).unwrap().lines() {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            let text = |key: &str| record[key].as_str().map(str::to_string);
+            let row = ChainedRow {
+                linked_project_version_id: record["linked_project_version_id"].as_i64(),
+                timestamp: text("timestamp").unwrap(),
+                operation_type: text("operation_type").unwrap(),
+                target_entity: text("target_entity"),
+                content_hash_before: text("content_hash_before"),
+                content_hash_after: text("content_hash_after"),
+                details_json: text("details_json"),
+            };
+            assert_eq!(record["event"]["operation_type"], record["operation_type"]);
+            assert_eq!(text("prev_hash"), Some(prev_hash.clone()));
+            prev_hash = row.entry_hash(&prev_hash);
+            assert_eq!(text("entry_hash"), Some(prev_hash.clone()));
+        }
+    }
+
+    #[test]
+    fn test_rows_without_hashes_still_read() {
+        let conn = setup_test_db();
+        log_operation(&conn, &entry(OperationEvent::ProjectSnapshotInitial { project_name: "P".to_string(), files_count: 0, total_size: 0 }))
+            .unwrap();
+        conn.execute("UPDATE OperationLog SET prev_hash = NULL, entry_hash = NULL", []).unwrap();
+
+        let operations = read_operations(&conn, &LogFilter::default(), 0, 10).unwrap();
+        assert_eq!((operations[0].prev_hash.as_deref(), operations[0].entry_hash.as_deref()), (None, None));
+        assert!(!verify_log_chain(&conn).unwrap().intact);
+    }
+}
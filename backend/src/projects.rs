@@ -0,0 +1,217 @@
+// diranalyze/backend/src/projects.rs
+
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use crate::delta_store;
+
+/// A project whose versions are tracked. `root_fingerprint` identifies the project root
+/// independently of its folder name, so two folders called "app" stay separate projects.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Project {
+    pub project_id: i64,
+    pub display_name: String,
+    pub root_fingerprint: String,
+    pub created_at: String,
+}
+
+fn project_from_row(row: &rusqlite::Row) -> Result<Project> {
+    Ok(Project {
+        project_id: row.get(0)?,
+        display_name: row.get(1)?,
+        root_fingerprint: row.get(2)?,
+        created_at: row.get(3)?,
+    })
+}
+
+/// The fingerprint used when a project root is sent without one. It only depends on the name,
+/// so `POST /api/projects` refuses a second project of the same name with a conflict, and
+/// snapshots of a root keep landing in the same project however its files change.
+pub fn default_fingerprint(project_root_name: &str) -> String {
+    format!("name:{}", hex::encode(Sha256::digest(project_root_name.as_bytes())))
+}
+
+/// Creates a project. Fails with a constraint violation if the fingerprint is already taken.
+pub fn create_project(conn: &Connection, display_name: &str, root_fingerprint: &str) -> Result<Project> {
+    conn.execute(
+        "INSERT INTO Projects (display_name, root_fingerprint, created_at) VALUES (?1, ?2, ?3)",
+        params![display_name, root_fingerprint, Utc::now().to_rfc3339()],
+    )?;
+    get_project(conn, conn.last_insert_rowid())?.ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+/// Returns the project with this fingerprint, creating it under `display_name` if there is none.
+pub fn find_or_create_project(conn: &Connection, display_name: &str, root_fingerprint: &str) -> Result<Project> {
+    let existing = conn
+        .query_row(
+            "SELECT project_id, display_name, root_fingerprint, created_at FROM Projects WHERE root_fingerprint = ?1",
+            params![root_fingerprint],
+            project_from_row,
+        )
+        .optional()?;
+    match existing {
+        Some(project) => Ok(project),
+        None => create_project(conn, display_name, root_fingerprint),
+    }
+}
+
+/// Finds or creates the project for a root sent without a fingerprint, keyed on
+/// `default_fingerprint`. Returns `None` when that project does not exist yet but other projects
+/// of this name were created with their own fingerprints: the root could be any of them, so
+/// the caller has to say which.
+pub fn find_or_create_named_project(conn: &Connection, project_root_name: &str) -> Result<Option<Project>> {
+    let fingerprint = default_fingerprint(project_root_name);
+    let same_named: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM Projects WHERE display_name = ?1 AND root_fingerprint != ?2",
+        params![project_root_name, fingerprint],
+        |row| row.get(0),
+    )?;
+    let existing = conn
+        .query_row(
+            "SELECT project_id, display_name, root_fingerprint, created_at FROM Projects WHERE root_fingerprint = ?1",
+            params![fingerprint],
+            project_from_row,
+        )
+        .optional()?;
+    match existing {
+        Some(project) => Ok(Some(project)),
+        None if same_named > 0 => Ok(None),
+        None => create_project(conn, project_root_name, &fingerprint).map(Some),
+    }
+}
+
+pub fn get_project(conn: &Connection, project_id: i64) -> Result<Option<Project>> {
+    conn.query_row(
+        "SELECT project_id, display_name, root_fingerprint, created_at FROM Projects WHERE project_id = ?1",
+        params![project_id],
+        project_from_row,
+    )
+    .optional()
+}
+
+pub fn list_projects(conn: &Connection) -> Result<Vec<Project>> {
+    let mut stmt = conn.prepare(
+        "SELECT project_id, display_name, root_fingerprint, created_at FROM Projects ORDER BY project_id ASC"
+    )?;
+    let rows = stmt.query_map([], project_from_row)?;
+    rows.collect()
+}
+
+/// Renames a project. Returns `false` if it does not exist.
+pub fn rename_project(conn: &Connection, project_id: i64, display_name: &str) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE Projects SET display_name = ?1 WHERE project_id = ?2",
+        params![display_name, project_id],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Deletes a project together with its versions and their file records (via `ON DELETE CASCADE`).
+/// Bodies that other projects share with it are kept as full blobs first.
+/// `OperationLog` entries are kept as the audit trail. Returns `false` if it does not exist.
+pub fn delete_project(conn: &Connection, project_id: i64) -> Result<bool> {
+    conn.execute_batch("SAVEPOINT delete_project;")?;
+    let result = delta_store::preserve_shared_contents(conn, project_id)
+        .and_then(|_| conn.execute("DELETE FROM Projects WHERE project_id = ?1", params![project_id]));
+    match result {
+        Ok(deleted) => {
+            conn.execute_batch("RELEASE delete_project;")?;
+            Ok(deleted > 0)
+        }
+        Err(e) => {
+            let _ = conn.execute_batch("ROLLBACK TO delete_project; RELEASE delete_project;");
+            Err(e)
+        }
+    }
+}
+
+/// Returns whether every listed version belongs to `project_id`.
+pub fn versions_belong_to_project(conn: &Connection, project_id: i64, version_ids: &[i64]) -> Result<bool> {
+    let mut stmt = conn.prepare("SELECT 1 FROM ProjectVersions WHERE version_id = ?1 AND project_id = ?2")?;
+    for version_id in version_ids {
+        if !stmt.exists(params![version_id, project_id])? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_manage;
+
+    #[test]
+    fn test_project_crud_and_fingerprint_identity() {
+        let conn = Connection::open_in_memory().unwrap();
+        db_manage::initialize_database(&conn).unwrap();
+
+        let first = find_or_create_project(&conn, "app", "fp-one").unwrap();
+        let second = find_or_create_project(&conn, "app", "fp-two").unwrap();
+        assert_ne!(first.project_id, second.project_id);
+        assert_eq!(find_or_create_project(&conn, "renamed elsewhere", "fp-one").unwrap(), first);
+        assert!(create_project(&conn, "dup", "fp-one").is_err());
+
+        assert!(rename_project(&conn, first.project_id, "app (work)").unwrap());
+        assert_eq!(get_project(&conn, first.project_id).unwrap().unwrap().display_name, "app (work)");
+        assert_eq!(list_projects(&conn).unwrap().len(), 2);
+
+        assert!(delete_project(&conn, second.project_id).unwrap());
+        assert!(!delete_project(&conn, second.project_id).unwrap());
+        assert!(get_project(&conn, second.project_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_roots_without_fingerprints_are_matched_by_name_unless_ambiguous() {
+        let conn = Connection::open_in_memory().unwrap();
+        db_manage::initialize_database(&conn).unwrap();
+
+        let named = find_or_create_named_project(&conn, "src").unwrap().unwrap();
+        assert_eq!(named.root_fingerprint, default_fingerprint("src"));
+        assert_eq!(find_or_create_named_project(&conn, "src").unwrap(), Some(named.clone()));
+
+        // Once a same-named root has its own fingerprint, the named project still answers.
+        find_or_create_project(&conn, "src", "fp-work").unwrap();
+        assert_eq!(find_or_create_named_project(&conn, "src").unwrap(), Some(named));
+
+        // Without a named project to fall back on, a bare name could be any of them.
+        find_or_create_project(&conn, "app", "fp-app").unwrap();
+        assert_eq!(find_or_create_named_project(&conn, "app").unwrap(), None);
+        assert_eq!(list_projects(&conn).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_deleting_a_project_keeps_contents_another_project_shares() {
+        use crate::version_control::{self, ScannedFileInfo};
+        use crate::version_store::{SqliteVersionStore, VersionStore};
+
+        let conn = Connection::open_in_memory().unwrap();
+        db_manage::initialize_database(&conn).unwrap();
+        let v1_body: String = (0..100).map(|i| format!("const line{} = {};\n", i, i)).collect();
+        let v2_body = v1_body.replace("const line50 = 50;", "const line50 = 5000;");
+        let file = |body: &str| ScannedFileInfo {
+            path: "src/app.js".to_string(),
+            hash: crate::blob_store::sha256_hex(body.as_bytes()),
+            size: body.len() as i64,
+            content: Some(body.as_bytes().to_vec()),
+        };
+        let mut store = SqliteVersionStore::new(&conn);
+
+        // Project A keeps the edited file as a delta; project B has the same file and reuses it.
+        let a = create_project(&conn, "src", "fp-a").unwrap();
+        let a_v1 = version_control::create_initial_project_snapshot(&mut store, a.project_id, "src", &[file(&v1_body)]).unwrap();
+        version_control::create_snapshot(&mut store, a_v1, "Edit line 50", &[file(&v2_body)]).unwrap();
+        let b = create_project(&conn, "src", "fp-b").unwrap();
+        let b_v1 = version_control::create_initial_project_snapshot(&mut store, b.project_id, "src", &[file(&v1_body)]).unwrap();
+        let (b_v2, _) = version_control::create_snapshot(&mut store, b_v1, "Same edit", &[file(&v2_body)]).unwrap();
+        let diffs: i64 = conn.query_row("SELECT COUNT(*) FROM FileDiffs", [], |row| row.get(0)).unwrap();
+        assert_eq!(diffs, 1);
+
+        assert!(delete_project(&conn, a.project_id).unwrap());
+        let plan = version_control::plan_restore(&store, b_v1, b_v2).unwrap();
+        assert!(plan.writes[0].content_available);
+        let restored = delta_store::get_content(&conn, &plan.writes[0].target_hash).unwrap();
+        assert_eq!(restored, Some(v2_body.into_bytes()));
+        assert_eq!(store.get_version_files(b_v2).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,222 @@
+// diranalyze/backend/src/token_budget.rs
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::CoreBPE;
+use crate::config::{LlmConfig, LlmProviderKind};
+use crate::llm_provider::{ChatMessage, Role};
+
+/// Chat formatting overhead per message (`<|start|>{role}\n ... <|end|>`), as OpenAI counts it.
+const TOKENS_PER_MESSAGE: u64 = 3;
+/// Every reply is primed with `<|start|>assistant<|message|>`.
+const TOKENS_PER_REPLY: u64 = 3;
+
+/// How many tokens a prompt will cost. The BPE tables ship inside `tiktoken-rs`, so counting
+/// never touches the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct PromptEstimate {
+    /// The BPE table that was used, e.g. `o200k_base`.
+    pub encoding: &'static str,
+    pub prompt_tokens: u64,
+    /// `true` when the model is an OpenAI model whose tokenizer is known. Other models (Anthropic,
+    /// Ollama, unrecognised names) are counted with `cl100k_base`, which is close but not exact.
+    pub exact: bool,
+}
+
+/// The encoding used for `model` on a provider of `kind`, and whether it is the model's own.
+fn encoding_for(kind: LlmProviderKind, model: &str) -> (&'static str, &'static CoreBPE, bool) {
+    let openai_like = matches!(kind, LlmProviderKind::OpenAi | LlmProviderKind::OpenAiCompatible);
+    match get_tokenizer(model).filter(|_| openai_like) {
+        Some(Tokenizer::O200kBase) => ("o200k_base", tiktoken_rs::o200k_base_singleton(), true),
+        Some(Tokenizer::Cl100kBase) => ("cl100k_base", tiktoken_rs::cl100k_base_singleton(), true),
+        _ => ("cl100k_base", tiktoken_rs::cl100k_base_singleton(), false),
+    }
+}
+
+/// Counts the prompt `messages` would make for `model`, including the chat formatting overhead.
+pub fn estimate_prompt(kind: LlmProviderKind, model: &str, messages: &[ChatMessage]) -> PromptEstimate {
+    let (encoding, bpe, exact) = encoding_for(kind, model);
+    let count = |text: &str| bpe.encode_ordinary(text).len() as u64;
+    let prompt_tokens = messages
+        .iter()
+        .map(|message| {
+            let role = match message.role {
+                Role::System => "system",
+                Role::User => "user",
+                Role::Assistant => "assistant",
+            };
+            TOKENS_PER_MESSAGE + count(role) + count(&message.content)
+        })
+        .sum::<u64>()
+        + TOKENS_PER_REPLY;
+    PromptEstimate { encoding, prompt_tokens, exact }
+}
+
+/// Counts plain text, for replies whose provider reported no usage.
+pub fn estimate_text(kind: LlmProviderKind, model: &str, text: &str) -> u64 {
+    encoding_for(kind, model).1.encode_ordinary(text).len() as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetScope {
+    Request,
+    Session,
+}
+
+/// Why a prompt was refused. Answered as 413 over HTTP.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct BudgetExceeded {
+    pub scope: BudgetScope,
+    pub prompt_tokens: u64,
+    pub limit: u64,
+    /// Tokens the session had already used; always 0 for the per-request limit.
+    pub used: u64,
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scope {
+            BudgetScope::Request => {
+                write!(f, "prompt of {} tokens exceeds the per-request limit of {}", self.prompt_tokens, self.limit)
+            }
+            BudgetScope::Session => write!(
+                f,
+                "prompt of {} tokens would exceed the session limit of {} ({} already used)",
+                self.prompt_tokens, self.limit, self.used
+            ),
+        }
+    }
+}
+
+/// The configured limits plus what each session has used so far. Session totals live in memory
+/// and start over when the backend restarts.
+#[derive(Debug)]
+pub struct TokenBudget {
+    max_request_tokens: Option<u64>,
+    max_session_tokens: Option<u64>,
+    sessions: Mutex<HashMap<String, u64>>,
+}
+
+impl TokenBudget {
+    pub fn new(max_request_tokens: Option<u64>, max_session_tokens: Option<u64>) -> Self {
+        TokenBudget { max_request_tokens, max_session_tokens, sessions: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn from_config(config: &LlmConfig) -> Self {
+        Self::new(config.max_request_tokens, config.max_session_tokens)
+    }
+
+    pub fn max_request_tokens(&self) -> Option<u64> {
+        self.max_request_tokens
+    }
+
+    pub fn max_session_tokens(&self) -> Option<u64> {
+        self.max_session_tokens
+    }
+
+    pub fn session_used(&self, session: Option<&str>) -> u64 {
+        session.map_or(0, |s| self.sessions.lock().unwrap().get(s).copied().unwrap_or(0))
+    }
+
+    /// Checks a prompt against both limits without admitting it.
+    pub fn check(&self, session: Option<&str>, prompt_tokens: u64) -> Result<(), BudgetExceeded> {
+        self.check_used(prompt_tokens, self.session_used(session), session.is_some())
+    }
+
+    fn check_used(&self, prompt_tokens: u64, used: u64, has_session: bool) -> Result<(), BudgetExceeded> {
+        if let Some(limit) = self.max_request_tokens.filter(|&limit| prompt_tokens > limit) {
+            return Err(BudgetExceeded { scope: BudgetScope::Request, prompt_tokens, limit, used: 0 });
+        }
+        if let Some(limit) = self.max_session_tokens.filter(|&limit| has_session && used + prompt_tokens > limit) {
+            return Err(BudgetExceeded { scope: BudgetScope::Session, prompt_tokens, limit, used });
+        }
+        Ok(())
+    }
+
+    /// Checks a prompt and, if it fits, counts it against the session straight away so that
+    /// concurrent requests cannot overrun the limit together. Requests without a session are
+    /// only held to the per-request limit.
+    pub fn admit(&self, session: Option<&str>, prompt_tokens: u64) -> Result<(), BudgetExceeded> {
+        let Some(session) = session else {
+            return self.check_used(prompt_tokens, 0, false);
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        let used = sessions.entry(session.to_string()).or_insert(0);
+        self.check_used(prompt_tokens, *used, true)?;
+        *used += prompt_tokens;
+        Ok(())
+    }
+
+    /// Counts the reply's tokens against the session once it is known.
+    pub fn charge(&self, session: Option<&str>, tokens: u64) {
+        if let Some(session) = session {
+            *self.sessions.lock().unwrap().entry(session.to_string()).or_insert(0) += tokens;
+        }
+    }
+
+    /// Gives back tokens `admit` counted for a call that failed or was cancelled, so a provider
+    /// outage does not use up the session.
+    pub fn refund(&self, session: Option<&str>, tokens: u64) {
+        if let Some(session) = session {
+            if let Some(used) = self.sessions.lock().unwrap().get_mut(session) {
+                *used = used.saturating_sub(tokens);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(text: &str) -> Vec<ChatMessage> {
+        vec![
+            ChatMessage { role: Role::System, content: "You are a helpful assistant.".to_string() },
+            ChatMessage { role: Role::User, content: text.to_string() },
+        ]
+    }
+
+    #[test]
+    fn test_estimate_prompt_picks_encoding_per_model() {
+        // 2 x 3 framing + 2 role tokens + 6 + 6 content tokens + 3 reply priming.
+        let estimate = estimate_prompt(LlmProviderKind::OpenAi, "gpt-4o", &messages("Hello, how are you?"));
+        assert_eq!(estimate, PromptEstimate { encoding: "o200k_base", prompt_tokens: 23, exact: true });
+
+        let estimate = estimate_prompt(LlmProviderKind::OpenAi, "gpt-4", &messages("Hello, how are you?"));
+        assert_eq!((estimate.encoding, estimate.exact), ("cl100k_base", true));
+        let estimate = estimate_prompt(LlmProviderKind::Anthropic, "claude-3-5-sonnet", &messages("Hello, how are you?"));
+        assert_eq!((estimate.encoding, estimate.exact), ("cl100k_base", false));
+        assert_eq!(estimate_text(LlmProviderKind::Ollama, "llama3", "hello world"), 2);
+    }
+
+    #[test]
+    fn test_budget_limits_requests_and_sessions() {
+        let budget = TokenBudget::new(Some(100), Some(250));
+        let refused = budget.admit(Some("s"), 101).unwrap_err();
+        assert_eq!((refused.scope, refused.limit, refused.used), (BudgetScope::Request, 100, 0));
+
+        budget.admit(Some("s"), 100).unwrap();
+        budget.charge(Some("s"), 50);
+        budget.admit(Some("s"), 100).unwrap();
+        assert_eq!(budget.session_used(Some("s")), 250);
+        assert!(budget.check(Some("s"), 1).is_err());
+        let refused = budget.admit(Some("s"), 1).unwrap_err();
+        assert_eq!((refused.scope, refused.used), (BudgetScope::Session, 250));
+        assert!(refused.to_string().contains("250 already used"));
+
+        // Other sessions and session-less requests are unaffected.
+        budget.admit(Some("other"), 100).unwrap();
+        budget.admit(None, 100).unwrap();
+        assert_eq!(budget.session_used(None), 0);
+
+        // A failed call gives its prompt back.
+        budget.admit(Some("other"), 100).unwrap();
+        budget.refund(Some("other"), 100);
+        assert_eq!(budget.session_used(Some("other")), 100);
+        budget.refund(Some("unknown"), 100);
+        assert_eq!(budget.session_used(Some("unknown")), 0);
+    }
+}
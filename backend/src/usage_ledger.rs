@@ -0,0 +1,334 @@
+// diranalyze/backend/src/usage_ledger.rs
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::config::{LlmConfig, ModelPrice};
+
+/// One LLM call as it is billed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageEntry {
+    pub timestamp: String,
+    pub provider: String,
+    pub model: String,
+    pub project_id: Option<i64>,
+    pub session: Option<String>,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// The provider reported no usage, so the counts are our own estimates.
+    pub tokens_estimated: bool,
+    /// `None` when no price is configured for the model.
+    pub cost_usd: Option<f64>,
+    /// The `LLM_CALL` entry this call was logged as.
+    pub log_id: Option<i64>,
+}
+
+pub fn record_usage(conn: &Connection, entry: &UsageEntry) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO UsageLedger
+            (timestamp, provider, model, project_id, session, input_tokens, output_tokens, tokens_estimated, cost_usd, log_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            entry.timestamp,
+            entry.provider,
+            entry.model,
+            entry.project_id,
+            entry.session,
+            entry.input_tokens as i64,
+            entry.output_tokens as i64,
+            entry.tokens_estimated,
+            entry.cost_usd,
+            entry.log_id,
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Per-model prices plus the spend caps they are checked against.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CostPolicy {
+    prices: BTreeMap<String, ModelPrice>,
+    daily_cap_usd: Option<f64>,
+    monthly_cap_usd: Option<f64>,
+}
+
+impl CostPolicy {
+    pub fn new(prices: BTreeMap<String, ModelPrice>, daily_cap_usd: Option<f64>, monthly_cap_usd: Option<f64>) -> Self {
+        CostPolicy { prices, daily_cap_usd, monthly_cap_usd }
+    }
+
+    pub fn from_config(config: &LlmConfig) -> Self {
+        Self::new(config.prices.clone(), config.daily_spend_cap_usd, config.monthly_spend_cap_usd)
+    }
+
+    /// The price of `model`: an exact entry, or else the longest entry the name starts with.
+    pub fn price_for(&self, model: &str) -> Option<ModelPrice> {
+        self.prices
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| *price)
+    }
+
+    pub fn cost(&self, model: &str, input_tokens: u64, output_tokens: u64) -> Option<f64> {
+        self.price_for(model).map(|price| {
+            (input_tokens as f64 * price.input_per_mtok + output_tokens as f64 * price.output_per_mtok) / 1_000_000.0
+        })
+    }
+
+    /// What has been spent in the current UTC day and month, next to the caps.
+    pub fn spend_status(&self, conn: &Connection, now: DateTime<Utc>) -> Result<SpendStatus> {
+        Ok(SpendStatus {
+            daily_cap_usd: self.daily_cap_usd,
+            spent_today_usd: spent_since(conn, &period_start(SpendPeriod::Day, now))?,
+            monthly_cap_usd: self.monthly_cap_usd,
+            spent_this_month_usd: spent_since(conn, &period_start(SpendPeriod::Month, now))?,
+        })
+    }
+
+    /// The first cap that is used up, if any. Calls are refused from the moment spending reaches
+    /// a cap until the period rolls over.
+    pub fn exhausted_cap(&self, conn: &Connection, now: DateTime<Utc>) -> Result<Option<SpendCapReached>> {
+        let caps = [(SpendPeriod::Day, self.daily_cap_usd), (SpendPeriod::Month, self.monthly_cap_usd)];
+        for (period, cap) in caps {
+            if let Some(cap_usd) = cap {
+                let spent_usd = spent_since(conn, &period_start(period, now))?;
+                if spent_usd >= cap_usd {
+                    return Ok(Some(SpendCapReached { period, cap_usd, spent_usd }));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpendPeriod {
+    Day,
+    Month,
+}
+
+/// The start of the UTC day or month containing `now`, in the form timestamps are stored in.
+fn period_start(period: SpendPeriod, now: DateTime<Utc>) -> String {
+    match period {
+        SpendPeriod::Day => now.format("%Y-%m-%dT00:00:00+00:00").to_string(),
+        SpendPeriod::Month => now.format("%Y-%m-01T00:00:00+00:00").to_string(),
+    }
+}
+
+fn spent_since(conn: &Connection, since: &str) -> Result<f64> {
+    conn.query_row(
+        "SELECT COALESCE(SUM(cost_usd), 0.0) FROM UsageLedger WHERE timestamp >= ?1",
+        params![since],
+        |row| row.get(0),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct SpendStatus {
+    pub daily_cap_usd: Option<f64>,
+    pub spent_today_usd: f64,
+    pub monthly_cap_usd: Option<f64>,
+    pub spent_this_month_usd: f64,
+}
+
+/// Why a call was refused. Answered as 402 over HTTP.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct SpendCapReached {
+    pub period: SpendPeriod,
+    pub cap_usd: f64,
+    pub spent_usd: f64,
+}
+
+impl fmt::Display for SpendCapReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let period = match self.period {
+            SpendPeriod::Day => "daily",
+            SpendPeriod::Month => "monthly",
+        };
+        write!(f, "the {} LLM spend cap of ${} is used up (${} spent)", period, self.cap_usd, self.spent_usd)
+    }
+}
+
+/// Restricts rollups to a time range. RFC 3339 bounds; `since` is inclusive, `until` exclusive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsageFilter {
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+impl UsageFilter {
+    fn where_clause(&self) -> (String, Vec<String>) {
+        let mut conditions = vec!["1 = 1".to_string()];
+        let mut values = Vec::new();
+        if let Some(since) = &self.since {
+            conditions.push("u.timestamp >= ?".to_string());
+            values.push(since.clone());
+        }
+        if let Some(until) = &self.until {
+            conditions.push("u.timestamp < ?".to_string());
+            values.push(until.clone());
+        }
+        (conditions.join(" AND "), values)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct UsageTotals {
+    pub calls: i64,
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    /// Cost of the priced calls only.
+    pub cost_usd: f64,
+    /// Calls whose model has no configured price.
+    pub unpriced_calls: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollupGroup {
+    Day,
+    Project,
+    Model,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(untagged)]
+pub enum RollupKey {
+    Day { day: String },
+    Project { project_id: Option<i64>, display_name: Option<String> },
+    Model { provider: String, model: String },
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct UsageRollup {
+    #[serde(flatten)]
+    pub key: RollupKey,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+const TOTALS_COLUMNS: &str = "COUNT(*), COALESCE(SUM(u.input_tokens), 0), COALESCE(SUM(u.output_tokens), 0),
+    COALESCE(SUM(u.cost_usd), 0.0), COALESCE(SUM(u.cost_usd IS NULL), 0)";
+
+fn totals_from_row(row: &rusqlite::Row, offset: usize) -> Result<UsageTotals> {
+    Ok(UsageTotals {
+        calls: row.get(offset)?,
+        input_tokens: row.get(offset + 1)?,
+        output_tokens: row.get(offset + 2)?,
+        cost_usd: row.get(offset + 3)?,
+        unpriced_calls: row.get(offset + 4)?,
+    })
+}
+
+pub fn usage_totals(conn: &Connection, filter: &UsageFilter) -> Result<UsageTotals> {
+    let (where_clause, values) = filter.where_clause();
+    conn.query_row(
+        &format!("SELECT {} FROM UsageLedger u WHERE {}", TOTALS_COLUMNS, where_clause),
+        rusqlite::params_from_iter(values),
+        |row| totals_from_row(row, 0),
+    )
+}
+
+/// Totals per UTC day (oldest first), per project or per provider and model (most expensive first).
+pub fn usage_rollups(conn: &Connection, filter: &UsageFilter, group: RollupGroup) -> Result<Vec<UsageRollup>> {
+    let (key_columns, joins, group_by, order_by) = match group {
+        RollupGroup::Day => ("substr(u.timestamp, 1, 10), NULL", "", "1", "1 ASC"),
+        RollupGroup::Project => (
+            "u.project_id, p.display_name",
+            "LEFT JOIN Projects p ON p.project_id = u.project_id",
+            "u.project_id",
+            "6 DESC, 1 ASC",
+        ),
+        RollupGroup::Model => ("u.provider, u.model", "", "1, 2", "6 DESC, 1 ASC, 2 ASC"),
+    };
+    let (where_clause, values) = filter.where_clause();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}, {} FROM UsageLedger u {} WHERE {} GROUP BY {} ORDER BY {}",
+        key_columns, TOTALS_COLUMNS, joins, where_clause, group_by, order_by
+    ))?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(values), |row| {
+        let key = match group {
+            RollupGroup::Day => RollupKey::Day { day: row.get(0)? },
+            RollupGroup::Project => RollupKey::Project { project_id: row.get(0)?, display_name: row.get(1)? },
+            RollupGroup::Model => RollupKey::Model { provider: row.get(0)?, model: row.get(1)? },
+        };
+        Ok(UsageRollup { key, totals: totals_from_row(row, 2)? })
+    })?;
+    rows.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_manage;
+    use crate::projects;
+
+    fn entry(timestamp: &str, model: &str, project_id: Option<i64>, cost_usd: Option<f64>) -> UsageEntry {
+        UsageEntry {
+            timestamp: timestamp.to_string(),
+            provider: "openai".to_string(),
+            model: model.to_string(),
+            project_id,
+            session: None,
+            input_tokens: 1000,
+            output_tokens: 100,
+            tokens_estimated: false,
+            cost_usd,
+            log_id: None,
+        }
+    }
+
+    #[test]
+    fn test_prices_match_longest_prefix() {
+        let policy = CostPolicy::new(
+            BTreeMap::from([
+                ("gpt-4o".to_string(), ModelPrice { input_per_mtok: 2.5, output_per_mtok: 10.0 }),
+                ("gpt-4o-mini".to_string(), ModelPrice { input_per_mtok: 0.15, output_per_mtok: 0.6 }),
+            ]),
+            None,
+            None,
+        );
+        assert_eq!(policy.cost("gpt-4o-2024-08-06", 1_000_000, 100_000), Some(3.5));
+        assert_eq!(policy.price_for("gpt-4o-mini-2024-07-18").unwrap().input_per_mtok, 0.15);
+        assert_eq!(policy.cost("llama3", 10, 10), None);
+    }
+
+    #[test]
+    fn test_rollups_and_spend_caps() {
+        let conn = Connection::open_in_memory().unwrap();
+        db_manage::initialize_database(&conn).unwrap();
+        let project = projects::create_project(&conn, "app", "fp-app").unwrap();
+        record_usage(&conn, &entry("2024-05-30T23:00:00+00:00", "gpt-4o", None, Some(0.5))).unwrap();
+        record_usage(&conn, &entry("2024-06-01T09:00:00+00:00", "gpt-4o", Some(project.project_id), Some(1.0))).unwrap();
+        record_usage(&conn, &entry("2024-06-01T10:00:00+00:00", "llama3", Some(project.project_id), None)).unwrap();
+
+        let all = UsageFilter::default();
+        let totals = usage_totals(&conn, &all).unwrap();
+        assert_eq!((totals.calls, totals.input_tokens, totals.cost_usd, totals.unpriced_calls), (3, 3000, 1.5, 1));
+
+        let by_day = usage_rollups(&conn, &all, RollupGroup::Day).unwrap();
+        assert_eq!(by_day[0].key, RollupKey::Day { day: "2024-05-30".to_string() });
+        assert_eq!((by_day[1].totals.calls, by_day[1].totals.cost_usd), (2, 1.0));
+        let by_project = usage_rollups(&conn, &all, RollupGroup::Project).unwrap();
+        assert_eq!(
+            by_project[0].key,
+            RollupKey::Project { project_id: Some(project.project_id), display_name: Some("app".to_string()) }
+        );
+        let by_model = usage_rollups(&conn, &all, RollupGroup::Model).unwrap();
+        assert_eq!(serde_json::to_value(&by_model[1]).unwrap()["model"], "llama3");
+        let june = UsageFilter { since: Some("2024-06-01T00:00:00+00:00".to_string()), until: None };
+        assert_eq!(usage_totals(&conn, &june).unwrap().calls, 2);
+
+        let now = DateTime::parse_from_rfc3339("2024-06-01T12:00:00+00:00").unwrap().with_timezone(&Utc);
+        let policy = CostPolicy::new(BTreeMap::new(), Some(2.0), Some(1.5));
+        let status = policy.spend_status(&conn, now).unwrap();
+        assert_eq!((status.spent_today_usd, status.spent_this_month_usd), (1.0, 1.0));
+        assert_eq!(policy.exhausted_cap(&conn, now).unwrap(), None);
+        record_usage(&conn, &entry("2024-06-01T11:00:00+00:00", "gpt-4o", None, Some(0.5))).unwrap();
+        let reached = policy.exhausted_cap(&conn, now).unwrap().unwrap();
+        assert_eq!((reached.period, reached.cap_usd, reached.spent_usd), (SpendPeriod::Month, 1.5, 1.5));
+        assert!(reached.to_string().contains("monthly"));
+    }
+}
@@ -1,7 +1,11 @@
 // diranalyze/backend/src/version_control.rs
 
-use rusqlite::{Connection, Result, params};
-use chrono::{DateTime, Utc};
+use rusqlite::Result;
+use crate::blob_store;
+use crate::operation_log::{OperationEvent, OperationLogEntry};
+use crate::version_store::{VersionFileRecord, VersionStore};
+use chrono::Utc;
+use std::collections::{BTreeMap, BTreeSet};
 
 // Define a simple struct to represent file info coming from the frontend/scanner
 #[derive(Debug, serde::Deserialize)] // Deserialize if it comes from an API request
@@ -9,69 +13,404 @@ pub struct ScannedFileInfo {
     pub path: String,        // Relative path from project root
     pub hash: String,        // SHA-256 content hash
     pub size: i64,           // File size in bytes
+    #[serde(skip)]
+    pub content: Option<Vec<u8>>, // File body, when the client uploaded it; stored in `Blobs`
+}
+
+/// A file whose uploaded content does not hash to the hash the client claimed.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct HashMismatch {
+    pub path: String,
+    pub provided_hash: String,
+    pub computed_hash: String,
+}
+
+/// Normalises every file's hash and, for files that came with content, checks it
+/// against the SHA-256 the backend computes itself. A file with content and an empty
+/// hash takes the computed one.
+///
+/// # Returns
+/// `Ok(())` if all uploaded contents match, otherwise every offending file.
+pub fn verify_and_normalize_hashes(files: &mut [ScannedFileInfo]) -> std::result::Result<(), Vec<HashMismatch>> {
+    let mut mismatches = Vec::new();
+    for file_info in files.iter_mut() {
+        let provided_hash = blob_store::normalize_content_hash(&file_info.hash);
+        let Some(content) = file_info.content.as_deref() else {
+            file_info.hash = provided_hash;
+            continue;
+        };
+        let computed_hash = blob_store::sha256_hex(content);
+        if !provided_hash.is_empty() && provided_hash != computed_hash {
+            mismatches.push(HashMismatch {
+                path: file_info.path.clone(),
+                provided_hash,
+                computed_hash,
+            });
+        } else {
+            file_info.hash = computed_hash;
+        }
+    }
+    if mismatches.is_empty() { Ok(()) } else { Err(mismatches) }
+}
+
+/// Writes the bodies of any files that came with content. Files that changed relative to the
+/// parent version are stored as deltas against the parent's content where possible; everything
+/// else goes to the blob store, deduplicated by hash.
+fn store_uploaded_contents<S: VersionStore>(
+    store: &mut S,
+    version_id: i64,
+    parent_version_id: Option<i64>,
+    files: &[ScannedFileInfo],
+) -> Result<()> {
+    let parent_hashes: BTreeMap<String, String> = match parent_version_id {
+        Some(parent_id) => store.get_version_files(parent_id)?
+            .into_iter()
+            .map(|f| (f.file_path, f.content_hash))
+            .collect(),
+        None => BTreeMap::new(),
+    };
+    for file_info in files {
+        let Some(content) = file_info.content.as_deref() else {
+            continue;
+        };
+        let source_hash = parent_hashes
+            .get(&file_info.path)
+            .filter(|parent_hash| **parent_hash != file_info.hash)
+            .map(String::as_str);
+        store.put_content(version_id, &file_info.path, content, source_hash)?;
+    }
+    Ok(())
+}
+
+fn version_file_records(files: &[ScannedFileInfo]) -> Vec<VersionFileRecord> {
+    files
+        .iter()
+        .map(|f| VersionFileRecord { file_path: f.path.clone(), content_hash: f.hash.clone(), file_size: f.size })
+        .collect()
 }
 
 /// Creates the initial version (Version 0) of the project in the database.
 /// This function assumes it's called after the initial scan of a project.
 ///
 /// # Arguments
-/// * `conn` - A mutable reference to the SQLite connection.
+/// * `store` - Where the version is persisted.
+/// * `project_id` - The `Projects` row this version belongs to.
 /// * `project_root_name` - The name of the root directory of the project.
 /// * `files` - A slice of `ScannedFileInfo` structs representing all files in the project.
 ///
 /// # Returns
 /// The `version_id` of the newly created project version, or an error.
-pub fn create_initial_project_snapshot(
-    conn: &mut Connection,
-    project_root_name: &str, // Or some unique project identifier if you manage multiple
+pub fn create_initial_project_snapshot<S: VersionStore>(
+    store: &mut S,
+    project_id: i64,
+    project_root_name: &str,
     files: &[ScannedFileInfo],
 ) -> Result<i64> {
-    let tx = conn.transaction()?;
-
-    let current_timestamp = Utc::now().to_rfc3339();
-    let description = format!("Initial snapshot of project: {}", project_root_name);
-
-    // 1. Insert into ProjectVersions
-    tx.execute(
-        "INSERT INTO ProjectVersions (parent_version_id, timestamp, description) VALUES (NULL, ?1, ?2)",
-        params![current_timestamp, description],
-    )?;
-    let version_id = tx.last_insert_rowid();
-
-    // 2. Insert all files into VersionFiles for this version_id
-    let mut stmt_vf = tx.prepare(
-        "INSERT INTO VersionFiles (project_version_id, file_path, content_hash, file_size) VALUES (?1, ?2, ?3, ?4)"
-    )?;
-    for file_info in files {
-        stmt_vf.execute(params![
-            version_id,
-            file_info.path,     // Ensure this path is relative to the project root
-            file_info.hash,
-            file_info.size
-        ])?;
-    }
-    drop(stmt_vf); // Explicitly drop statement before commit if preferred, or it drops on scope end
-
-    // 3. (Optional) Log this high-level operation in OperationLog
-    let op_details = serde_json::json!({
-        "project_name": project_root_name,
-        "files_count": files.len(),
-        "total_size": files.iter().map(|f| f.size).sum::<i64>()
-    });
-    tx.execute(
-        "INSERT INTO OperationLog (linked_project_version_id, timestamp, operation_type, target_entity, details_json)
-         VALUES (?1, ?2, ?3, ?4, ?5)",
-        params![
-            version_id,
-            current_timestamp,
-            "PROJECT_SNAPSHOT_INITIAL",
-            project_root_name,
-            op_details.to_string()
-        ],
-    )?;
-
-    tx.commit()?;
-    Ok(version_id)
+    store.transaction(|store| {
+        let current_timestamp = Utc::now().to_rfc3339();
+        let description = format!("Initial snapshot of project: {}", project_root_name);
+
+        // 1. Create the root version and record all of its files
+        let version_id = store.create_version(Some(project_id), None, &current_timestamp, &description)?;
+        store.add_version_files(version_id, &version_file_records(files))?;
+        store_uploaded_contents(store, version_id, None, files)?;
+
+        // 2. Log this high-level operation in OperationLog
+        store.append_log(&OperationLogEntry {
+            linked_project_version_id: Some(version_id),
+            timestamp: current_timestamp,
+            event: OperationEvent::ProjectSnapshotInitial {
+                project_name: project_root_name.to_string(),
+                files_count: files.len(),
+                total_size: files.iter().map(|f| f.size).sum(),
+            },
+        })?;
+        Ok(version_id)
+    })
+}
+
+/// Counts of file-level changes between a new snapshot and its parent version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct SnapshotChangeCounts {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+}
+
+/// Creates a new version of the project as a child of an existing version.
+/// Used after patches are applied so the history forms a chain instead of a set of
+/// disconnected "Version 0" roots.
+///
+/// # Arguments
+/// * `store` - Where the version is persisted.
+/// * `parent_version_id` - The `version_id` this snapshot derives from. Must exist.
+/// * `description` - Human-readable summary of what this version represents.
+/// * `files` - The complete file list of the project at this version.
+///
+/// # Returns
+/// The new `version_id` and the change counts relative to the parent, or an error.
+/// A missing parent is reported as `rusqlite::Error::QueryReturnedNoRows`.
+pub fn create_snapshot<S: VersionStore>(
+    store: &mut S,
+    parent_version_id: i64,
+    description: &str,
+    files: &[ScannedFileInfo],
+) -> Result<(i64, SnapshotChangeCounts)> {
+    store.transaction(|store| {
+        // 1. Validate the parent exists before we write anything.
+        let parent = store.get_version(parent_version_id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+
+        let current_timestamp = Utc::now().to_rfc3339();
+
+        // 2. Create the version, linked to the parent and in the parent's project, with its full file list
+        let version_id = store.create_version(parent.project_id, Some(parent_version_id), &current_timestamp, description)?;
+        store.add_version_files(version_id, &version_file_records(files))?;
+        store_uploaded_contents(store, version_id, Some(parent_version_id), files)?;
+
+        // 3. Diff against the parent to get the change counts for the log
+        let diff = diff_versions(store, parent_version_id, version_id)?;
+        let counts = SnapshotChangeCounts {
+            added: diff.added.len(),
+            removed: diff.deleted.len(),
+            modified: diff.modified.len(),
+        };
+
+        // 4. Log the operation with the counts relative to the parent
+        store.append_log(&OperationLogEntry {
+            linked_project_version_id: Some(version_id),
+            timestamp: current_timestamp,
+            event: OperationEvent::ProjectSnapshotPatch {
+                parent_version_id,
+                description: description.to_string(),
+                files_count: files.len(),
+                total_size: files.iter().map(|f| f.size).sum(),
+                added: counts.added,
+                removed: counts.removed,
+                modified: counts.modified,
+            },
+        })?;
+        Ok((version_id, counts))
+    })
+}
+
+/// How a single file differs between two versions. Hashes and sizes are `None`
+/// on the side where the file does not exist.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct FileChange {
+    pub file_path: String,
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
+    pub old_size: Option<i64>,
+    pub new_size: Option<i64>,
+    pub size_delta: i64,
+}
+
+/// File-level comparison of two versions, keyed by `file_path` and `content_hash`.
+/// Each list is ordered by path.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct VersionDiff {
+    pub from_version_id: i64,
+    pub to_version_id: i64,
+    pub added: Vec<FileChange>,
+    pub deleted: Vec<FileChange>,
+    pub modified: Vec<FileChange>,
+    pub unchanged: Vec<FileChange>,
+    pub total_size_delta: i64,
+}
+
+/// Compares the recorded file lists of two versions.
+///
+/// # Returns
+/// The structural diff going from `from_version_id` to `to_version_id`, or an error.
+/// A missing version is reported as `rusqlite::Error::QueryReturnedNoRows`.
+pub fn diff_versions<S: VersionStore>(store: &S, from_version_id: i64, to_version_id: i64) -> Result<VersionDiff> {
+    for version_id in [from_version_id, to_version_id] {
+        if store.get_version(version_id)?.is_none() {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+    }
+
+    let mut old_files: BTreeMap<String, VersionFileRecord> = store.get_version_files(from_version_id)?
+        .into_iter()
+        .map(|f| (f.file_path.clone(), f))
+        .collect();
+    let new_files = store.get_version_files(to_version_id)?;
+
+    let mut diff = VersionDiff {
+        from_version_id,
+        to_version_id,
+        added: Vec::new(),
+        deleted: Vec::new(),
+        modified: Vec::new(),
+        unchanged: Vec::new(),
+        total_size_delta: 0,
+    };
+
+    for new_file in new_files {
+        let old_file = old_files.remove(&new_file.file_path);
+        let change = FileChange {
+            file_path: new_file.file_path,
+            old_hash: old_file.as_ref().map(|f| f.content_hash.clone()),
+            size_delta: new_file.file_size - old_file.as_ref().map_or(0, |f| f.file_size),
+            old_size: old_file.as_ref().map(|f| f.file_size),
+            new_hash: Some(new_file.content_hash),
+            new_size: Some(new_file.file_size),
+        };
+        match &old_file {
+            None => diff.added.push(change),
+            Some(old) if Some(&old.content_hash) != change.new_hash.as_ref() => diff.modified.push(change),
+            Some(_) => diff.unchanged.push(change),
+        }
+    }
+    // Whatever is left only existed in the old version.
+    for (file_path, old_file) in old_files {
+        diff.deleted.push(FileChange {
+            file_path,
+            old_hash: Some(old_file.content_hash),
+            new_hash: None,
+            old_size: Some(old_file.file_size),
+            new_size: None,
+            size_delta: -old_file.file_size,
+        });
+    }
+
+    diff.total_size_delta = diff
+        .added
+        .iter()
+        .chain(&diff.deleted)
+        .chain(&diff.modified)
+        .map(|c| c.size_delta)
+        .sum();
+    Ok(diff)
+}
+
+/// A file the working tree must end up with. `expected_pre_hash` is what the file should
+/// currently contain (`None` if it should not exist yet), so the client can detect drift.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct RestoreWrite {
+    pub file_path: String,
+    pub expected_pre_hash: Option<String>,
+    pub target_hash: String,
+    pub target_size: i64,
+    pub content_available: bool,
+}
+
+/// A file that must be removed from the working tree.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct RestoreDeletion {
+    pub file_path: String,
+    pub expected_pre_hash: String,
+}
+
+/// The operations needed to bring a working tree at `from_version_id` to `to_version_id`.
+/// Directories are listed parents first; writes and deletions are ordered by path.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct RestorePlan {
+    pub from_version_id: i64,
+    pub to_version_id: i64,
+    pub directories_to_create: Vec<String>,
+    pub writes: Vec<RestoreWrite>,
+    pub deletions: Vec<RestoreDeletion>,
+    pub unchanged_count: usize,
+}
+
+/// Every ancestor directory of a project-relative `/`-separated file path.
+fn ancestor_directories(file_path: &str) -> impl Iterator<Item = &str> {
+    file_path.match_indices('/').map(move |(i, _)| &file_path[..i])
+}
+
+/// Builds the plan for restoring a working tree from one version to another.
+/// It only reads the store; nothing is logged until the client reports the restore via
+/// `record_restore`.
+///
+/// # Returns
+/// The restore plan, or an error. A missing version is reported as
+/// `rusqlite::Error::QueryReturnedNoRows`.
+pub fn plan_restore<S: VersionStore>(store: &S, from_version_id: i64, to_version_id: i64) -> Result<RestorePlan> {
+    let diff = diff_versions(store, from_version_id, to_version_id)?;
+
+    let existing_dirs: BTreeSet<&str> = diff
+        .deleted
+        .iter()
+        .chain(&diff.modified)
+        .chain(&diff.unchanged)
+        .flat_map(|c| ancestor_directories(&c.file_path))
+        .collect();
+    let directories_to_create: BTreeSet<String> = diff
+        .added
+        .iter()
+        .flat_map(|c| ancestor_directories(&c.file_path))
+        .filter(|dir| !existing_dirs.contains(dir))
+        .map(str::to_string)
+        .collect();
+
+    let mut writes = Vec::with_capacity(diff.added.len() + diff.modified.len());
+    for change in diff.added.iter().chain(&diff.modified) {
+        let target_hash = change.new_hash.clone().unwrap_or_default();
+        writes.push(RestoreWrite {
+            file_path: change.file_path.clone(),
+            expected_pre_hash: change.old_hash.clone(),
+            content_available: store.has_content(&target_hash)?,
+            target_hash,
+            target_size: change.new_size.unwrap_or_default(),
+        });
+    }
+    writes.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+
+    let deletions = diff
+        .deleted
+        .iter()
+        .map(|change| RestoreDeletion {
+            file_path: change.file_path.clone(),
+            expected_pre_hash: change.old_hash.clone().unwrap_or_default(),
+        })
+        .collect();
+
+    Ok(RestorePlan {
+        from_version_id,
+        to_version_id,
+        directories_to_create: directories_to_create.into_iter().collect(),
+        writes,
+        deletions,
+        unchanged_count: diff.unchanged.len(),
+    })
+}
+
+/// Records that a working tree at `from_version_id` was restored to `to_version_id`.
+/// Creates a new child of `from_version_id` whose files are a copy of the restored version,
+/// and logs a `PROJECT_RESTORE` operation.
+///
+/// # Returns
+/// The `version_id` of the new child version, or an error. A missing version is reported as
+/// `rusqlite::Error::QueryReturnedNoRows`.
+pub fn record_restore<S: VersionStore>(store: &mut S, from_version_id: i64, to_version_id: i64) -> Result<i64> {
+    store.transaction(|store| {
+        // 1. Validates both versions and gives us the counts for the log
+        let diff = diff_versions(store, from_version_id, to_version_id)?;
+        let from_version = store.get_version(from_version_id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+
+        let current_timestamp = Utc::now().to_rfc3339();
+        let description = format!("Restored to version {}", to_version_id);
+
+        // 2. The restored state becomes a new child of the version the tree was at,
+        //    with a copy of the restored version's file list
+        let version_id = store.create_version(from_version.project_id, Some(from_version_id), &current_timestamp, &description)?;
+        let restored_files = store.get_version_files(to_version_id)?;
+        store.add_version_files(version_id, &restored_files)?;
+
+        // 3. Log the restore
+        store.append_log(&OperationLogEntry {
+            linked_project_version_id: Some(version_id),
+            timestamp: current_timestamp,
+            event: OperationEvent::ProjectRestore {
+                from_version_id,
+                restored_version_id: to_version_id,
+                files_written: diff.added.len() + diff.modified.len(),
+                files_deleted: diff.deleted.len(),
+            },
+        })?;
+        Ok(version_id)
+    })
 }
 
 // --- Example Usage (for testing this module, not for direct API use yet) ---
@@ -79,17 +418,25 @@ pub fn create_initial_project_snapshot(
 mod tests {
     use super::*;
     use crate::db_manage; // To use the open_db_connection and initialize_database
+    use crate::delta_store;
+    use crate::version_store::{MemoryVersionStore, SqliteVersionStore};
+    use rusqlite::{params, Connection};
+
+    const TEST_PROJECT_ID: i64 = 1;
 
     fn setup_test_db() -> Connection {
-        // Use an in-memory database for testing
-        let mut conn = Connection::open_in_memory().expect("Failed to open in-memory DB");
+        // Use an in-memory database for testing, with one project to hang versions off
+        let conn = Connection::open_in_memory().expect("Failed to open in-memory DB");
         db_manage::initialize_database(&conn).expect("Failed to initialize test DB schema");
+        let project = crate::projects::create_project(&conn, "TestProject", "fp-test").expect("Failed to create test project");
+        assert_eq!(project.project_id, TEST_PROJECT_ID);
         conn
     }
 
     #[test]
     fn test_create_initial_snapshot() {
-        let mut conn = setup_test_db();
+        let conn = setup_test_db();
+        let mut store = SqliteVersionStore::new(&conn);
 
         let project_name = "MyTestProject";
         let files_data = vec![
@@ -97,15 +444,17 @@ mod tests {
                 path: "MyTestProject/README.md".to_string(),
                 hash: "abc123readmehash".to_string(),
                 size: 1024,
+                content: None,
             },
             ScannedFileInfo {
                 path: "MyTestProject/src/main.js".to_string(),
                 hash: "def456mainjshash".to_string(),
                 size: 2048,
+                content: None,
             },
         ];
 
-        let result = create_initial_project_snapshot(&mut conn, project_name, &files_data);
+        let result = create_initial_project_snapshot(&mut store, TEST_PROJECT_ID, project_name, &files_data);
         assert!(result.is_ok());
         let version_id = result.unwrap();
         assert_eq!(version_id, 1); // First version
@@ -117,6 +466,8 @@ mod tests {
         }).unwrap();
         assert_eq!(pv_row.0, "Initial snapshot of project: MyTestProject");
         assert_eq!(pv_row.1, None);
+        let owner: i64 = conn.query_row("SELECT project_id FROM ProjectVersions WHERE version_id = ?1", params![version_id], |row| row.get(0)).unwrap();
+        assert_eq!(owner, TEST_PROJECT_ID);
 
         // Verify VersionFiles table
         let mut stmt_vf_count = conn.prepare("SELECT COUNT(*) FROM VersionFiles WHERE project_version_id = ?1").unwrap();
@@ -135,4 +486,235 @@ mod tests {
         let ol_count: i64 = stmt_ol_count.query_row(params![version_id], |row| row.get(0)).unwrap();
         assert_eq!(ol_count, 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_create_snapshot_links_parent_and_counts_changes() {
+        let conn = setup_test_db();
+        let mut store = SqliteVersionStore::new(&conn);
+
+        let parent_files = vec![
+            ScannedFileInfo { path: "P/README.md".to_string(), hash: "h_readme".to_string(), size: 10, content: None },
+            ScannedFileInfo { path: "P/src/a.js".to_string(), hash: "h_a1".to_string(), size: 20, content: None },
+            ScannedFileInfo { path: "P/src/old.js".to_string(), hash: "h_old".to_string(), size: 30, content: None },
+        ];
+        let parent_id = create_initial_project_snapshot(&mut store, TEST_PROJECT_ID, "P", &parent_files).unwrap();
+
+        let child_files = vec![
+            ScannedFileInfo { path: "P/README.md".to_string(), hash: "h_readme".to_string(), size: 10, content: None },
+            ScannedFileInfo { path: "P/src/a.js".to_string(), hash: "h_a2".to_string(), size: 25, content: None },
+            ScannedFileInfo { path: "P/src/new.js".to_string(), hash: "h_new".to_string(), size: 5, content: None },
+        ];
+        let (child_id, counts) = create_snapshot(&mut store, parent_id, "Applied patch", &child_files).unwrap();
+        assert_eq!(counts, SnapshotChangeCounts { added: 1, removed: 1, modified: 1 });
+
+        let parent_of_child: Option<i64> = conn
+            .query_row("SELECT parent_version_id FROM ProjectVersions WHERE version_id = ?1", params![child_id], |row| row.get(0))
+            .unwrap();
+        assert_eq!(parent_of_child, Some(parent_id));
+
+        let (op_type, details): (String, String) = conn
+            .query_row(
+                "SELECT operation_type, details_json FROM OperationLog WHERE linked_project_version_id = ?1",
+                params![child_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(op_type, "PROJECT_SNAPSHOT_PATCH");
+        let details: serde_json::Value = serde_json::from_str(&details).unwrap();
+        assert_eq!(details["added"], 1);
+        assert_eq!(details["removed"], 1);
+        assert_eq!(details["modified"], 1);
+    }
+
+    #[test]
+    fn test_create_snapshot_rejects_missing_parent() {
+        let conn = setup_test_db();
+        let mut store = SqliteVersionStore::new(&conn);
+        let result = create_snapshot(&mut store, 42, "Orphan", &[]);
+        assert!(matches!(result, Err(rusqlite::Error::QueryReturnedNoRows)));
+
+        let versions: i64 = conn.query_row("SELECT COUNT(*) FROM ProjectVersions", [], |row| row.get(0)).unwrap();
+        assert_eq!(versions, 0);
+    }
+
+    #[test]
+    fn test_list_and_get_versions() {
+        let conn = setup_test_db();
+        let mut store = SqliteVersionStore::new(&conn);
+        let files = vec![
+            ScannedFileInfo { path: "P/a.txt".to_string(), hash: "h_a".to_string(), size: 3, content: None },
+            ScannedFileInfo { path: "P/b.txt".to_string(), hash: "h_b".to_string(), size: 4, content: None },
+        ];
+        let v1 = create_initial_project_snapshot(&mut store, TEST_PROJECT_ID, "P", &files).unwrap();
+        let (v2, _) = create_snapshot(&mut store, v1, "Removed b", &files[..1]).unwrap();
+
+        assert_eq!(store.count_versions(TEST_PROJECT_ID).unwrap(), 2);
+        let page = store.list_versions(TEST_PROJECT_ID, 10, 0).unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].version_id, v1);
+        assert_eq!((page[0].file_count, page[0].total_size), (2, 7));
+        assert_eq!(page[1].parent_version_id, Some(v1));
+
+        let second_page = store.list_versions(TEST_PROJECT_ID, 1, 1).unwrap();
+        assert_eq!(second_page.len(), 1);
+        assert_eq!(second_page[0].version_id, v2);
+
+        let detail = store.get_version(v2).unwrap().unwrap();
+        assert_eq!(detail.project_id, Some(TEST_PROJECT_ID));
+        assert_eq!(detail.description.as_deref(), Some("Removed b"));
+        assert_eq!((detail.file_count, detail.total_size), (1, 3));
+        assert!(store.get_version(999).unwrap().is_none());
+
+        let v1_files = store.get_version_files(v1).unwrap();
+        assert_eq!(v1_files.iter().map(|f| f.file_path.as_str()).collect::<Vec<_>>(), vec!["P/a.txt", "P/b.txt"]);
+    }
+
+    #[test]
+    fn test_diff_versions() {
+        let mut store = MemoryVersionStore::new();
+        let v1_files = vec![
+            ScannedFileInfo { path: "P/keep.txt".to_string(), hash: "h_keep".to_string(), size: 10, content: None },
+            ScannedFileInfo { path: "P/edit.txt".to_string(), hash: "h_edit1".to_string(), size: 20, content: None },
+            ScannedFileInfo { path: "P/gone.txt".to_string(), hash: "h_gone".to_string(), size: 30, content: None },
+        ];
+        let v2_files = vec![
+            ScannedFileInfo { path: "P/keep.txt".to_string(), hash: "h_keep".to_string(), size: 10, content: None },
+            ScannedFileInfo { path: "P/edit.txt".to_string(), hash: "h_edit2".to_string(), size: 25, content: None },
+            ScannedFileInfo { path: "P/new.txt".to_string(), hash: "h_new".to_string(), size: 7, content: None },
+        ];
+        let v1 = create_initial_project_snapshot(&mut store, TEST_PROJECT_ID, "P", &v1_files).unwrap();
+        let (v2, _) = create_snapshot(&mut store, v1, "Edit", &v2_files).unwrap();
+
+        let diff = diff_versions(&store, v1, v2).unwrap();
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].file_path, "P/new.txt");
+        assert_eq!(diff.added[0].size_delta, 7);
+        assert_eq!(diff.deleted[0].file_path, "P/gone.txt");
+        assert_eq!(diff.deleted[0].size_delta, -30);
+        assert_eq!(diff.modified[0].old_hash.as_deref(), Some("h_edit1"));
+        assert_eq!(diff.modified[0].new_hash.as_deref(), Some("h_edit2"));
+        assert_eq!(diff.modified[0].size_delta, 5);
+        assert_eq!(diff.unchanged.len(), 1);
+        assert_eq!(diff.total_size_delta, 7 - 30 + 5);
+
+        let reverse = diff_versions(&store, v2, v1).unwrap();
+        assert_eq!(reverse.added[0].file_path, "P/gone.txt");
+        assert_eq!(reverse.deleted[0].file_path, "P/new.txt");
+
+        assert!(matches!(diff_versions(&store, v1, 999), Err(rusqlite::Error::QueryReturnedNoRows)));
+    }
+
+    #[test]
+    fn test_snapshot_stores_uploaded_contents() {
+        let conn = setup_test_db();
+        let mut store = SqliteVersionStore::new(&conn);
+        let body = b"console.log('v1');".to_vec();
+        let files = vec![ScannedFileInfo {
+            path: "P/app.js".to_string(),
+            hash: blob_store::sha256_hex(&body),
+            size: body.len() as i64,
+            content: Some(body.clone()),
+        }];
+        let v1 = create_initial_project_snapshot(&mut store, TEST_PROJECT_ID, "P", &files).unwrap();
+        create_snapshot(&mut store, v1, "Same content", &files).unwrap();
+
+        let blob_count: i64 = conn.query_row("SELECT COUNT(*) FROM Blobs", [], |row| row.get(0)).unwrap();
+        assert_eq!(blob_count, 1);
+        let recorded_hash = &store.get_version_files(v1).unwrap()[0].content_hash;
+        assert_eq!(blob_store::get_blob(&conn, recorded_hash).unwrap(), Some(body));
+    }
+
+    #[test]
+    fn test_verify_and_normalize_hashes() {
+        let body = b"abc".to_vec();
+        let real_hash = blob_store::sha256_hex(&body);
+        let mut files = vec![
+            ScannedFileInfo { path: "P/upper.txt".to_string(), hash: format!("SHA256:{}", real_hash.to_uppercase()), size: 3, content: Some(body.clone()) },
+            ScannedFileInfo { path: "P/empty.txt".to_string(), hash: String::new(), size: 3, content: Some(body.clone()) },
+            ScannedFileInfo { path: "P/no_body.txt".to_string(), hash: " ABC123 ".to_string(), size: 3, content: None },
+        ];
+        assert!(verify_and_normalize_hashes(&mut files).is_ok());
+        assert_eq!(files[0].hash, real_hash);
+        assert_eq!(files[1].hash, real_hash);
+        assert_eq!(files[2].hash, "abc123");
+
+        let mut bad = vec![
+            ScannedFileInfo { path: "P/README.md".to_string(), hash: "readmehash123".to_string(), size: 3, content: Some(body) },
+        ];
+        let mismatches = verify_and_normalize_hashes(&mut bad).unwrap_err();
+        assert_eq!(mismatches, vec![HashMismatch {
+            path: "P/README.md".to_string(),
+            provided_hash: "readmehash123".to_string(),
+            computed_hash: real_hash,
+        }]);
+    }
+
+    #[test]
+    fn test_plan_and_record_restore() {
+        let mut store = MemoryVersionStore::new();
+        let old_body = b"old".to_vec();
+        let v1_files = vec![
+            ScannedFileInfo { path: "P/keep.txt".to_string(), hash: "h_keep".to_string(), size: 1, content: None },
+            ScannedFileInfo { path: "P/lib/edit.txt".to_string(), hash: blob_store::sha256_hex(&old_body), size: 3, content: Some(old_body) },
+            ScannedFileInfo { path: "P/docs/guide/intro.md".to_string(), hash: "h_intro".to_string(), size: 5, content: None },
+        ];
+        let v2_files = vec![
+            ScannedFileInfo { path: "P/keep.txt".to_string(), hash: "h_keep".to_string(), size: 1, content: None },
+            ScannedFileInfo { path: "P/lib/edit.txt".to_string(), hash: "h_edit2".to_string(), size: 4, content: None },
+            ScannedFileInfo { path: "P/extra.txt".to_string(), hash: "h_extra".to_string(), size: 2, content: None },
+        ];
+        let v1 = create_initial_project_snapshot(&mut store, TEST_PROJECT_ID, "P", &v1_files).unwrap();
+        let (v2, _) = create_snapshot(&mut store, v1, "Patch", &v2_files).unwrap();
+
+        // Going back from v2 to v1
+        let plan = plan_restore(&store, v2, v1).unwrap();
+        assert_eq!(plan.directories_to_create, vec!["P/docs", "P/docs/guide"]);
+        assert_eq!(plan.writes.len(), 2);
+        assert_eq!(plan.writes[0].file_path, "P/docs/guide/intro.md");
+        assert_eq!(plan.writes[0].expected_pre_hash, None);
+        assert!(!plan.writes[0].content_available);
+        assert_eq!(plan.writes[1].file_path, "P/lib/edit.txt");
+        assert_eq!(plan.writes[1].expected_pre_hash.as_deref(), Some("h_edit2"));
+        assert!(plan.writes[1].content_available);
+        assert_eq!(plan.deletions, vec![RestoreDeletion { file_path: "P/extra.txt".to_string(), expected_pre_hash: "h_extra".to_string() }]);
+        assert_eq!(plan.unchanged_count, 1);
+
+        let v3 = record_restore(&mut store, v2, v1).unwrap();
+        let v3_summary = store.get_version(v3).unwrap().unwrap();
+        assert_eq!(v3_summary.parent_version_id, Some(v2));
+        assert_eq!(diff_versions(&store, v1, v3).unwrap().unchanged.len(), 3);
+        let restore_entry = store.log_entries().last().unwrap();
+        assert!(matches!(restore_entry.event, OperationEvent::ProjectRestore { files_written: 2, files_deleted: 1, .. }));
+        assert_eq!(restore_entry.linked_project_version_id, Some(v3));
+
+        assert!(matches!(plan_restore(&store, v1, 999), Err(rusqlite::Error::QueryReturnedNoRows)));
+    }
+
+    #[test]
+    fn test_snapshot_stores_changed_text_as_delta() {
+        let conn = setup_test_db();
+        let mut store = SqliteVersionStore::new(&conn);
+        let v1_body: String = (0..100).map(|i| format!("const line{} = {};\n", i, i)).collect();
+        let v2_body = v1_body.replace("const line50 = 50;", "const line50 = 5000;");
+        let file = |body: &str| ScannedFileInfo {
+            path: "P/src/app.js".to_string(),
+            hash: blob_store::sha256_hex(body.as_bytes()),
+            size: body.len() as i64,
+            content: Some(body.as_bytes().to_vec()),
+        };
+
+        let v1 = create_initial_project_snapshot(&mut store, TEST_PROJECT_ID, "P", &[file(&v1_body)]).unwrap();
+        let (v2, _) = create_snapshot(&mut store, v1, "Edit line 50", &[file(&v2_body)]).unwrap();
+
+        let (diff_version, source_hash): (i64, String) = conn
+            .query_row("SELECT target_project_version_id, source_content_hash FROM FileDiffs", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!(diff_version, v2);
+        assert_eq!(source_hash, blob_store::sha256_hex(v1_body.as_bytes()));
+
+        let v2_hash = &store.get_version_files(v2).unwrap()[0].content_hash;
+        assert!(!blob_store::has_blob(&conn, v2_hash).unwrap());
+        assert_eq!(delta_store::get_content(&conn, v2_hash).unwrap(), Some(v2_body.into_bytes()));
+        assert!(plan_restore(&store, v1, v2).unwrap().writes[0].content_available);
+    }
+}
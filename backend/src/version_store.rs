@@ -0,0 +1,421 @@
+// diranalyze/backend/src/version_store.rs
+
+use rusqlite::{params, Connection, Result};
+use std::collections::{BTreeMap, HashMap};
+use crate::blob_store;
+use crate::delta_store;
+use crate::operation_log::{self, OperationLogEntry};
+
+/// A row of `ProjectVersions` together with aggregate information about its files.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct VersionSummary {
+    pub version_id: i64,
+    pub project_id: Option<i64>,
+    pub parent_version_id: Option<i64>,
+    pub timestamp: String,
+    pub description: Option<String>,
+    pub file_count: i64,
+    pub total_size: i64,
+}
+
+/// A single file entry of a project version, as stored in `VersionFiles`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct VersionFileRecord {
+    pub file_path: String,
+    pub content_hash: String,
+    pub file_size: i64,
+}
+
+/// Persistence used by the versioning logic in `version_control`.
+///
+/// Implementations report a missing version as `rusqlite::Error::QueryReturnedNoRows`, which
+/// the API turns into a 404, whatever they are backed by.
+pub trait VersionStore {
+    /// Runs `f` atomically: if it fails, none of its writes are kept.
+    fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+        Self: Sized;
+
+    /// Inserts a `ProjectVersions` row and returns its `version_id`.
+    fn create_version(
+        &mut self,
+        project_id: Option<i64>,
+        parent_version_id: Option<i64>,
+        timestamp: &str,
+        description: &str,
+    ) -> Result<i64>;
+
+    /// Fetches a single version, or `None` if no version has that id.
+    fn get_version(&self, version_id: i64) -> Result<Option<VersionSummary>>;
+
+    /// Returns the number of versions of a project, for paginating `list_versions`.
+    fn count_versions(&self, project_id: i64) -> Result<i64>;
+
+    /// Lists a project's versions ordered by `version_id` (oldest first), one page at a time.
+    fn list_versions(&self, project_id: i64, limit: i64, offset: i64) -> Result<Vec<VersionSummary>>;
+
+    /// Records the file list of a version. A path may only appear once per version.
+    fn add_version_files(&mut self, version_id: i64, files: &[VersionFileRecord]) -> Result<()>;
+
+    /// Lists every file recorded for a version, ordered by path.
+    /// Returns an empty list for unknown versions; use `get_version` to tell the two apart.
+    fn get_version_files(&self, version_id: i64) -> Result<Vec<VersionFileRecord>>;
+
+    /// Stores a file body written by `version_id`. `source_hash` names the file's previous
+    /// content, which a store may use to keep only a delta.
+    ///
+    /// # Returns
+    /// The SHA-256 hash of `content`.
+    fn put_content(&mut self, version_id: i64, file_path: &str, content: &[u8], source_hash: Option<&str>) -> Result<String>;
+
+    /// Loads a file body by hash, or `None` if it is not stored.
+    fn get_content(&self, content_hash: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Returns whether a file body with this hash is stored.
+    fn has_content(&self, content_hash: &str) -> Result<bool>;
+
+    /// Validates `entry` and appends it to the operation log, returning its `log_id`.
+    /// Invalid events are rejected the same way as by `operation_log::log_operation`.
+    fn append_log(&mut self, entry: &OperationLogEntry) -> Result<i64>;
+}
+
+/// `VersionStore` over the SQLite schema managed by `db_manage`. File bodies go through
+/// `delta_store`, so changed text files are kept as deltas.
+pub struct SqliteVersionStore<'c> {
+    conn: &'c Connection,
+}
+
+impl<'c> SqliteVersionStore<'c> {
+    pub fn new(conn: &'c Connection) -> Self {
+        SqliteVersionStore { conn }
+    }
+}
+
+const VERSION_SUMMARY_SELECT: &str =
+    "SELECT pv.version_id, pv.project_id, pv.parent_version_id, pv.timestamp, pv.description,
+            COUNT(vf.version_file_id), COALESCE(SUM(vf.file_size), 0)
+     FROM ProjectVersions pv
+     LEFT JOIN VersionFiles vf ON vf.project_version_id = pv.version_id";
+
+fn version_summary_from_row(row: &rusqlite::Row) -> Result<VersionSummary> {
+    Ok(VersionSummary {
+        version_id: row.get(0)?,
+        project_id: row.get(1)?,
+        parent_version_id: row.get(2)?,
+        timestamp: row.get(3)?,
+        description: row.get(4)?,
+        file_count: row.get(5)?,
+        total_size: row.get(6)?,
+    })
+}
+
+impl VersionStore for SqliteVersionStore<'_> {
+    /// Uses a savepoint, so it also works inside a transaction the caller already opened.
+    fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        self.conn.execute_batch("SAVEPOINT version_store;")?;
+        match f(self) {
+            Ok(value) => {
+                self.conn.execute_batch("RELEASE version_store;")?;
+                Ok(value)
+            }
+            Err(e) => {
+                let _ = self.conn.execute_batch("ROLLBACK TO version_store; RELEASE version_store;");
+                Err(e)
+            }
+        }
+    }
+
+    fn create_version(
+        &mut self,
+        project_id: Option<i64>,
+        parent_version_id: Option<i64>,
+        timestamp: &str,
+        description: &str,
+    ) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO ProjectVersions (project_id, parent_version_id, timestamp, description) VALUES (?1, ?2, ?3, ?4)",
+            params![project_id, parent_version_id, timestamp, description],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn get_version(&self, version_id: i64) -> Result<Option<VersionSummary>> {
+        let sql = format!("{} WHERE pv.version_id = ?1 GROUP BY pv.version_id", VERSION_SUMMARY_SELECT);
+        let mut stmt = self.conn.prepare(&sql)?;
+        let mut rows = stmt.query_map(params![version_id], version_summary_from_row)?;
+        rows.next().transpose()
+    }
+
+    fn count_versions(&self, project_id: i64) -> Result<i64> {
+        self.conn.query_row(
+            "SELECT COUNT(*) FROM ProjectVersions WHERE project_id = ?1",
+            params![project_id],
+            |row| row.get(0),
+        )
+    }
+
+    fn list_versions(&self, project_id: i64, limit: i64, offset: i64) -> Result<Vec<VersionSummary>> {
+        let sql = format!(
+            "{} WHERE pv.project_id = ?1 GROUP BY pv.version_id ORDER BY pv.version_id ASC LIMIT ?2 OFFSET ?3",
+            VERSION_SUMMARY_SELECT
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(params![project_id, limit, offset], version_summary_from_row)?;
+        rows.collect()
+    }
+
+    fn add_version_files(&mut self, version_id: i64, files: &[VersionFileRecord]) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "INSERT INTO VersionFiles (project_version_id, file_path, content_hash, file_size) VALUES (?1, ?2, ?3, ?4)"
+        )?;
+        for file in files {
+            stmt.execute(params![version_id, file.file_path, file.content_hash, file.file_size])?;
+        }
+        Ok(())
+    }
+
+    fn get_version_files(&self, version_id: i64) -> Result<Vec<VersionFileRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT file_path, content_hash, file_size FROM VersionFiles
+             WHERE project_version_id = ?1 ORDER BY file_path ASC"
+        )?;
+        let rows = stmt.query_map(params![version_id], |row| {
+            Ok(VersionFileRecord {
+                file_path: row.get(0)?,
+                content_hash: row.get(1)?,
+                file_size: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    fn put_content(&mut self, version_id: i64, file_path: &str, content: &[u8], source_hash: Option<&str>) -> Result<String> {
+        delta_store::put_content(self.conn, version_id, file_path, content, source_hash)
+    }
+
+    fn get_content(&self, content_hash: &str) -> Result<Option<Vec<u8>>> {
+        delta_store::get_content(self.conn, content_hash)
+    }
+
+    fn has_content(&self, content_hash: &str) -> Result<bool> {
+        delta_store::has_content(self.conn, content_hash)
+    }
+
+    fn append_log(&mut self, entry: &OperationLogEntry) -> Result<i64> {
+        operation_log::log_operation(self.conn, entry)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct StoredVersion {
+    project_id: Option<i64>,
+    parent_version_id: Option<i64>,
+    timestamp: String,
+    description: String,
+    files: BTreeMap<String, VersionFileRecord>,
+}
+
+/// `VersionStore` kept entirely in memory. Bodies are stored whole (no deltas) and nothing
+/// survives the process; used to test versioning logic without SQLite.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemoryVersionStore {
+    versions: BTreeMap<i64, StoredVersion>,
+    contents: HashMap<String, Vec<u8>>,
+    log: Vec<OperationLogEntry>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl MemoryVersionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every entry appended so far, oldest first.
+    pub fn log_entries(&self) -> &[OperationLogEntry] {
+        &self.log
+    }
+
+    fn summary(&self, version_id: i64, version: &StoredVersion) -> VersionSummary {
+        VersionSummary {
+            version_id,
+            project_id: version.project_id,
+            parent_version_id: version.parent_version_id,
+            timestamp: version.timestamp.clone(),
+            description: Some(version.description.clone()),
+            file_count: version.files.len() as i64,
+            total_size: version.files.values().map(|f| f.file_size).sum(),
+        }
+    }
+}
+
+impl VersionStore for MemoryVersionStore {
+    /// Keeps a copy of the whole store and puts it back if `f` fails.
+    fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        let before = self.clone();
+        f(self).inspect_err(|_| *self = before)
+    }
+
+    fn create_version(
+        &mut self,
+        project_id: Option<i64>,
+        parent_version_id: Option<i64>,
+        timestamp: &str,
+        description: &str,
+    ) -> Result<i64> {
+        if parent_version_id.is_some_and(|parent| !self.versions.contains_key(&parent)) {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        let version_id = self.versions.keys().next_back().map_or(1, |last| last + 1);
+        self.versions.insert(version_id, StoredVersion {
+            project_id,
+            parent_version_id,
+            timestamp: timestamp.to_string(),
+            description: description.to_string(),
+            files: BTreeMap::new(),
+        });
+        Ok(version_id)
+    }
+
+    fn get_version(&self, version_id: i64) -> Result<Option<VersionSummary>> {
+        Ok(self.versions.get(&version_id).map(|v| self.summary(version_id, v)))
+    }
+
+    fn count_versions(&self, project_id: i64) -> Result<i64> {
+        Ok(self.versions.values().filter(|v| v.project_id == Some(project_id)).count() as i64)
+    }
+
+    fn list_versions(&self, project_id: i64, limit: i64, offset: i64) -> Result<Vec<VersionSummary>> {
+        Ok(self
+            .versions
+            .iter()
+            .filter(|(_, v)| v.project_id == Some(project_id))
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .map(|(id, v)| self.summary(*id, v))
+            .collect())
+    }
+
+    fn add_version_files(&mut self, version_id: i64, files: &[VersionFileRecord]) -> Result<()> {
+        let version = self.versions.get_mut(&version_id).ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+        for file in files {
+            if version.files.insert(file.file_path.clone(), file.clone()).is_some() {
+                return Err(rusqlite::Error::SqliteFailure(
+                    rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE),
+                    Some(format!("duplicate file path '{}' in version {}", file.file_path, version_id)),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn get_version_files(&self, version_id: i64) -> Result<Vec<VersionFileRecord>> {
+        Ok(self
+            .versions
+            .get(&version_id)
+            .map(|v| v.files.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn put_content(&mut self, _version_id: i64, _file_path: &str, content: &[u8], _source_hash: Option<&str>) -> Result<String> {
+        let content_hash = blob_store::sha256_hex(content);
+        self.contents.entry(content_hash.clone()).or_insert_with(|| content.to_vec());
+        Ok(content_hash)
+    }
+
+    fn get_content(&self, content_hash: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.contents.get(content_hash).cloned())
+    }
+
+    fn has_content(&self, content_hash: &str) -> Result<bool> {
+        Ok(self.contents.contains_key(content_hash))
+    }
+
+    fn append_log(&mut self, entry: &OperationLogEntry) -> Result<i64> {
+        entry.event.validate().map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        self.log.push(entry.clone());
+        Ok(self.log.len() as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_manage;
+    use crate::operation_log::OperationEvent;
+
+    fn record(path: &str, hash: &str, size: i64) -> VersionFileRecord {
+        VersionFileRecord { file_path: path.to_string(), content_hash: hash.to_string(), file_size: size }
+    }
+
+    /// The behaviour both implementations must agree on.
+    fn exercise_store<S: VersionStore>(store: &mut S, project_id: i64) {
+        let v1 = store.create_version(Some(project_id), None, "2024-01-01T00:00:00Z", "Initial").unwrap();
+        store.add_version_files(v1, &[record("P/b.txt", "h_b", 4), record("P/a.txt", "h_a", 3)]).unwrap();
+        let v2 = store.create_version(Some(project_id), Some(v1), "2024-01-02T00:00:00Z", "Second").unwrap();
+
+        let summary = store.get_version(v1).unwrap().unwrap();
+        assert_eq!((summary.file_count, summary.total_size), (2, 7));
+        assert_eq!(store.get_version(v2).unwrap().unwrap().parent_version_id, Some(v1));
+        assert!(store.get_version(999).unwrap().is_none());
+        assert_eq!(store.count_versions(project_id).unwrap(), 2);
+        let page: Vec<i64> = store.list_versions(project_id, 1, 1).unwrap().iter().map(|v| v.version_id).collect();
+        assert_eq!(page, vec![v2]);
+
+        let paths: Vec<String> = store.get_version_files(v1).unwrap().into_iter().map(|f| f.file_path).collect();
+        assert_eq!(paths, vec!["P/a.txt", "P/b.txt"]);
+        assert!(store.get_version_files(999).unwrap().is_empty());
+
+        let hash = store.put_content(v1, "P/a.txt", b"abc", None).unwrap();
+        assert_eq!(hash, blob_store::sha256_hex(b"abc"));
+        assert!(store.has_content(&hash).unwrap());
+        assert_eq!(store.get_content(&hash).unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(store.get_content("missing").unwrap(), None);
+
+        // A failing transaction leaves nothing behind.
+        let failed: Result<()> = store.transaction(|s| {
+            let v3 = s.create_version(Some(project_id), Some(v2), "2024-01-03T00:00:00Z", "Doomed")?;
+            s.add_version_files(v3, &[record("P/x.txt", "h1", 1), record("P/x.txt", "h2", 1)])
+        });
+        assert!(failed.is_err());
+        assert_eq!(store.count_versions(project_id).unwrap(), 2);
+
+        let patch = |hash_after: String| OperationLogEntry {
+            linked_project_version_id: Some(v1),
+            timestamp: "2024-01-01T00:00:00Z".to_string(),
+            event: OperationEvent::PatchApplied {
+                file_path: "P/a.txt".to_string(),
+                hash_before: None,
+                hash_after: Some(hash_after),
+                hunks_applied: 1,
+            },
+        };
+        store.append_log(&patch(hash)).unwrap();
+        assert!(store.append_log(&patch("not-a-hash".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        let conn = Connection::open_in_memory().unwrap();
+        db_manage::initialize_database(&conn).unwrap();
+        let project = crate::projects::create_project(&conn, "P", "fp").unwrap();
+        exercise_store(&mut SqliteVersionStore::new(&conn), project.project_id);
+
+        let logged: i64 = conn.query_row("SELECT COUNT(*) FROM OperationLog WHERE operation_type = 'PATCH_APPLIED'", [], |row| row.get(0)).unwrap();
+        assert_eq!(logged, 1);
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = MemoryVersionStore::new();
+        exercise_store(&mut store, 1);
+        assert_eq!(store.log_entries().len(), 1);
+    }
+}
@@ -0,0 +1,330 @@
+// diranalyze/backend/src/ws_protocol.rs
+
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::time::Duration;
+use crate::live_events::LiveEvent;
+
+/// Bumped whenever a frame changes incompatibly. Clients announce theirs in `hello`.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// How often the server pings an idle connection.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// A connection that sent nothing (not even a pong) for this long is closed.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// WebSocket close codes used by the server (RFC 6455, section 7.4.1).
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// Groups of `LiveEvent`s a client can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    /// Every new `OperationLog` entry.
+    OperationLog,
+    /// Created snapshots and recorded restores.
+    Snapshots,
+    /// Switches of the active project database.
+    Databases,
+}
+
+impl Topic {
+    pub const ALL: [Topic; 3] = [Topic::OperationLog, Topic::Snapshots, Topic::Databases];
+
+    pub fn of(event: &LiveEvent) -> Topic {
+        match event {
+            LiveEvent::OperationLogged { .. } => Topic::OperationLog,
+            LiveEvent::SnapshotCreated { .. } | LiveEvent::RestoreRecorded { .. } => Topic::Snapshots,
+            LiveEvent::DatabaseOpened { .. } => Topic::Databases,
+        }
+    }
+}
+
+/// A frame sent by the client. Every frame except `hello` and `ping` carries an `id` that the
+/// matching `response` or `error` echoes back.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Must be the first frame on a connection.
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        client: Option<String>,
+    },
+    /// Calls one of the methods listed in the server's capabilities.
+    Request {
+        id: String,
+        method: String,
+        #[serde(default)]
+        params: Value,
+    },
+    Subscribe { id: String, topics: Vec<Topic> },
+    Unsubscribe { id: String, topics: Vec<Topic> },
+    /// Application-level heartbeat for clients that cannot send WebSocket pings (browsers).
+    Ping {
+        #[serde(default)]
+        id: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Anything other than `hello` was sent first.
+    HandshakeRequired,
+    UnsupportedVersion,
+    /// The frame was not valid JSON or did not match any message type.
+    InvalidMessage,
+    UnknownMethod,
+    InvalidParams,
+    NotFound,
+    /// An upstream service such as an LLM provider failed.
+    Upstream,
+    /// An LLM provider is rate limiting us; try again later.
+    RateLimited,
+    /// An LLM prompt was refused by the token budget.
+    BudgetExceeded,
+    /// An LLM call was refused because a spend cap is used up.
+    SpendCapReached,
+    Internal,
+}
+
+impl ErrorCode {
+    /// Maps the status an HTTP handler answered with onto the error frame code.
+    pub fn from_status(status: axum::http::StatusCode) -> Self {
+        match status {
+            axum::http::StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            axum::http::StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::BudgetExceeded,
+            axum::http::StatusCode::PAYMENT_REQUIRED => ErrorCode::SpendCapReached,
+            axum::http::StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+            s if s.is_client_error() => ErrorCode::InvalidParams,
+            axum::http::StatusCode::BAD_GATEWAY
+            | axum::http::StatusCode::SERVICE_UNAVAILABLE
+            | axum::http::StatusCode::GATEWAY_TIMEOUT => ErrorCode::Upstream,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// What the server advertises in its `hello`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Capabilities {
+    pub methods: Vec<String>,
+    pub topics: Vec<Topic>,
+    pub heartbeat_interval_secs: u64,
+}
+
+/// A frame sent by the server.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Hello {
+        protocol_version: u32,
+        server_version: String,
+        capabilities: Capabilities,
+    },
+    Response { id: String, result: Value },
+    /// `id` is `None` when the offending frame could not be correlated with a request.
+    Error {
+        id: Option<String>,
+        code: ErrorCode,
+        message: String,
+    },
+    /// Intermediate progress of the request `id`; its `response` follows once it is done.
+    Progress { id: String, completed: i64, total: i64 },
+    /// Streamed text for the request `id`; its `response` carries the assembled result.
+    Delta { id: String, content: String },
+    Event { topic: Topic, payload: LiveEvent },
+    /// The client fell behind and `skipped` events were dropped for it.
+    Lagged { skipped: u64 },
+    Pong { id: Option<String> },
+}
+
+impl ServerMessage {
+    pub fn error(id: Option<String>, code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error { id, code, message: message.into() }
+    }
+
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).expect("server messages always serialize")
+    }
+}
+
+/// What the connection loop should do with an incoming text frame.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Reply(ServerMessage),
+    /// Run `method` and answer with a `response` or `error` carrying `id`.
+    Dispatch { id: String, method: String, params: Value },
+    /// Send `message`, then close the connection with `code`.
+    Close { message: ServerMessage, code: u16, reason: &'static str },
+}
+
+/// Per-connection protocol state: the handshake and the client's subscriptions.
+#[derive(Debug, Clone)]
+pub struct Session {
+    methods: &'static [&'static str],
+    greeted: bool,
+    topics: BTreeSet<Topic>,
+}
+
+impl Session {
+    /// `methods` are the RPC methods the connection loop can dispatch.
+    pub fn new(methods: &'static [&'static str]) -> Self {
+        Session { methods, greeted: false, topics: BTreeSet::new() }
+    }
+
+    pub fn is_subscribed(&self, topic: Topic) -> bool {
+        self.topics.contains(&topic)
+    }
+
+    pub fn has_subscriptions(&self) -> bool {
+        !self.topics.is_empty()
+    }
+
+    /// Handles one text frame. Everything except `request` is answered here.
+    pub fn handle_text(&mut self, text: &str) -> Action {
+        let value: Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(e) => return Action::Reply(ServerMessage::error(None, ErrorCode::InvalidMessage, e.to_string())),
+        };
+        // Keep the id around so even a malformed request gets a correlated error.
+        let id = value.get("id").and_then(Value::as_str).map(str::to_string);
+        let message: ClientMessage = match serde_json::from_value(value) {
+            Ok(message) => message,
+            Err(e) => return Action::Reply(ServerMessage::error(id, ErrorCode::InvalidMessage, e.to_string())),
+        };
+
+        match message {
+            ClientMessage::Hello { protocol_version, .. } if protocol_version != PROTOCOL_VERSION => Action::Close {
+                message: ServerMessage::error(
+                    None,
+                    ErrorCode::UnsupportedVersion,
+                    format!("server speaks protocol version {}, client asked for {}", PROTOCOL_VERSION, protocol_version),
+                ),
+                code: CLOSE_PROTOCOL_ERROR,
+                reason: "unsupported protocol version",
+            },
+            ClientMessage::Hello { .. } if self.greeted => {
+                Action::Reply(ServerMessage::error(None, ErrorCode::InvalidMessage, "hello was already received"))
+            }
+            ClientMessage::Hello { .. } => {
+                self.greeted = true;
+                Action::Reply(ServerMessage::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    server_version: env!("CARGO_PKG_VERSION").to_string(),
+                    capabilities: Capabilities {
+                        methods: self.methods.iter().map(|m| m.to_string()).collect(),
+                        topics: Topic::ALL.to_vec(),
+                        heartbeat_interval_secs: HEARTBEAT_INTERVAL.as_secs(),
+                    },
+                })
+            }
+            ClientMessage::Ping { id } => Action::Reply(ServerMessage::Pong { id }),
+            _ if !self.greeted => Action::Reply(ServerMessage::error(
+                id,
+                ErrorCode::HandshakeRequired,
+                "send a hello frame first",
+            )),
+            ClientMessage::Request { id, method, .. } if !self.methods.contains(&method.as_str()) => Action::Reply(
+                ServerMessage::error(Some(id), ErrorCode::UnknownMethod, format!("unknown method '{}'", method)),
+            ),
+            ClientMessage::Request { id, method, params } => Action::Dispatch { id, method, params },
+            ClientMessage::Subscribe { id, topics } => {
+                self.topics.extend(topics);
+                Action::Reply(self.subscriptions(id))
+            }
+            ClientMessage::Unsubscribe { id, topics } => {
+                for topic in &topics {
+                    self.topics.remove(topic);
+                }
+                Action::Reply(self.subscriptions(id))
+            }
+        }
+    }
+
+    /// The frame for a live event, or `None` if the client is not subscribed to its topic.
+    pub fn event_frame(&self, event: LiveEvent) -> Option<ServerMessage> {
+        let topic = Topic::of(&event);
+        self.is_subscribed(topic).then_some(ServerMessage::Event { topic, payload: event })
+    }
+
+    fn subscriptions(&self, id: String) -> ServerMessage {
+        ServerMessage::Response { id, result: serde_json::json!({ "topics": self.topics }) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const METHODS: &[&str] = &["list_projects"];
+
+    fn reply(session: &mut Session, frame: Value) -> Value {
+        match session.handle_text(&frame.to_string()) {
+            Action::Reply(message) => serde_json::to_value(message).unwrap(),
+            other => panic!("expected a reply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_handshake_and_request_correlation() {
+        let mut session = Session::new(METHODS);
+        let early = reply(&mut session, json!({ "type": "request", "id": "r1", "method": "list_projects" }));
+        assert_eq!(early["type"], "error");
+        assert_eq!(early["code"], "handshake_required");
+        assert_eq!(early["id"], "r1");
+
+        let hello = reply(&mut session, json!({ "type": "hello", "protocol_version": PROTOCOL_VERSION }));
+        assert_eq!(hello["type"], "hello");
+        assert_eq!(hello["capabilities"]["methods"], json!(["list_projects"]));
+        assert_eq!(hello["capabilities"]["topics"], json!(["operation_log", "snapshots", "databases"]));
+
+        assert_eq!(
+            session.handle_text(&json!({ "type": "request", "id": "r2", "method": "list_projects" }).to_string()),
+            Action::Dispatch { id: "r2".to_string(), method: "list_projects".to_string(), params: Value::Null }
+        );
+        let unknown = reply(&mut session, json!({ "type": "request", "id": "r3", "method": "drop_tables" }));
+        assert_eq!((unknown["code"].as_str(), unknown["id"].as_str()), (Some("unknown_method"), Some("r3")));
+        let malformed = reply(&mut session, json!({ "type": "subscribe", "id": "r4", "topics": ["nope"] }));
+        assert_eq!((malformed["code"].as_str(), malformed["id"].as_str()), (Some("invalid_message"), Some("r4")));
+        let not_json = serde_json::to_value(match session.handle_text("{") {
+            Action::Reply(message) => message,
+            other => panic!("unexpected {:?}", other),
+        })
+        .unwrap();
+        assert_eq!(not_json["id"], Value::Null);
+        assert_eq!(reply(&mut session, json!({ "type": "ping", "id": "p" })), json!({ "type": "pong", "id": "p" }));
+    }
+
+    #[test]
+    fn test_version_mismatch_closes_connection() {
+        let mut session = Session::new(METHODS);
+        match session.handle_text(&json!({ "type": "hello", "protocol_version": PROTOCOL_VERSION + 1 }).to_string()) {
+            Action::Close { message: ServerMessage::Error { code, .. }, code: close_code, .. } => {
+                assert_eq!(code, ErrorCode::UnsupportedVersion);
+                assert_eq!(close_code, CLOSE_PROTOCOL_ERROR);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_subscriptions_filter_events() {
+        let mut session = Session::new(METHODS);
+        reply(&mut session, json!({ "type": "hello", "protocol_version": PROTOCOL_VERSION }));
+        let database_event = LiveEvent::DatabaseOpened { project_root_name: "app".to_string() };
+        assert_eq!(session.event_frame(database_event.clone()), None);
+
+        let subscribed = reply(&mut session, json!({ "type": "subscribe", "id": "s", "topics": ["databases", "snapshots"] }));
+        assert_eq!(subscribed["result"]["topics"], json!(["snapshots", "databases"]));
+        let frame = serde_json::to_value(session.event_frame(database_event.clone()).unwrap()).unwrap();
+        assert_eq!(frame["topic"], "databases");
+        assert_eq!(frame["payload"]["event"], "database_opened");
+
+        reply(&mut session, json!({ "type": "unsubscribe", "id": "u", "topics": ["databases"] }));
+        assert_eq!(session.event_frame(database_event), None);
+        assert!(session.has_subscriptions());
+    }
+}